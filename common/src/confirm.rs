@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use amqprs::{
+    callbacks::ChannelCallback,
+    channel::{BasicPublishArguments, Channel, ConfirmSelectArguments},
+    connection::Connection,
+    error::Error,
+    Ack, BasicProperties, Cancel, CloseChannel, Nack, Return,
+};
+use async_trait::async_trait;
+use tokio::{sync::oneshot, time::Instant};
+
+/// Publishers waiting for confirm by delivery tag, confirm is `true` if message was acknowledged
+type PendingConfirms = Arc<Mutex<BTreeMap<u64, oneshot::Sender<bool>>>>;
+
+/// Channel in confirm mode, many messages can wait for their confirms at once
+pub struct ConfirmChannel {
+    channel: Channel,
+    /// Delivery tag of last published message, locked while publishing so tags match broker's
+    last_delivery_tag: tokio::sync::Mutex<u64>,
+    pending: PendingConfirms,
+}
+
+impl ConfirmChannel {
+    pub async fn open(connection: &Connection) -> Result<Self, Error> {
+        let channel = connection.open_channel(None).await?;
+        let pending = PendingConfirms::default();
+        channel
+            .register_callback(ConfirmCallback {
+                pending: pending.clone(),
+            })
+            .await?;
+        channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await?;
+        Ok(Self {
+            channel,
+            last_delivery_tag: tokio::sync::Mutex::new(0),
+            pending,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.channel.is_open()
+    }
+
+    /// Publish message through default exchange, confirm is waited for separately.
+    /// Channel is closed after error, because delivery tags can't be tracked anymore
+    pub async fn publish(
+        &self,
+        routing_key: &str,
+        props: BasicProperties,
+        content: Vec<u8>,
+    ) -> Result<PendingConfirm, Error> {
+        let mut last_delivery_tag = self.last_delivery_tag.lock().await;
+        let delivery_tag = *last_delivery_tag + 1;
+        let (tx, rx) = oneshot::channel();
+        // Registered before publishing, because confirm can arrive before publish returns
+        self.pending.lock().unwrap().insert(delivery_tag, tx);
+        let args = BasicPublishArguments::default()
+            .routing_key(routing_key.to_owned())
+            .finish();
+        if let Err(e) = self.channel.basic_publish(props, content, args).await {
+            self.pending.lock().unwrap().remove(&delivery_tag);
+            self.channel.clone().close().await.ok();
+            return Err(e);
+        }
+        *last_delivery_tag = delivery_tag;
+        Ok(PendingConfirm(rx))
+    }
+}
+
+/// Confirm of published message
+pub struct PendingConfirm(oneshot::Receiver<bool>);
+
+impl PendingConfirm {
+    /// Wait until message is acknowledged by broker
+    pub async fn wait(self, timeout: Duration) -> Result<(), Error> {
+        self.wait_until(Instant::now() + timeout).await
+    }
+
+    /// Wait until message is acknowledged by broker, with deadline shared by many messages
+    pub async fn wait_until(self, deadline: Instant) -> Result<(), Error> {
+        match tokio::time::timeout_at(deadline, self.0).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) => Err(Error::ChannelUseError(
+                "message was rejected by broker".to_owned(),
+            )),
+            Ok(Err(_)) => Err(Error::ChannelUseError("channel is closed".to_owned())),
+            Err(_) => Err(Error::ChannelUseError(
+                "timed out waiting for publisher confirm".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Channel callback passing publisher confirms to messages waiting for them
+struct ConfirmCallback {
+    pending: PendingConfirms,
+}
+
+impl ConfirmCallback {
+    fn confirm(&self, delivery_tag: u64, multiple: bool, ack: bool) {
+        let mut pending = self.pending.lock().unwrap();
+        let confirmed = if multiple {
+            // Unconfirmed messages with larger tags stay in `pending`
+            let rest = pending.split_off(&(delivery_tag + 1));
+            std::mem::replace(&mut *pending, rest)
+        } else {
+            pending.remove_entry(&delivery_tag).into_iter().collect()
+        };
+        drop(pending);
+        for (_, tx) in confirmed {
+            // Publisher can stop waiting after timeout
+            tx.send(ack).ok();
+        }
+    }
+}
+
+#[async_trait]
+impl ChannelCallback for ConfirmCallback {
+    async fn close(&mut self, _channel: &Channel, close: CloseChannel) -> Result<(), Error> {
+        tracing::error!("Publisher channel closed by server: {close}");
+        // Waiting publishers fail instead of timing out
+        self.pending.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _channel: &Channel, _cancel: Cancel) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, _channel: &Channel, active: bool) -> Result<bool, Error> {
+        Ok(active)
+    }
+
+    async fn publish_ack(&mut self, _channel: &Channel, ack: Ack) {
+        self.confirm(ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, _channel: &Channel, nack: Nack) {
+        self.confirm(nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        _channel: &Channel,
+        ret: Return,
+        _basic_properties: BasicProperties,
+        _content: Vec<u8>,
+    ) {
+        tracing::warn!("Published message returned: {ret}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_pending(pending: &PendingConfirms, delivery_tag: u64) -> oneshot::Receiver<bool> {
+        let (tx, rx) = oneshot::channel();
+        pending.lock().unwrap().insert(delivery_tag, tx);
+        rx
+    }
+
+    #[test]
+    fn multiple_confirm_resolves_earlier_tags() {
+        let pending = PendingConfirms::default();
+        let callback = ConfirmCallback {
+            pending: pending.clone(),
+        };
+        let mut receivers: Vec<_> = (1..=4).map(|x| add_pending(&pending, x)).collect();
+
+        callback.confirm(3, true, true);
+        for rx in &mut receivers[..3] {
+            assert_eq!(rx.try_recv(), Ok(true));
+        }
+        assert!(receivers[3].try_recv().is_err());
+        assert_eq!(pending.lock().unwrap().keys().collect::<Vec<_>>(), [&4]);
+    }
+
+    #[test]
+    fn single_confirm_resolves_only_its_tag() {
+        let pending = PendingConfirms::default();
+        let callback = ConfirmCallback {
+            pending: pending.clone(),
+        };
+        let mut first = add_pending(&pending, 1);
+        let mut second = add_pending(&pending, 2);
+
+        callback.confirm(2, false, false);
+        assert_eq!(second.try_recv(), Ok(false));
+        assert!(first.try_recv().is_err());
+        callback.confirm(1, false, true);
+        assert_eq!(first.try_recv(), Ok(true));
+        assert!(pending.lock().unwrap().is_empty());
+    }
+}
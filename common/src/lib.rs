@@ -1,32 +1,123 @@
 use serde::{Deserialize, Serialize};
 
+pub mod confirm;
+pub mod retry;
 pub mod storage;
 
 pub const ELASTICSEARCH_INDEX: &str = "image_hosting";
-pub const RABBITMQ_QUEUE_NAME: &str = "image_hosting_queue";
+/// Queue for background jobs (processing uploads, updating and deleting images)
+pub const RABBITMQ_JOBS_QUEUE_NAME: &str = "image_hosting_queue";
+/// Queue for latency-sensitive requests with responses (search)
+pub const RABBITMQ_RPC_QUEUE_NAME: &str = "image_hosting_rpc_queue";
+/// Queue for image processing status updates from worker to web server
 pub const RABBITMQ_CALLBACK_QUEUE_NAME: &str = "image_hosting_callback_queue";
 
+/// Background jobs sent to worker through jobs queue
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WorkerMessage {
     OnUpload(OnUploadMessage),
+    Delete(DeleteMessage),
+    Update(UpdateMessage),
+}
+
+/// Requests sent to worker through RPC queue, answered with `RpcReply`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcRequest {
     Search(SearchMessage),
+    Similar(SimilarMessage),
 }
 
+/// Responses to `RpcRequest`, variant matches request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcResponse {
+    Search(SearchResponse),
+    Similar(SearchResponse),
+}
+
+/// Reply of worker to `RpcRequest`, error is sent instead of leaving client waiting
+pub type RpcReply = Result<RpcResponse, String>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OnUploadMessage {
     pub id: i64,
     pub format: String,
+    #[serde(default)]
+    pub blob_hash: Option<String>,
     pub title: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SearchQuery {
+    Text(String),
+    /// Hash of image in storage, see `storage::get_query_image_key`
+    Image(String),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchMessage {
-    pub query_text: String,
+    pub query: SearchQuery,
     pub page: i64,
 }
 
+/// Remove thumbnail and search index entry of deleted image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteMessage {
+    pub id: i64,
+    pub format: String,
+    /// Widths of responsive thumbnails of image
+    #[serde(default)]
+    pub thumbnail_widths: Vec<u32>,
+}
+
+/// Change of image metadata in search index, `None` fields are left unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateMessage {
+    pub id: i64,
+    pub title: Option<String>,
+}
+
+/// Find images with embeddings closest to embedding of image `id`, excluding it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimilarMessage {
+    pub id: i64,
+    pub count: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchResponse {
     pub ids: Vec<i64>,
     pub last_page: bool,
 }
+
+/// Messages from worker to web server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CallbackMessage {
+    Status(StatusMessage),
+    Thumbnails(ThumbnailsMessage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProcessingStatus {
+    Processing,
+    ThumbnailReady,
+    Indexed,
+    Done,
+    Failed,
+}
+
+/// Image processing status update
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusMessage {
+    pub id: i64,
+    pub status: ProcessingStatus,
+    pub error: Option<String>,
+}
+
+/// Dimensions of image (after applying EXIF orientation) and widths of created thumbnails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailsMessage {
+    pub id: i64,
+    pub width: u32,
+    pub height: u32,
+    pub thumbnail_widths: Vec<u32>,
+}
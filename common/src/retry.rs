@@ -0,0 +1,227 @@
+use std::{sync::Arc, time::Duration};
+
+use amqprs::{
+    channel::{Channel, QueueDeclareArguments},
+    connection::Connection,
+    error::Error,
+    BasicProperties, FieldTable, FieldValue,
+};
+use tokio::sync::Mutex;
+
+use crate::confirm::ConfirmChannel;
+
+/// Header with number of times message was already retried
+const RETRY_COUNT_HEADER: &str = "x-retry-count";
+/// Header with name of queue from which message was dead-lettered
+const ORIGINAL_QUEUE_HEADER: &str = "x-original-queue";
+/// Header with reason why message was dead-lettered
+const ERROR_HEADER: &str = "x-error";
+/// Maximum time of waiting for publisher confirm of moved message
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times and with what delay failed messages are retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry with given number (starting from 0), doubled after each retry
+    fn delay(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(retry.min(31)))
+    }
+}
+
+/// Name of queue for messages that failed processing too many times
+pub fn dead_letter_queue_name(queue_name: &str) -> String {
+    format!("{queue_name}.dead")
+}
+
+/// Name of queue in which messages wait for `delay` before returning to original queue.
+/// Delay is in the name, so that changing retry settings doesn't conflict with existing queues
+fn delay_queue_name(queue_name: &str, delay: Duration) -> String {
+    format!("{queue_name}.retry.{}ms", delay.as_millis())
+}
+
+/// Declare delay queues and dead-letter queue for queue
+pub async fn declare_retry_queues(
+    channel: &Channel,
+    queue_name: &str,
+    policy: RetryPolicy,
+) -> Result<(), Error> {
+    for retry in 0..policy.max_retries {
+        let delay = policy.delay(retry);
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            "x-message-ttl".try_into().unwrap(),
+            FieldValue::l(delay.as_millis().try_into().unwrap_or(i64::MAX)),
+        );
+        // Expired messages return to original queue through default exchange
+        arguments.insert("x-dead-letter-exchange".try_into().unwrap(), "".into());
+        arguments.insert(
+            "x-dead-letter-routing-key".try_into().unwrap(),
+            queue_name.into(),
+        );
+        channel
+            .queue_declare(
+                QueueDeclareArguments::new(&delay_queue_name(queue_name, delay))
+                    .durable(true)
+                    .arguments(arguments)
+                    .finish(),
+            )
+            .await?;
+    }
+    channel
+        .queue_declare(
+            QueueDeclareArguments::new(&dead_letter_queue_name(queue_name))
+                .durable(true)
+                .finish(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Schedule failed message to be retried after delay,
+/// or move it to dead-letter queue if there are no retries left
+pub async fn retry_or_dead_letter(
+    publisher: &RetryPublisher,
+    queue_name: &str,
+    policy: RetryPolicy,
+    props: BasicProperties,
+    content: Vec<u8>,
+    error: &str,
+) -> Result<(), Error> {
+    let retries = retry_count(&props);
+    if retries >= policy.max_retries {
+        return dead_letter(publisher, queue_name, props, content, error).await;
+    }
+
+    tracing::warn!(
+        "Retrying message from {queue_name} ({} of {}): {error}",
+        retries + 1,
+        policy.max_retries
+    );
+    let props = with_header(
+        props,
+        RETRY_COUNT_HEADER,
+        FieldValue::l((retries + 1).into()),
+    );
+    publisher
+        .publish(
+            &delay_queue_name(queue_name, policy.delay(retries)),
+            props,
+            content,
+        )
+        .await
+}
+
+/// Move message to dead-letter queue, recording original queue and error in headers
+pub async fn dead_letter(
+    publisher: &RetryPublisher,
+    queue_name: &str,
+    props: BasicProperties,
+    content: Vec<u8>,
+    error: &str,
+) -> Result<(), Error> {
+    tracing::error!("Moving message from {queue_name} to dead-letter queue: {error}");
+    let props = with_header(props, ORIGINAL_QUEUE_HEADER, queue_name.into());
+    let props = with_header(props, ERROR_HEADER, error.into());
+    publisher
+        .publish(&dead_letter_queue_name(queue_name), props, content)
+        .await
+}
+
+/// Return dead letter to its original queue with all retries available again
+pub async fn replay(
+    publisher: &RetryPublisher,
+    queue_name: &str,
+    mut props: BasicProperties,
+    content: Vec<u8>,
+) -> Result<(), Error> {
+    let original_queue = get_header_str(&props, ORIGINAL_QUEUE_HEADER)
+        .unwrap_or(queue_name)
+        .to_owned();
+    let mut headers = props.headers().cloned().unwrap_or_default();
+    for name in [RETRY_COUNT_HEADER, ORIGINAL_QUEUE_HEADER, ERROR_HEADER] {
+        headers.remove(&name.try_into().unwrap());
+    }
+    props.with_headers(headers);
+    publisher.publish(&original_queue, props, content).await
+}
+
+/// Number of times message was already retried
+pub fn retry_count(props: &BasicProperties) -> u32 {
+    let value = props
+        .headers()
+        .and_then(|x| x.get(&RETRY_COUNT_HEADER.try_into().unwrap()));
+    match value {
+        Some(FieldValue::l(x)) => (*x).try_into().unwrap_or(0),
+        Some(FieldValue::I(x)) => (*x).try_into().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Reason why message was dead-lettered
+pub fn dead_letter_error(props: &BasicProperties) -> Option<&str> {
+    get_header_str(props, ERROR_HEADER)
+}
+
+fn get_header_str<'a>(props: &'a BasicProperties, name: &str) -> Option<&'a str> {
+    props
+        .headers()
+        .and_then(|x| x.get(&name.try_into().unwrap()))
+        .and_then(|x| x.try_into().ok())
+}
+
+fn with_header(mut props: BasicProperties, name: &str, value: FieldValue) -> BasicProperties {
+    let mut headers = props.headers().cloned().unwrap_or_default();
+    let name = name.try_into().unwrap();
+    // Existing value is removed first, otherwise table size is computed incorrectly
+    headers.remove(&name);
+    headers.insert(name, value);
+    props.with_headers(headers);
+    props
+}
+
+/// Publishes messages to delay and dead-letter queues through channel in confirm mode, so that
+/// original message is acknowledged only after broker has taken its copy
+pub struct RetryPublisher {
+    connection: Connection,
+    /// Opened on first use and after errors
+    channel: Mutex<Option<Arc<ConfirmChannel>>>,
+}
+
+impl RetryPublisher {
+    pub fn new(connection: &Connection) -> Self {
+        Self {
+            connection: connection.clone(),
+            channel: Mutex::new(None),
+        }
+    }
+
+    /// Publish message to queue and wait for its confirm
+    async fn publish(
+        &self,
+        queue_name: &str,
+        props: BasicProperties,
+        content: Vec<u8>,
+    ) -> Result<(), Error> {
+        let channel = {
+            let mut channel = self.channel.lock().await;
+            match channel.as_ref().filter(|x| x.is_open()) {
+                Some(x) => x.clone(),
+                None => channel
+                    .insert(Arc::new(ConfirmChannel::open(&self.connection).await?))
+                    .clone(),
+            }
+        };
+        // Other messages are published while this one waits for confirm
+        channel
+            .publish(queue_name, props, content)
+            .await?
+            .wait(CONFIRM_TIMEOUT)
+            .await
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{
+    ByteStream, ObjectMetadata, Storage, BLOBS_PATH, IMAGES_PATH, QUERIES_PATH, TEMP_PATH,
+    THUMBNAILS_PATH,
+};
+
+/// Size of chunks in which files are streamed
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Storage in local directory
+pub struct FilesystemStorage {
+    root: PathBuf,
+}
+
+impl FilesystemStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn init(&self) -> Result<(), String> {
+        for folder in [
+            IMAGES_PATH,
+            BLOBS_PATH,
+            THUMBNAILS_PATH,
+            QUERIES_PATH,
+            TEMP_PATH,
+        ] {
+            tokio::fs::create_dir_all(self.root.join(folder))
+                .await
+                .map_err(|_| "storage_error".to_owned())?;
+        }
+        Ok(())
+    }
+
+    async fn metadata(&self, key: &str) -> Result<ObjectMetadata, String> {
+        let metadata = tokio::fs::metadata(self.path(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        Ok(ObjectMetadata {
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .map_err(|_| "storage_error".to_owned())?,
+        })
+    }
+
+    async fn load(&self, key: &str) -> Result<Vec<u8>, String> {
+        tokio::fs::read(self.path(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn load_range(&self, key: &str, range: Range<u64>) -> Result<ByteStream, String> {
+        let mut file = tokio::fs::File::open(self.path(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        let reader = file.take(range.end - range.start);
+        Ok(Box::pin(futures::stream::try_unfold(
+            reader,
+            |mut reader| async move {
+                let mut buf = vec![0; READ_CHUNK_SIZE];
+                let len = reader
+                    .read(&mut buf)
+                    .await
+                    .map_err(|_| "storage_error".to_owned())?;
+                if len == 0 {
+                    return Ok(None);
+                }
+                buf.truncate(len);
+                Ok(Some((Bytes::from(buf), reader)))
+            },
+        )))
+    }
+
+    async fn store(&self, key: &str, data: Vec<u8>) -> Result<(), String> {
+        tokio::fs::write(self.path(key), data)
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        tokio::fs::remove_file(self.path(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn link(&self, src: &str, dst: &str) -> Result<(), String> {
+        // Symlink target is relative to the folder of `dst`
+        let mut target = PathBuf::new();
+        for _ in 0..dst.matches('/').count() {
+            target.push("..");
+        }
+        target.push(src);
+        tokio::fs::symlink(target, self.path(dst))
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        // Same filesystem as storage, so files can be renamed atomically
+        self.root.join(TEMP_PATH)
+    }
+
+    async fn store_file(&self, key: &str, path: &Path) -> Result<(), String> {
+        tokio::fs::rename(path, self.path(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+}
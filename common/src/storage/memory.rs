@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::{ByteStream, ObjectMetadata, Storage, TEMP_PATH};
+
+/// Storage in process memory, for tests and local development
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<HashMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn init(&self) -> Result<(), String> {
+        tokio::fs::create_dir_all(self.temp_dir())
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn metadata(&self, key: &str) -> Result<ObjectMetadata, String> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|(data, modified)| ObjectMetadata {
+                size: data.len() as u64,
+                modified: *modified,
+            })
+            .ok_or_else(|| "storage_error".to_owned())
+    }
+
+    async fn load(&self, key: &str) -> Result<Vec<u8>, String> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|(data, _)| data.clone())
+            .ok_or_else(|| "storage_error".to_owned())
+    }
+
+    async fn load_range(&self, key: &str, range: Range<u64>) -> Result<ByteStream, String> {
+        let chunk = self
+            .objects
+            .read()
+            .unwrap()
+            .get(key)
+            .and_then(|(data, _)| data.get(range.start as usize..range.end as usize))
+            .map(Bytes::copy_from_slice)
+            .ok_or_else(|| "storage_error".to_owned())?;
+        Ok(Box::pin(futures::stream::once(async { Ok(chunk) })))
+    }
+
+    async fn store(&self, key: &str, data: Vec<u8>) -> Result<(), String> {
+        self.objects
+            .write()
+            .unwrap()
+            .insert(key.to_owned(), (data, SystemTime::now()));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        self.objects
+            .write()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| "storage_error".to_owned())
+    }
+
+    async fn link(&self, src: &str, dst: &str) -> Result<(), String> {
+        let data = self.load(src).await?;
+        self.store(dst, data).await
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        std::env::temp_dir().join(format!("image_hosting_{TEMP_PATH}"))
+    }
+
+    async fn store_file(&self, key: &str, path: &Path) -> Result<(), String> {
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        self.store(key, data).await?;
+        tokio::fs::remove_file(path)
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use sha2::{Digest, Sha256};
+
+pub use filesystem::FilesystemStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+pub use temp_file::TempFile;
+
+mod filesystem;
+mod memory;
+mod s3;
+mod temp_file;
+
+const IMAGES_PATH: &str = "images";
+const BLOBS_PATH: &str = "blobs";
+const THUMBNAILS_PATH: &str = "thumbnails";
+const QUERIES_PATH: &str = "queries";
+const TEMP_PATH: &str = "tmp";
+
+/// Formats of thumbnail variants created in addition to thumbnail in format of original,
+/// in order of preference. Variant is only kept if it's smaller than thumbnail in original format
+pub const THUMBNAIL_VARIANTS: [&str; 2] = ["avif", "webp"];
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+/// Contents of stored object, read in chunks
+pub type ByteStream = BoxStream<'static, Result<Bytes, String>>;
+
+/// Metadata of stored object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Backend for storing images and thumbnails by key (relative path with `/` separators)
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Prepare backend for use (create folders, check connection)
+    async fn init(&self) -> Result<(), String>;
+    async fn metadata(&self, key: &str) -> Result<ObjectMetadata, String>;
+    async fn load(&self, key: &str) -> Result<Vec<u8>, String>;
+    /// Stream bytes in `range` of object, which must be within its size
+    async fn load_range(&self, key: &str, range: Range<u64>) -> Result<ByteStream, String>;
+    async fn store(&self, key: &str, data: Vec<u8>) -> Result<(), String>;
+    async fn delete(&self, key: &str) -> Result<(), String>;
+    /// Make `dst` have the same contents as `src` (symlink or copy)
+    async fn link(&self, src: &str, dst: &str) -> Result<(), String>;
+    /// Local folder for temporary files, from which they can be cheaply moved into storage
+    fn temp_dir(&self) -> PathBuf;
+    /// Move local file into storage (atomically if possible)
+    async fn store_file(&self, key: &str, path: &Path) -> Result<(), String>;
+}
+
+/// Storage backend settings, read from environment variables
+#[derive(Debug, Clone)]
+pub enum StorageSettings {
+    Filesystem {
+        path: String,
+    },
+    S3 {
+        endpoint: Option<String>,
+        region: String,
+        bucket: String,
+        access_key_id: String,
+        secret_access_key: String,
+        allow_http: bool,
+    },
+    Memory,
+}
+
+impl StorageSettings {
+    /// Read settings from `STORAGE_BACKEND` (`filesystem`, `s3` or `memory`) and backend-specific variables
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| format!("{name} environment variable is not set"))
+        };
+        let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "filesystem".to_owned());
+        match backend.as_str() {
+            "filesystem" => Ok(Self::Filesystem {
+                path: std::env::var("STORAGE_PATH").unwrap_or_else(|_| "storage".to_owned()),
+            }),
+            "s3" => Ok(Self::S3 {
+                endpoint: std::env::var("S3_ENDPOINT").ok(),
+                region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+                bucket: var("S3_BUCKET")?,
+                access_key_id: var("S3_ACCESS_KEY_ID")?,
+                secret_access_key: var("S3_SECRET_ACCESS_KEY")?,
+                allow_http: std::env::var("S3_ALLOW_HTTP")
+                    .map(|x| x == "true")
+                    .unwrap_or(false),
+            }),
+            "memory" => Ok(Self::Memory),
+            _ => Err(format!("Unknown storage backend: {backend}")),
+        }
+    }
+
+    pub fn build(self) -> Result<Box<dyn Storage>, String> {
+        Ok(match self {
+            Self::Filesystem { path } => Box::new(FilesystemStorage::new(path)),
+            Self::S3 {
+                endpoint,
+                region,
+                bucket,
+                access_key_id,
+                secret_access_key,
+                allow_http,
+            } => Box::new(S3Storage::new(
+                endpoint.as_deref(),
+                &region,
+                &bucket,
+                &access_key_id,
+                &secret_access_key,
+                allow_http,
+            )?),
+            Self::Memory => Box::new(MemoryStorage::new()),
+        })
+    }
+}
+
+/// Create storage backend from environment variables, initialize it and make it globally available
+pub async fn init_storage() -> Result<(), String> {
+    let storage = StorageSettings::from_env()?.build()?;
+    storage.init().await?;
+    STORAGE
+        .set(storage)
+        .map_err(|_| "Storage is already initialized".to_owned())
+}
+
+/// Global storage backend, panics if `init_storage` wasn't called
+pub fn storage() -> &'static dyn Storage {
+    STORAGE.get().expect("Storage is not initialized").as_ref()
+}
+
+/// Detect image format by first bytes of image
+pub fn get_image_format(
+    header: &[u8],
+    image_extensions: &[&'static str],
+) -> Result<&'static str, String> {
+    let format = image::guess_format(header).map_err(|_| "unsupported_image_format".to_owned())?;
+    let format = format.extensions_str()[0];
+    if !image_extensions.contains(&format) {
+        return Err("unsupported_image_format".to_owned());
+    }
+    Ok(format)
+}
+
+pub fn get_image_key(id: i64, format: &str, thumbnail: bool) -> String {
+    let folder = if thumbnail {
+        THUMBNAILS_PATH
+    } else {
+        IMAGES_PATH
+    };
+    format!("{folder}/{id}.{format}")
+}
+
+/// Hex-encoded SHA-256 hash of image contents, used as blob name
+pub fn get_blob_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+pub fn get_blob_key(hash: &str) -> String {
+    format!("{BLOBS_PATH}/{hash}")
+}
+
+/// Key of image uploaded for search by image, named by its hash
+pub fn get_query_image_key(hash: &str) -> String {
+    format!("{QUERIES_PATH}/{hash}")
+}
+
+/// Key of original image: content-addressed blob or legacy per-image file
+pub fn get_original_key(id: i64, format: &str, blob_hash: Option<&str>) -> String {
+    match blob_hash {
+        Some(hash) => get_blob_key(hash),
+        None => get_image_key(id, format, false),
+    }
+}
+
+/// Key of thumbnail in format with extension `format`, with given width or default size
+pub fn get_thumbnail_key(id: i64, format: &str, width: Option<u32>) -> String {
+    match width {
+        Some(width) => format!("{THUMBNAILS_PATH}/{id}_{width}w.{format}"),
+        None => get_image_key(id, format, true),
+    }
+}
+
+/// Formats and keys of thumbnail variants of image with extension `format`,
+/// excluding variant in the same format
+pub fn get_thumbnail_variant_keys(
+    id: i64,
+    format: &str,
+    width: Option<u32>,
+) -> Vec<(&'static str, String)> {
+    THUMBNAIL_VARIANTS
+        .into_iter()
+        .filter(|&x| x != format)
+        .map(|x| (x, get_thumbnail_key(id, x, width)))
+        .collect()
+}
+
+pub async fn link_thumbnail(original_key: &str, id: i64, format: &str) -> Result<(), String> {
+    storage()
+        .link(original_key, &get_image_key(id, format, true))
+        .await
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    /// Use memory storage as global storage, so that it's used by `TempFile`
+    pub(crate) async fn init_memory_storage() {
+        STORAGE.get_or_init(|| Box::new(MemoryStorage::new()));
+        storage().init().await.unwrap();
+    }
+
+    async fn load_range(storage: &dyn Storage, key: &str, range: Range<u64>) -> Vec<u8> {
+        let chunks: Vec<_> = storage
+            .load_range(key, range)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    /// Store, load and delete object in initialized backend
+    async fn check_backend(storage: &dyn Storage) {
+        let key = get_image_key(1, "png", false);
+        let data: Vec<u8> = (0..=255).collect();
+        assert!(storage.load(&key).await.is_err());
+        assert!(storage.metadata(&key).await.is_err());
+
+        storage.store(&key, data.clone()).await.unwrap();
+        assert_eq!(storage.load(&key).await.unwrap(), data);
+        assert_eq!(storage.metadata(&key).await.unwrap().size, 256);
+        assert_eq!(load_range(storage, &key, 10..20).await, &data[10..20]);
+        assert_eq!(load_range(storage, &key, 0..256).await, data);
+        assert!(load_range(storage, &key, 5..5).await.is_empty());
+
+        storage.store(&key, vec![1, 2, 3]).await.unwrap();
+        assert_eq!(storage.load(&key).await.unwrap(), [1, 2, 3]);
+
+        storage.delete(&key).await.unwrap();
+        assert!(storage.load(&key).await.is_err());
+        assert!(storage.delete(&key).await.is_err());
+    }
+
+    /// Images with the same contents share blob, which stays when link of one of them is deleted
+    async fn check_shared_blob(storage: &dyn Storage) {
+        let data = b"image".to_vec();
+        let hash = get_blob_hash(&data);
+        let (first, second) = (
+            get_original_key(1, "png", Some(&hash)),
+            get_original_key(2, "png", Some(&hash)),
+        );
+        assert_eq!(first, second);
+        assert_eq!(first, get_blob_key(&hash));
+
+        storage.store(&first, data.clone()).await.unwrap();
+        for id in [1, 2] {
+            storage
+                .link(&first, &get_image_key(id, "png", true))
+                .await
+                .unwrap();
+        }
+        storage
+            .delete(&get_image_key(1, "png", true))
+            .await
+            .unwrap();
+        assert_eq!(storage.load(&first).await.unwrap(), data);
+        assert_eq!(
+            storage.load(&get_image_key(2, "png", true)).await.unwrap(),
+            data
+        );
+        // Legacy images without blob keep their own files
+        assert_eq!(
+            get_original_key(3, "png", None),
+            get_image_key(3, "png", false)
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_storage() {
+        let storage = MemoryStorage::new();
+        storage.init().await.unwrap();
+        check_backend(&storage).await;
+        check_shared_blob(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn filesystem_storage() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = FilesystemStorage::new(root.path());
+        storage.init().await.unwrap();
+        check_backend(&storage).await;
+        check_shared_blob(&storage).await;
+
+        let path = storage.temp_dir().join("upload");
+        tokio::fs::write(&path, b"query").await.unwrap();
+        let key = get_query_image_key(&get_blob_hash(b"query"));
+        storage.store_file(&key, &path).await.unwrap();
+        assert_eq!(storage.load(&key).await.unwrap(), b"query");
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,158 @@
+use std::{ops::Range, path::PathBuf};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path,
+    GetOptions, ObjectStore, PutPayload, WriteMultipart,
+};
+use tokio::io::AsyncReadExt;
+
+use super::{ByteStream, ObjectMetadata, Storage, TEMP_PATH};
+
+/// Size of parts for uploading local files
+const UPLOAD_CHUNK_SIZE: usize = 5 * 1024 * 1024;
+
+/// Storage in S3-compatible object store (AWS S3, MinIO, etc.)
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: Option<&str>,
+        region: &str,
+        bucket: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+        allow_http: bool,
+    ) -> Result<Self, String> {
+        let mut builder = AmazonS3Builder::new()
+            .with_region(region)
+            .with_bucket_name(bucket)
+            .with_access_key_id(access_key_id)
+            .with_secret_access_key(secret_access_key)
+            .with_allow_http(allow_http);
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        Ok(Self {
+            store: builder
+                .build()
+                .map_err(|e| format!("Can't create S3 client: {e}"))?,
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn init(&self) -> Result<(), String> {
+        tokio::fs::create_dir_all(self.temp_dir())
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        // Check that bucket is accessible
+        self.store
+            .list_with_delimiter(None)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Can't access S3 bucket: {e}"))
+    }
+
+    async fn metadata(&self, key: &str) -> Result<ObjectMetadata, String> {
+        self.store
+            .head(&Path::from(key))
+            .await
+            .map(|x| ObjectMetadata {
+                size: x.size,
+                modified: x.last_modified.into(),
+            })
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn load(&self, key: &str) -> Result<Vec<u8>, String> {
+        let result = self
+            .store
+            .get(&Path::from(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        result
+            .bytes()
+            .await
+            .map(|x| x.to_vec())
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn load_range(&self, key: &str, range: Range<u64>) -> Result<ByteStream, String> {
+        let options = GetOptions {
+            range: Some(range.into()),
+            ..Default::default()
+        };
+        let result = self
+            .store
+            .get_opts(&Path::from(key), options)
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        Ok(Box::pin(
+            result.into_stream().map_err(|_| "storage_error".to_owned()),
+        ))
+    }
+
+    async fn store(&self, key: &str, data: Vec<u8>) -> Result<(), String> {
+        self.store
+            .put(&Path::from(key), PutPayload::from(data))
+            .await
+            .map(|_| ())
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        self.store
+            .delete(&Path::from(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    async fn link(&self, src: &str, dst: &str) -> Result<(), String> {
+        // Object stores have no symlinks, so copy server-side
+        self.store
+            .copy(&Path::from(src), &Path::from(dst))
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        std::env::temp_dir().join(format!("image_hosting_{TEMP_PATH}"))
+    }
+
+    async fn store_file(&self, key: &str, path: &std::path::Path) -> Result<(), String> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        let upload = self
+            .store
+            .put_multipart(&Path::from(key))
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_CHUNK_SIZE);
+        let mut buf = vec![0; UPLOAD_CHUNK_SIZE];
+        loop {
+            let len = match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(_) => {
+                    let _ = writer.abort().await;
+                    return Err("storage_error".to_owned());
+                }
+            };
+            writer.write(&buf[..len]);
+        }
+        writer
+            .finish()
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        tokio::fs::remove_file(path)
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+}
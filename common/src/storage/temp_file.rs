@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use super::storage;
+
+/// Number of first bytes kept for detecting image format
+const HEADER_LEN: usize = 64;
+
+/// Temporary local file written in chunks, hashed on the fly and removed on drop unless stored
+pub struct TempFile {
+    path: PathBuf,
+    file: tokio::fs::File,
+    hasher: Sha256,
+    size: u64,
+    header: Vec<u8>,
+}
+
+impl TempFile {
+    pub async fn create() -> Result<Self, String> {
+        let path = storage()
+            .temp_dir()
+            .join(uuid::Uuid::new_v4().simple().to_string());
+        let file = tokio::fs::File::create_new(&path)
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        Ok(Self {
+            path,
+            file,
+            hasher: Sha256::new(),
+            size: 0,
+            header: Vec::with_capacity(HEADER_LEN),
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), String> {
+        let header_rest = (HEADER_LEN - self.header.len()).min(chunk.len());
+        self.header.extend_from_slice(&chunk[..header_rest]);
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        self.file
+            .write_all(chunk)
+            .await
+            .map_err(|_| "storage_error".to_owned())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// First bytes of file, enough for detecting image format
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Whether the header has been fully received
+    pub fn header_complete(&self) -> bool {
+        self.header.len() == HEADER_LEN
+    }
+
+    /// Flush file to disk and get hex-encoded SHA-256 hash of its contents
+    pub async fn finish(&mut self) -> Result<String, String> {
+        self.file
+            .sync_all()
+            .await
+            .map_err(|_| "storage_error".to_owned())?;
+        Ok(format!("{:x}", self.hasher.clone().finalize()))
+    }
+
+    /// Move file into storage
+    pub async fn store(self, key: &str) -> Result<(), String> {
+        storage().store_file(key, &self.path).await
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // File is already moved if it was stored
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{get_blob_hash, get_blob_key, tests::init_memory_storage};
+
+    #[tokio::test]
+    async fn finish_and_store() {
+        init_memory_storage().await;
+        let data: Vec<u8> = (0..200).collect();
+        let mut file = TempFile::create().await.unwrap();
+        for chunk in data.chunks(30) {
+            file.write(chunk).await.unwrap();
+        }
+        assert_eq!(file.size(), 200);
+        assert_eq!(file.header(), &data[..HEADER_LEN]);
+        assert!(file.header_complete());
+
+        let hash = file.finish().await.unwrap();
+        assert_eq!(hash, get_blob_hash(&data));
+        let path = file.path.clone();
+        let key = get_blob_key(&hash);
+        file.store(&key).await.unwrap();
+        assert_eq!(storage().load(&key).await.unwrap(), data);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn removed_on_drop() {
+        init_memory_storage().await;
+        let mut file = TempFile::create().await.unwrap();
+        file.write(b"short").await.unwrap();
+        assert!(!file.header_complete());
+        let path = file.path.clone();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+}
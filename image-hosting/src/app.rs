@@ -5,6 +5,7 @@ use leptos_router::{components::*, path, SsrMode};
 use crate::{
     components::nav_tabs::NavTabs,
     error_template::{AppError, ErrorTemplate},
+    events::LiveUpdates,
     i18n::*,
     pages::{
         image::Image, index::Index, login::LogIn, logout::LogOut, register::Register,
@@ -45,6 +46,9 @@ pub fn App() -> impl IntoView {
         status: RwSignal::new(StatusDialogState::None),
     });
     let context = use_context::<crate::AppState>().unwrap();
+    let live_updates = LiveUpdates::new();
+    provide_context(live_updates);
+    Effect::new(move |_| live_updates.subscribe());
 
     view! {
         <Stylesheet href="/water.min.css"/>
@@ -0,0 +1,124 @@
+use leptos::prelude::*;
+
+#[cfg(feature = "ssr")]
+use axum_extra::extract::CookieJar;
+#[cfg(feature = "ssr")]
+use common::{OnUploadMessage, WorkerMessage};
+#[cfg(feature = "ssr")]
+use leptos_axum::extract;
+
+use crate::{components::status_dialog::StatusDialogState, i18n::*, image::Image};
+
+#[cfg(feature = "ssr")]
+use crate::{
+    db::image::{get_failed_images_by_author, reset_failed_image_status},
+    outbox::{add_to_outbox, notify_outbox},
+    user::{decode_session_token, AuthState},
+    util::{get_lang, get_locale},
+};
+
+/// List of current user's images which failed processing, with buttons to retry
+#[component]
+pub fn FailedImages() -> impl IntoView {
+    let i18n = use_i18n();
+    let app_state = use_context::<crate::AppState>().unwrap();
+
+    let retry_action = ServerAction::<RetryImageProcessing>::new();
+    let images = Resource::new(
+        move || retry_action.version().get(),
+        |_| async { get_failed_images().await },
+    );
+
+    Effect::new(move |_| {
+        if let Some(Err(e)) = retry_action.value().get() {
+            app_state.status.set(StatusDialogState::Error(
+                t_string!(i18n, retry_error).to_owned() + &e.to_string(),
+            ));
+        }
+    });
+
+    view! {
+        <Suspense fallback=|| ()>
+            {move || {
+                images
+                    .get()
+                    .and_then(|x| x.ok())
+                    .filter(|x| !x.is_empty())
+                    .map(|images| view! {
+                        <section class="failed_images">
+                            <h3>{move || t!(i18n, failed_images)}</h3>
+                            <ul>
+                                <For each=move || images.clone() key=|x| x.id children=move |image| {
+                                    view! {
+                                        <li>
+                                            <a href={format!("/image/{}", image.id)}>{image.title}</a>
+                                            ": "
+                                            {image.status_error.unwrap_or_default()}
+                                            <button on:click=move |_| {
+                                                retry_action.dispatch(RetryImageProcessing { image_id: image.id });
+                                            }>
+                                                {move || t!(i18n, retry)}
+                                            </button>
+                                        </li>
+                                    }
+                                } />
+                            </ul>
+                        </section>
+                    })
+            }}
+        </Suspense>
+    }
+}
+
+#[server(GetFailedImages)]
+pub async fn get_failed_images() -> Result<Vec<Image>, ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    let curr_user_id = match decode_session_token(&cookie_jar) {
+        AuthState::Authorized { user } => user.id,
+        AuthState::NotAuthorized => return Ok(Vec::new()),
+    };
+    get_failed_images_by_author(curr_user_id)
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned().into())
+}
+
+#[server(RetryImageProcessing)]
+pub async fn retry_image_processing(image_id: i64) -> Result<(), ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    let curr_user_id = match decode_session_token(&cookie_jar) {
+        AuthState::Authorized { user } => user.id,
+        AuthState::NotAuthorized => {
+            return Err(td_string!(locale, not_logged_in).to_owned().into())
+        }
+    };
+
+    let mut transaction = crate::DB_CONN
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    let (image, blob_hash) = reset_failed_image_status(&mut transaction, image_id, curr_user_id)
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?
+        .ok_or_else(|| td_string!(locale, image_not_failed).to_owned())?;
+    add_to_outbox(
+        &mut transaction,
+        &WorkerMessage::OnUpload(OnUploadMessage {
+            id: image.id,
+            format: image.format,
+            blob_hash,
+            title: image.title,
+        }),
+    )
+    .await
+    .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    transaction
+        .commit()
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    notify_outbox();
+    Ok(())
+}
@@ -4,27 +4,34 @@ use serde::Deserialize;
 #[cfg(feature = "ssr")]
 use axum::{
     extract::{Path, Query},
-    http::{header, StatusCode},
-    response::IntoResponse,
+    http::{HeaderMap, Method, StatusCode},
+    response::Response,
 };
 #[cfg(feature = "ssr")]
 use axum_extra::extract::CookieJar;
 #[cfg(feature = "ssr")]
-use chrono::{DateTime, Utc};
-#[cfg(feature = "ssr")]
-use common::storage::{get_image_metadata, get_image_path, load_image};
+use common::storage::{get_original_key, get_thumbnail_key, get_thumbnail_variant_keys, storage};
 #[cfg(feature = "ssr")]
 use leptos_axum::extract;
 
 use crate::{
-    components::status_dialog::StatusDialogState, i18n::*, image::Image, image_votes::ImageVotes,
+    components::status_dialog::StatusDialogState,
+    events::LiveUpdates,
+    i18n::*,
+    image::{Image, ImageStatus},
+    image_votes::ImageVotes,
     user::User,
 };
 
 #[cfg(feature = "ssr")]
 use crate::{
-    db::image_votes::{delete_image_vote, get_image_votes, insert_image_vote},
+    db::{
+        image::get_image_format_and_blob_hash,
+        image_votes::{delete_image_vote, get_image_votes, insert_image_vote},
+    },
     image::{IMAGE_EXTENSIONS, IMAGE_MIME},
+    serve::{accepts_image, hash_etag, stored_etag, Content, ImageFile},
+    transform::{get_transformed_image, TransformParams},
     user::{decode_session_token, AuthState},
     util::{get_lang, get_locale},
 };
@@ -39,10 +46,43 @@ pub fn ImageComp(
     let i18n = use_i18n();
     let app_state = use_context::<crate::AppState>().unwrap();
 
+    let live_updates = use_context::<LiveUpdates>().unwrap();
+
     let img_path = format!(
         "/api/image/{}.{}?thumbnail={}",
         image.id, image.format, thumbnail
     );
+    let image_id = image.id;
+    let image_update = Memo::new(move |_| live_updates.image(image_id).unwrap_or_default());
+    // Original is shown until thumbnail is created, bypass cache to load it
+    let img_src = move || {
+        if thumbnail && image_update.get().thumbnail_ready {
+            format!("{img_path}&ready")
+        } else {
+            img_path.clone()
+        }
+    };
+    // Responsive thumbnails are only known after image was processed
+    let thumbnail_widths = if thumbnail {
+        image.thumbnail_widths.clone()
+    } else {
+        Vec::new()
+    };
+    let img_src_ = img_src.clone();
+    let img_srcset = move || {
+        (!thumbnail_widths.is_empty()).then(|| {
+            let src = img_src_();
+            thumbnail_widths
+                .iter()
+                .map(|w| format!("{src}&width={w} {w}w"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    };
+    let img_sizes = (thumbnail && !image.thumbnail_widths.is_empty())
+        .then_some("(min-width: 1350px) 30vw, (min-width: 900px) 45vw, 100vw");
+    let initial_status = image.status;
+    let status = move || image_update.get().status.unwrap_or(initial_status);
 
     let vote_action = ServerAction::<VoteOnImage>::new();
     let (rating, set_rating) = signal(image_votes.rating);
@@ -68,7 +108,22 @@ pub fn ImageComp(
             <h3>
                 <a href={format!("/image/{}", image.id)}>{image.title}</a>
             </h3>
-            <img src=img_path />
+            {move || match status() {
+                ImageStatus::Pending | ImageStatus::Processing => {
+                    view! { <p class="status">{move || t!(i18n, processing)}</p> }.into_any()
+                }
+                ImageStatus::Failed => {
+                    view! { <p class="status failed">{move || t!(i18n, processing_failed)}</p> }.into_any()
+                }
+                ImageStatus::Done => ().into_any(),
+            }}
+            <img
+                src=img_src
+                srcset=img_srcset
+                sizes=img_sizes
+                width=image.width
+                height=image.height
+            />
             <div>
                 <p>
                     <button
@@ -109,14 +164,31 @@ pub fn ImageComp(
 
 #[derive(Deserialize)]
 pub struct GetImageFileQuery {
+    #[serde(default)]
     pub thumbnail: bool,
+    /// Width of responsive thumbnail
+    pub width: Option<u32>,
+    /// Width of transformed image
+    pub w: Option<u32>,
+    /// Height of transformed image
+    pub h: Option<u32>,
+    /// How transformed image is fitted into width and height: `contain` or `cover`
+    pub fit: Option<String>,
+    /// Extension of format of transformed image
+    pub format: Option<String>,
+    /// Quality of transformed image in lossy format
+    pub q: Option<u8>,
+    /// Signature allowing any parameters of transformation
+    pub sig: Option<String>,
 }
 
 #[cfg(feature = "ssr")]
 pub async fn get_image_file(
+    method: Method,
+    headers: HeaderMap,
     Path(file_name): Path<String>,
     Query(q): Query<GetImageFileQuery>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
     let dot_pos = file_name
         .find('.')
         .ok_or_else(|| (StatusCode::BAD_REQUEST, String::new()))?;
@@ -129,47 +201,85 @@ pub async fn get_image_file(
         None => return Err((StatusCode::BAD_REQUEST, String::new())),
     };
 
-    let mut path = None;
-    let mut modified = None;
+    if let Some(params) =
+        TransformParams::from_query(&q, format).map_err(|e| (StatusCode::BAD_REQUEST, e))?
+    {
+        return get_transformed_image(id, format, params, q.sig.as_deref())
+            .await?
+            .into_response(&method, &headers)
+            .await;
+    }
+
+    let mut file = None;
     let mut max_age = 31536000;
-    // Try to use thumbnail if it is requested
+    // Try to use thumbnail if it is requested, in the best format accepted by client,
+    // responsive thumbnail of requested width is preferred over default one
     if q.thumbnail {
-        let t_path = get_image_path(id, format, true);
-        let t_modified = get_image_metadata(&t_path)
-            .await
-            .map(|x| x.modified().unwrap());
-        if let Ok(t_modified) = t_modified {
-            path = Some(t_path);
-            modified = Some(t_modified);
-        } else {
+        let widths = q.width.into_iter().map(Some).chain(std::iter::once(None));
+        let candidates = widths.flat_map(|width| {
+            let variants = get_thumbnail_variant_keys(id, format, width)
+                .into_iter()
+                .filter(|(variant, _)| accepts_image(&headers, variant))
+                .map(|(variant, key)| (key, format!("image/{variant}")));
+            let fallback = (
+                get_thumbnail_key(id, format, width),
+                IMAGE_MIME[format_ind].to_owned(),
+            );
+            variants.chain(std::iter::once(fallback))
+        });
+        let candidates: Vec<_> = candidates.collect();
+        for (t_key, content_type) in candidates {
+            if let Ok(metadata) = storage().metadata(&t_key).await {
+                let etag = stored_etag(&t_key, metadata)
+                    .await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+                file = Some((t_key, metadata, etag, content_type));
+                break;
+            }
+        }
+        if file.is_none() {
             // If not found, temporarily use full image
             max_age = 0;
         }
     }
 
-    if path.is_none() {
-        path = Some(get_image_path(id, format, false));
-        modified = Some(
-            get_image_metadata(path.as_ref().unwrap())
+    let (key, metadata, etag, content_type) = match file {
+        Some(x) => x,
+        None => {
+            let (db_format, blob_hash) = get_image_format_and_blob_hash(id)
                 .await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
-                .modified()
-                .unwrap(),
-        );
-    }
-    let last_modified = DateTime::<Utc>::from(modified.unwrap()).to_rfc2822();
-
-    match load_image(path.unwrap()).await {
-        Ok(x) => Ok((
-            [
-                (header::CONTENT_TYPE, IMAGE_MIME[format_ind].to_owned()),
-                (header::LAST_MODIFIED, last_modified),
-                (header::CACHE_CONTROL, format!("max-age={max_age}")),
-            ],
-            x,
-        )),
-        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+                .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db_error".to_owned()))?
+                .ok_or_else(|| (StatusCode::NOT_FOUND, String::new()))?;
+            if db_format != format {
+                return Err((StatusCode::NOT_FOUND, String::new()));
+            }
+            let key = get_original_key(id, format, blob_hash.as_deref());
+            let metadata = storage()
+                .metadata(&key)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+            // Blob name is hash of its contents
+            let etag = match blob_hash {
+                Some(hash) => hash_etag(&hash),
+                None => stored_etag(&key, metadata)
+                    .await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?,
+            };
+            (key, metadata, etag, IMAGE_MIME[format_ind].to_owned())
+        }
+    };
+
+    ImageFile {
+        content: Content::Stored(key),
+        size: metadata.size,
+        modified: Some(metadata.modified),
+        etag,
+        content_type,
+        max_age,
+        vary_accept: q.thumbnail,
     }
+    .into_response(&method, &headers)
+    .await
 }
 
 #[server(VoteOnImage)]
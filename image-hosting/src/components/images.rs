@@ -10,7 +10,8 @@ pub type ImagesData = (Vec<(Image, User, ImageVotes)>, bool);
 
 #[component]
 pub fn Images<F>(
-    images: Resource<Result<ImagesData, ServerFnError<String>>>,
+    /// Images from resource, read inside `Suspense`
+    images: Signal<Option<Result<ImagesData, ServerFnError<String>>>>,
     query_str: F,
 ) -> impl IntoView
 where
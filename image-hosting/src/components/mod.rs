@@ -1,4 +1,6 @@
+pub mod failed_images;
 pub mod image;
 pub mod images;
 pub mod nav_tabs;
+pub mod similar_images;
 pub mod status_dialog;
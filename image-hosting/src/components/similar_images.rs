@@ -0,0 +1,89 @@
+use leptos::prelude::*;
+
+#[cfg(feature = "ssr")]
+use std::time::Duration;
+
+#[cfg(feature = "ssr")]
+use axum_extra::extract::CookieJar;
+#[cfg(feature = "ssr")]
+use common::{RpcRequest, RpcResponse, SimilarMessage};
+#[cfg(feature = "ssr")]
+use leptos_axum::extract;
+
+use crate::{
+    components::image::ImageComp, i18n::*, image::Image, image_votes::ImageVotes, user::User,
+};
+
+#[cfg(feature = "ssr")]
+use crate::{
+    components::images::IMAGES_PER_PAGE,
+    db::image::get_images_with_authors_and_votes_by_ids,
+    rpc::{call, RpcError},
+    user::{decode_session_token, AuthState},
+    util::{get_lang, get_locale},
+};
+
+/// Time to wait for similar images, they are optional for image page
+#[cfg(feature = "ssr")]
+const SIMILAR_IMAGES_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Strip of images visually similar to image
+#[component]
+pub fn SimilarImages(image_id: i64) -> impl IntoView {
+    let i18n = use_i18n();
+
+    let images = Resource::new(
+        move || image_id,
+        |image_id| async move { get_similar_images(image_id).await },
+    );
+
+    view! {
+        <Suspense fallback=|| ()>
+            {move || {
+                images
+                    .get()
+                    .and_then(|x| x.ok())
+                    .filter(|x| !x.is_empty())
+                    .map(|images| view! {
+                        <section class="similar_images">
+                            <h3>{move || t!(i18n, similar_images)}</h3>
+                            <div>
+                                <For each=move || images.clone() key=|x| x.0.id children=move |x| {
+                                    view! {
+                                        <ImageComp image={x.0} author={x.1} image_votes={x.2} thumbnail=true />
+                                    }
+                                } />
+                            </div>
+                        </section>
+                    })
+            }}
+        </Suspense>
+    }
+}
+
+#[server(GetSimilarImages)]
+pub async fn get_similar_images(
+    image_id: i64,
+) -> Result<Vec<(Image, User, ImageVotes)>, ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    let curr_user_id = match decode_session_token(&cookie_jar) {
+        AuthState::Authorized { user } => user.id,
+        AuthState::NotAuthorized => -1,
+    };
+
+    let request = RpcRequest::Similar(SimilarMessage {
+        id: image_id,
+        count: IMAGES_PER_PAGE,
+    });
+    let response = match call(&request, SIMILAR_IMAGES_TIMEOUT).await {
+        Ok(RpcResponse::Similar(x)) => Ok(x),
+        Ok(_) => Err(RpcError::UnexpectedResponse),
+        Err(e) => Err(e),
+    }
+    .map_err(|_| td_string!(locale, search_error).to_owned())?;
+
+    get_images_with_authors_and_votes_by_ids(curr_user_id, response.ids)
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned().into())
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "ssr")]
+
+use common::storage::get_blob_key;
+use sqlx::{Postgres, Transaction};
+
+/// Lock storage object until end of transaction, so that it isn't stored and deleted at once
+pub async fn lock_storage_key(
+    transaction: &mut Transaction<'_, Postgres>,
+    key: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"select true as "locked!" from pg_advisory_xact_lock(hashtextextended($1, 0))"#,
+        key
+    )
+    .fetch_one(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// Add reference to blob, returns `true` if blob is new and must be stored.
+/// Blob is locked until end of transaction
+pub async fn acquire_blob(
+    transaction: &mut Transaction<'_, Postgres>,
+    hash: &str,
+) -> Result<bool, sqlx::Error> {
+    lock_storage_key(transaction, &get_blob_key(hash)).await?;
+    sqlx::query!(
+        r#"
+        insert into "blobs" ("hash", "ref_count") values ($1, 1)
+        on conflict ("hash") do update set "ref_count" = "blobs"."ref_count" + 1
+        returning "ref_count"
+        "#,
+        hash
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map(|x| x.ref_count == 1)
+}
+
+/// Remove reference to blob, returns `true` if it was the last one and blob must be deleted
+pub async fn release_blob(
+    transaction: &mut Transaction<'_, Postgres>,
+    hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let ref_count = sqlx::query!(
+        r#"update "blobs" set "ref_count" = "ref_count" - 1 where "hash" = $1 returning "ref_count""#,
+        hash
+    )
+    .fetch_one(&mut **transaction)
+    .await?
+    .ref_count;
+    if ref_count > 0 {
+        return Ok(false);
+    }
+    sqlx::query!(r#"delete from "blobs" where "hash" = $1"#, hash)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(true)
+}
+
+/// Whether blob is referenced
+pub async fn blob_exists(
+    transaction: &mut Transaction<'_, Postgres>,
+    hash: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select exists(select 1 from "blobs" where "hash" = $1) as "exists!""#,
+        hash
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
+
+/// Get IDs and formats of images stored before content-addressed storage
+pub async fn get_images_without_blob() -> Result<Vec<(i64, String)>, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(r#"select "id", "format" from "images" where "blob_hash" is null order by "id""#)
+        .fetch_all(db)
+        .await
+        .map(|res| res.into_iter().map(|x| (x.id, x.format)).collect())
+}
+
+pub async fn set_image_blob_hash(
+    transaction: &mut Transaction<'_, Postgres>,
+    image_id: i64,
+    hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"update "images" set "blob_hash" = $2 where "id" = $1"#,
+        image_id,
+        hash
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
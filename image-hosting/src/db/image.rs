@@ -5,7 +5,11 @@ use std::collections::HashMap;
 use chrono::{DateTime, Utc};
 use sqlx::{Postgres, Transaction};
 
-use crate::{image::Image, image_votes::ImageVotes, user::User};
+use crate::{
+    image::{Image, ImageStatus},
+    image_votes::ImageVotes,
+    user::User,
+};
 
 macro_rules! get_images_with_authors_and_votes {
     ($curr_user_id:ident, $where:literal, $order_by: literal, $( $var:expr ),*) => {
@@ -17,6 +21,10 @@ macro_rules! get_images_with_authors_and_votes {
                 i."title" as "title",
                 i."author" as "author",
                 i."timestamp" as "timestamp",
+                i."status" as "status: ImageStatus",
+                i."width" as "width",
+                i."height" as "height",
+                i."thumbnail_widths" as "thumbnail_widths",
                 u."name" as "author_name",
                 (coalesce(sum(case when iv."upvote" is null then 0 else
                     (case when iv."upvote" then 1 else -1 end) end), 0)) as "rating!",
@@ -50,6 +58,12 @@ macro_rules! record_to_images_with_authors_and_votes {
                 title: $x.title,
                 author: $x.author,
                 timestamp: $x.timestamp,
+                status: $x.status,
+                // Error is only shown to author in list of failed images
+                status_error: None,
+                width: $x.width,
+                height: $x.height,
+                thumbnail_widths: $x.thumbnail_widths,
             },
             User {
                 id: $x.author,
@@ -73,7 +87,7 @@ pub async fn get_all_images_with_authors_and_votes(
     let last_timestamp = last_timestamp.unwrap_or(DateTime::<Utc>::MAX_UTC);
     get_images_with_authors_and_votes!(
         curr_user_id,
-        r#"where i."timestamp" < $3"#,
+        r#"where i."deleted_at" is null and i."timestamp" < $3"#,
         r#"order by i."timestamp" desc limit $2"#,
         count + 1,
         last_timestamp
@@ -104,7 +118,7 @@ pub async fn get_all_images_with_authors_and_votes_by_author(
     let last_timestamp = last_timestamp.unwrap_or(DateTime::<Utc>::MAX_UTC);
     get_images_with_authors_and_votes!(
         curr_user_id,
-        r#"where i."author" = $3 and i."timestamp" < $4"#,
+        r#"where i."deleted_at" is null and i."author" = $3 and i."timestamp" < $4"#,
         r#"order by i."timestamp" desc limit $2"#,
         count + 1,
         author_id,
@@ -126,6 +140,39 @@ pub async fn get_all_images_with_authors_and_votes_by_author(
     })
 }
 
+/// Find images with words from query in title, used when search index is unavailable
+pub async fn search_images_with_authors_and_votes_by_title(
+    curr_user_id: i64,
+    count: i64,
+    query: &str,
+    page: i64,
+) -> Result<(Vec<(Image, User, ImageVotes)>, bool), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    get_images_with_authors_and_votes!(
+        curr_user_id,
+        r#"where i."deleted_at" is null and i."title_tsv" @@ websearch_to_tsquery('simple', $3)"#,
+        r#"order by ts_rank(i."title_tsv", websearch_to_tsquery('simple', $3)) desc, i."id" desc
+        limit $2 offset $4"#,
+        count + 1,
+        query,
+        page * count
+    )
+    .fetch_all(db)
+    .await
+    .map(|res| {
+        let mut v: Vec<_> = res
+            .into_iter()
+            .map(|x| record_to_images_with_authors_and_votes!(x))
+            .collect();
+        let mut last_page = true;
+        if v.len() == (count + 1) as usize {
+            last_page = false;
+            v.pop();
+        }
+        (v, last_page)
+    })
+}
+
 pub async fn get_images_with_authors_and_votes_by_ids(
     curr_user_id: i64,
     ids: Vec<i64>,
@@ -133,7 +180,7 @@ pub async fn get_images_with_authors_and_votes_by_ids(
     let db = crate::DB_CONN.get().unwrap();
     get_images_with_authors_and_votes!(
         curr_user_id,
-        r#"where i."id" in (select unnest($2::bigint[]))"#,
+        r#"where i."deleted_at" is null and i."id" in (select unnest($2::bigint[]))"#,
         "",
         &ids
     )
@@ -145,7 +192,10 @@ pub async fn get_images_with_authors_and_votes_by_ids(
             .map(|x| record_to_images_with_authors_and_votes!(x))
             .map(|x| (x.0.id, x))
             .collect();
-        ids.into_iter().map(|id| hm[&id].clone()).collect()
+        // Search index can still contain deleted images
+        ids.into_iter()
+            .filter_map(|id| hm.get(&id).cloned())
+            .collect()
     })
 }
 
@@ -154,22 +204,220 @@ pub async fn get_image_with_authors_and_votes_by_id(
     curr_user_id: i64,
 ) -> Result<Option<(Image, User, ImageVotes)>, sqlx::Error> {
     let db = crate::DB_CONN.get().unwrap();
-    get_images_with_authors_and_votes!(curr_user_id, r#"where i."id" = $2"#, "", image_id)
-        .fetch_optional(db)
-        .await
-        .map(|x| x.map(|y| record_to_images_with_authors_and_votes!(y)))
+    get_images_with_authors_and_votes!(
+        curr_user_id,
+        r#"where i."deleted_at" is null and i."id" = $2"#,
+        "",
+        image_id
+    )
+    .fetch_optional(db)
+    .await
+    .map(|x| x.map(|y| record_to_images_with_authors_and_votes!(y)))
+}
+
+/// Get format and blob hash (if image is in content-addressed storage) of image
+pub async fn get_image_format_and_blob_hash(
+    image_id: i64,
+) -> Result<Option<(String, Option<String>)>, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"select "format", "blob_hash" from "images" where "id" = $1"#,
+        image_id
+    )
+    .fetch_optional(db)
+    .await
+    .map(|x| x.map(|y| (y.format, y.blob_hash)))
 }
 
 pub async fn insert_image(
     transaction: &mut Transaction<'_, Postgres>,
     image: &mut Image,
+    blob_hash: &str,
 ) -> Result<(), sqlx::Error> {
     image.id = sqlx::query!(
-        r#"insert into "images" ("format", "title", "author", "timestamp") values ($1, $2, $3, $4) returning "id""#,
-        image.format, image.title, image.author, image.timestamp
+        r#"insert into "images" ("format", "title", "author", "timestamp", "blob_hash") values ($1, $2, $3, $4, $5) returning "id""#,
+        image.format, image.title, image.author, image.timestamp, blob_hash
     )
     .fetch_one(&mut **transaction)
     .await?
     .id;
     Ok(())
 }
+
+pub async fn set_image_status(
+    image_id: i64,
+    status: ImageStatus,
+    status_error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"update "images" set "status" = $2, "status_error" = $3 where "id" = $1"#,
+        image_id,
+        status as ImageStatus,
+        status_error
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Save dimensions of image and widths of its responsive thumbnails
+pub async fn set_image_thumbnails(
+    image_id: i64,
+    width: i32,
+    height: i32,
+    thumbnail_widths: &[i32],
+) -> Result<(), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"update "images" set "width" = $2, "height" = $3, "thumbnail_widths" = $4 where "id" = $1"#,
+        image_id,
+        width,
+        height,
+        thumbnail_widths
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_failed_images_by_author(author_id: i64) -> Result<Vec<Image>, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query_as!(
+        Image,
+        r#"select "id", "format", "title", "author", "timestamp", "status" as "status: ImageStatus", "status_error",
+        "width", "height", "thumbnail_widths"
+        from "images" where "author" = $1 and "status" = 'failed' and "deleted_at" is null order by "timestamp" desc"#,
+        author_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Mark failed image of author as pending again, returning it if it was failed
+pub async fn reset_failed_image_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    image_id: i64,
+    author_id: i64,
+) -> Result<Option<(Image, Option<String>)>, sqlx::Error> {
+    sqlx::query!(
+        r#"update "images" set "status" = 'pending', "status_error" = null
+        where "id" = $1 and "author" = $2 and "status" = 'failed' and "deleted_at" is null
+        returning "id", "format", "title", "author", "timestamp", "status" as "status: ImageStatus", "blob_hash",
+        "width", "height", "thumbnail_widths""#,
+        image_id,
+        author_id
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map(|x| {
+        x.map(|y| {
+            (
+                Image {
+                    id: y.id,
+                    format: y.format,
+                    title: y.title,
+                    author: y.author,
+                    timestamp: y.timestamp,
+                    status: y.status,
+                    status_error: None,
+                    width: y.width,
+                    height: y.height,
+                    thumbnail_widths: y.thumbnail_widths,
+                },
+                y.blob_hash,
+            )
+        })
+    })
+}
+
+/// Change title of image of author, returns `false` if there is no such image
+pub async fn update_image_title(
+    transaction: &mut Transaction<'_, Postgres>,
+    image_id: i64,
+    author_id: i64,
+    title: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query!(
+        r#"update "images" set "title" = $3 where "id" = $1 and "author" = $2 and "deleted_at" is null"#,
+        image_id,
+        author_id,
+        title
+    )
+    .execute(&mut **transaction)
+    .await
+    .map(|x| x.rows_affected() == 1)
+}
+
+/// Mark image of author as deleted, returns `false` if there is no such image
+pub async fn soft_delete_image(image_id: i64, author_id: i64) -> Result<bool, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"update "images" set "deleted_at" = now() where "id" = $1 and "author" = $2 and "deleted_at" is null"#,
+        image_id,
+        author_id
+    )
+    .execute(db)
+    .await
+    .map(|x| x.rows_affected() == 1)
+}
+
+/// Undo deletion of image of author if it was deleted after `deleted_after`
+pub async fn restore_image(
+    image_id: i64,
+    author_id: i64,
+    deleted_after: DateTime<Utc>,
+) -> Result<bool, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"update "images" set "deleted_at" = null where "id" = $1 and "author" = $2 and "deleted_at" > $3"#,
+        image_id,
+        author_id,
+        deleted_after
+    )
+    .execute(db)
+    .await
+    .map(|x| x.rows_affected() == 1)
+}
+
+/// Image whose undo window has passed
+pub struct ImageToPurge {
+    pub id: i64,
+    pub format: String,
+    pub blob_hash: Option<String>,
+    pub thumbnail_widths: Vec<i32>,
+}
+
+/// Lock images deleted before `deleted_before`
+pub async fn get_images_to_purge(
+    transaction: &mut Transaction<'_, Postgres>,
+    deleted_before: DateTime<Utc>,
+    count: i64,
+) -> Result<Vec<ImageToPurge>, sqlx::Error> {
+    sqlx::query_as!(
+        ImageToPurge,
+        r#"select "id", "format", "blob_hash", "thumbnail_widths" from "images" where "deleted_at" < $1
+        order by "deleted_at" limit $2 for update skip locked"#,
+        deleted_before,
+        count
+    )
+    .fetch_all(&mut **transaction)
+    .await
+}
+
+/// Remove image and its votes
+pub async fn delete_image(
+    transaction: &mut Transaction<'_, Postgres>,
+    image_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"delete from "images_votes" where "image_id" = $1"#,
+        image_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(r#"delete from "images" where "id" = $1"#, image_id)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(())
+}
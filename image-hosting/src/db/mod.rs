@@ -1,5 +1,8 @@
 #![cfg(feature = "ssr")]
 
+pub mod blob;
 pub mod image;
 pub mod image_votes;
+pub mod outbox;
+pub mod query_image;
 pub mod user;
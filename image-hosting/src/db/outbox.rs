@@ -0,0 +1,114 @@
+#![cfg(feature = "ssr")]
+
+use sqlx::{Postgres, Transaction};
+
+/// Maximum delay between publishing attempts
+const MAX_RETRY_DELAY_SECS: f64 = 300.0;
+
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+}
+
+pub async fn insert_outbox_message(
+    transaction: &mut Transaction<'_, Postgres>,
+    routing_key: &str,
+    payload: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"insert into "outbox" ("routing_key", "payload") values ($1, $2)"#,
+        routing_key,
+        payload
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// Claim messages that are due for publishing and aren't claimed by other relays.
+/// Claim expires after `claim_secs`, so messages are delivered again if relay crashes
+pub async fn claim_outbox_messages(
+    count: i64,
+    claim_secs: f64,
+) -> Result<Vec<OutboxMessage>, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    let mut messages = sqlx::query_as!(
+        OutboxMessage,
+        r#"
+        update "outbox" set "claimed_until" = now() + make_interval(secs => $2)
+        where "id" in (
+            select "id" from "outbox"
+            where "next_attempt" <= now() and ("claimed_until" is null or "claimed_until" < now())
+            order by "id"
+            limit $1
+            for update skip locked
+        )
+        returning "id", "routing_key", "payload"
+        "#,
+        count,
+        claim_secs
+    )
+    .fetch_all(db)
+    .await?;
+    messages.sort_by_key(|x| x.id);
+    Ok(messages)
+}
+
+pub async fn delete_outbox_messages(ids: &[i64]) -> Result<(), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"delete from "outbox" where "id" in (select unnest($1::bigint[]))"#,
+        ids
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Release claims of messages, so they can be delivered again immediately
+pub async fn release_outbox_messages(ids: &[i64]) -> Result<(), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"update "outbox" set "claimed_until" = null where "id" in (select unnest($1::bigint[]))"#,
+        ids
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Release claims of all messages except `except_ids`
+pub async fn release_other_outbox_messages(except_ids: &[i64]) -> Result<(), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"
+        update "outbox" set "claimed_until" = null
+        where "claimed_until" is not null and "id" not in (select unnest($1::bigint[]))
+        "#,
+        except_ids
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Release claims and schedule next publishing attempt with exponential backoff
+pub async fn postpone_outbox_messages(ids: &[i64]) -> Result<(), sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"
+        update "outbox" set
+            "attempts" = "attempts" + 1,
+            "next_attempt" = now() + make_interval(secs => least(power(2, "attempts"), $2)),
+            "claimed_until" = null
+        where "id" in (select unnest($1::bigint[]))
+        "#,
+        ids,
+        MAX_RETRY_DELAY_SECS
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
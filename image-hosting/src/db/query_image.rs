@@ -0,0 +1,73 @@
+#![cfg(feature = "ssr")]
+
+use chrono::{DateTime, Utc};
+use common::storage::get_query_image_key;
+use sqlx::{Postgres, Transaction};
+
+use super::blob::lock_storage_key;
+
+/// Add image for search by image or mark existing one as used. Image is locked until end of
+/// transaction
+pub async fn insert_query_image(
+    transaction: &mut Transaction<'_, Postgres>,
+    hash: &str,
+) -> Result<(), sqlx::Error> {
+    lock_storage_key(transaction, &get_query_image_key(hash)).await?;
+    sqlx::query!(
+        r#"
+        insert into "query_images" ("hash") values ($1)
+        on conflict ("hash") do update set "last_used" = now()
+        "#,
+        hash
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// Mark image for search by image as used, returns `false` if it doesn't exist
+pub async fn use_query_image(hash: &str) -> Result<bool, sqlx::Error> {
+    let db = crate::DB_CONN.get().unwrap();
+    sqlx::query!(
+        r#"update "query_images" set "last_used" = now() where "hash" = $1"#,
+        hash
+    )
+    .execute(db)
+    .await
+    .map(|x| x.rows_affected() == 1)
+}
+
+/// Whether image for search by image exists
+pub async fn query_image_exists(
+    transaction: &mut Transaction<'_, Postgres>,
+    hash: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select exists(select 1 from "query_images" where "hash" = $1) as "exists!""#,
+        hash
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
+
+/// Lock and remove images for search by image not used since `used_before`, returns their hashes
+pub async fn delete_unused_query_images(
+    transaction: &mut Transaction<'_, Postgres>,
+    used_before: DateTime<Utc>,
+    count: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        delete from "query_images" where "hash" in (
+            select "hash" from "query_images" where "last_used" < $1
+            order by "last_used" limit $2 for update skip locked
+        )
+        returning "hash"
+        "#,
+        used_before,
+        count
+    )
+    .fetch_all(&mut **transaction)
+    .await
+    .map(|res| res.into_iter().map(|x| x.hash).collect())
+}
@@ -0,0 +1,151 @@
+#![cfg(feature = "ssr")]
+
+use std::time::Duration;
+
+use chrono::{TimeDelta, Utc};
+use common::{
+    storage::{get_blob_key, get_image_key, get_query_image_key, storage},
+    DeleteMessage, WorkerMessage,
+};
+
+use crate::{
+    db::{
+        blob::{blob_exists, lock_storage_key, release_blob},
+        image::{delete_image, get_images_to_purge},
+        query_image::{delete_unused_query_images, query_image_exists},
+    },
+    image::DELETION_UNDO_MINUTES,
+    outbox::{add_to_outbox, notify_outbox},
+    transform::remove_cached,
+};
+
+const PURGE_BATCH_SIZE: i64 = 64;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+/// Time after which unused images for search by image are removed
+const QUERY_IMAGE_TTL_HOURS: i64 = 24;
+
+/// Permanently delete batch of images whose undo window has passed, returns their count
+async fn purge_deleted_images() -> anyhow::Result<usize> {
+    let deleted_before = Utc::now() - TimeDelta::minutes(DELETION_UNDO_MINUTES);
+
+    let mut transaction = crate::DB_CONN.get().unwrap().begin().await?;
+    let images = get_images_to_purge(&mut transaction, deleted_before, PURGE_BATCH_SIZE).await?;
+    let mut blobs_to_delete = Vec::new();
+    for image in &images {
+        delete_image(&mut transaction, image.id).await?;
+        if let Some(hash) = &image.blob_hash {
+            if release_blob(&mut transaction, hash).await? {
+                blobs_to_delete.push(hash.clone());
+            }
+        }
+        add_to_outbox(
+            &mut transaction,
+            &WorkerMessage::Delete(DeleteMessage {
+                id: image.id,
+                format: image.format.clone(),
+                thumbnail_widths: image.thumbnail_widths.iter().map(|&x| x as u32).collect(),
+            }),
+        )
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    // Files are deleted after commit, because file of image that wasn't purged can't be
+    // restored, and file left after failure is harmless
+    for hash in &blobs_to_delete {
+        if let Err(err) = delete_unreferenced_file(PurgedFile::Blob(hash)).await {
+            tracing::warn!("Deleting blob {hash} failed: {err:?}");
+        }
+    }
+    for image in images.iter().filter(|x| x.blob_hash.is_none()) {
+        let key = get_image_key(image.id, &image.format, false);
+        if let Err(err) = delete_file(&key).await {
+            tracing::warn!("Deleting {key} failed: {err:?}");
+        }
+    }
+    for image in &images {
+        remove_cached(image.id).await;
+    }
+    if !images.is_empty() {
+        notify_outbox();
+    }
+    Ok(images.len())
+}
+
+/// Remove batch of images for search by image which weren't used recently, returns their count
+async fn purge_query_images() -> anyhow::Result<usize> {
+    let used_before = Utc::now() - TimeDelta::hours(QUERY_IMAGE_TTL_HOURS);
+
+    let mut transaction = crate::DB_CONN.get().unwrap().begin().await?;
+    let hashes =
+        delete_unused_query_images(&mut transaction, used_before, PURGE_BATCH_SIZE).await?;
+    transaction.commit().await?;
+
+    for hash in &hashes {
+        if let Err(err) = delete_unreferenced_file(PurgedFile::QueryImage(hash)).await {
+            tracing::warn!("Deleting image for search {hash} failed: {err:?}");
+        }
+    }
+    Ok(hashes.len())
+}
+
+async fn delete_file(key: &str) -> anyhow::Result<()> {
+    if storage().metadata(key).await.is_ok() {
+        storage().delete(key).await.map_err(anyhow::Error::msg)?;
+    }
+    Ok(())
+}
+
+/// Content-addressed file whose row was deleted
+enum PurgedFile<'a> {
+    Blob(&'a str),
+    QueryImage(&'a str),
+}
+
+/// Delete file whose row was deleted, unless it was uploaded again since then. Key is locked,
+/// so that it isn't uploaded while it's being deleted
+async fn delete_unreferenced_file(file: PurgedFile<'_>) -> anyhow::Result<()> {
+    let mut transaction = crate::DB_CONN.get().unwrap().begin().await?;
+    let (key, referenced) = match file {
+        PurgedFile::Blob(hash) => {
+            let key = get_blob_key(hash);
+            lock_storage_key(&mut transaction, &key).await?;
+            (key, blob_exists(&mut transaction, hash).await?)
+        }
+        PurgedFile::QueryImage(hash) => {
+            let key = get_query_image_key(hash);
+            lock_storage_key(&mut transaction, &key).await?;
+            (key, query_image_exists(&mut transaction, hash).await?)
+        }
+    };
+    if !referenced {
+        delete_file(&key).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Periodically purge deleted images and unused images for search by image, never returns
+pub async fn run_purge() {
+    loop {
+        // There can be more to purge if the whole batch was used
+        let mut full_batch = false;
+        match purge_deleted_images().await {
+            Ok(count) => {
+                full_batch |= count as i64 == PURGE_BATCH_SIZE;
+                if count > 0 {
+                    tracing::info!("Purged {count} deleted images");
+                }
+            }
+            Err(err) => tracing::error!("Purging deleted images failed: {err:?}"),
+        }
+        match purge_query_images().await {
+            Ok(count) => full_batch |= count as i64 == PURGE_BATCH_SIZE,
+            Err(err) => tracing::error!("Purging images for search failed: {err:?}"),
+        }
+        if !full_batch {
+            tokio::time::sleep(PURGE_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ssr")]
+use std::convert::Infallible;
+
+#[cfg(feature = "ssr")]
+use axum::response::sse::{Event, KeepAlive, Sse};
+#[cfg(feature = "ssr")]
+use futures::Stream;
+#[cfg(feature = "ssr")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "ssr")]
+use tokio::sync::{broadcast, watch};
+
+use crate::image::ImageStatus;
+
+#[cfg(feature = "ssr")]
+const EVENTS_CAPACITY: usize = 256;
+
+#[cfg(feature = "ssr")]
+static EVENTS: Lazy<broadcast::Sender<ServerEvent>> =
+    Lazy::new(|| broadcast::channel(EVENTS_CAPACITY).0);
+#[cfg(feature = "ssr")]
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// Event pushed to all connected clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    ThumbnailReady {
+        image_id: i64,
+    },
+    Indexed {
+        image_id: i64,
+    },
+    Processed {
+        image_id: i64,
+    },
+    /// Error isn't sent, because all clients receive events. It's shown to author of image
+    ProcessingFailed {
+        image_id: i64,
+    },
+}
+
+/// Send event to all connected clients
+#[cfg(feature = "ssr")]
+pub fn publish_event(event: ServerEvent) {
+    // Error means that there are no clients
+    let _ = EVENTS.send(event);
+}
+
+/// End all event streams, so that server can shut down gracefully
+#[cfg(feature = "ssr")]
+pub fn close_event_streams() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// Server-Sent Events stream of `ServerEvent`s as JSON
+#[cfg(feature = "ssr")]
+pub async fn get_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures::stream::unfold(
+        (EVENTS.subscribe(), SHUTDOWN.subscribe()),
+        |(mut events_rx, mut shutdown_rx)| async move {
+            loop {
+                let event = tokio::select! {
+                    _ = shutdown_rx.wait_for(|x| *x) => return None,
+                    event = events_rx.recv() => event,
+                };
+                match event {
+                    Ok(event) => {
+                        let event = Event::default().json_data(&event).unwrap();
+                        return Some((Ok(event), (events_rx, shutdown_rx)));
+                    }
+                    // Skip events missed by slow client
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Changes of image received after page was loaded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageUpdate {
+    pub thumbnail_ready: bool,
+    pub indexed: bool,
+    pub status: Option<ImageStatus>,
+}
+
+/// Client-side state of events received from server
+#[derive(Debug, Clone, Copy)]
+pub struct LiveUpdates {
+    images: RwSignal<HashMap<i64, ImageUpdate>>,
+}
+
+impl LiveUpdates {
+    pub fn new() -> Self {
+        Self {
+            images: RwSignal::new(HashMap::new()),
+        }
+    }
+
+    pub fn image(&self, image_id: i64) -> Option<ImageUpdate> {
+        self.images.with(|x| x.get(&image_id).cloned())
+    }
+
+    #[cfg(feature = "hydrate")]
+    fn apply(&self, event: ServerEvent) {
+        self.images.update(|images| match event {
+            ServerEvent::ThumbnailReady { image_id } => {
+                images.entry(image_id).or_default().thumbnail_ready = true;
+            }
+            ServerEvent::Indexed { image_id } => {
+                images.entry(image_id).or_default().indexed = true;
+            }
+            ServerEvent::Processed { image_id } => {
+                images.entry(image_id).or_default().status = Some(ImageStatus::Done);
+            }
+            ServerEvent::ProcessingFailed { image_id } => {
+                images.entry(image_id).or_default().status = Some(ImageStatus::Failed);
+            }
+        });
+    }
+
+    /// Connect to events stream, must be called in browser
+    pub fn subscribe(self) {
+        #[cfg(feature = "hydrate")]
+        {
+            use wasm_bindgen::{closure::Closure, JsCast};
+
+            let Ok(source) = web_sys::EventSource::new("/api/events") else {
+                return;
+            };
+            let on_message = Closure::<dyn Fn(web_sys::MessageEvent)>::new(
+                move |message: web_sys::MessageEvent| {
+                    if let Some(event) = message
+                        .data()
+                        .as_string()
+                        .and_then(|x| serde_json::from_str(&x).ok())
+                    {
+                        self.apply(event);
+                    }
+                },
+            );
+            source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+            // Both live as long as the page
+            on_message.forget();
+        }
+    }
+}
+
+impl Default for LiveUpdates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -11,6 +11,22 @@ pub const IMAGE_MIME: [&str; 5] = [
 ];
 pub const IMAGE_ACCEPT_EXT_MIME: &str =
     ".jpg,.jpeg,.png,.gif,.webp,image/jpeg,image/png,image/gif,image/webp";
+/// Time during which deleted image can be restored
+pub const DELETION_UNDO_MINUTES: i64 = 10;
+
+/// Progress of thumbnail creation and indexing in worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::Type))]
+#[cfg_attr(
+    feature = "ssr",
+    sqlx(type_name = "image_status", rename_all = "lowercase")
+)]
+pub enum ImageStatus {
+    Pending,
+    Processing,
+    Done,
+    Failed,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Image {
@@ -20,6 +36,13 @@ pub struct Image {
     pub author: i64,
     #[serde(with = "chrono::serde::ts_microseconds")]
     pub timestamp: DateTime<Utc>,
+    pub status: ImageStatus,
+    pub status_error: Option<String>,
+    /// Dimensions after applying EXIF orientation, known after thumbnail creation
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// Widths of responsive thumbnails
+    pub thumbnail_widths: Vec<i32>,
 }
 
 impl Default for Image {
@@ -30,6 +53,11 @@ impl Default for Image {
             title: String::new(),
             author: -1,
             timestamp: DateTime::<Utc>::MIN_UTC,
+            status: ImageStatus::Pending,
+            status_error: None,
+            width: None,
+            height: None,
+            thumbnail_widths: Vec::new(),
         }
     }
 }
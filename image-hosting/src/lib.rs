@@ -1,27 +1,23 @@
 #[cfg(feature = "ssr")]
 use std::sync::OnceLock;
 
-#[cfg(feature = "ssr")]
-use amqprs::channel::Channel;
-#[cfg(feature = "ssr")]
-use common::SearchResponse;
-#[cfg(feature = "ssr")]
-use dashmap::DashMap;
-#[cfg(feature = "ssr")]
-use once_cell::sync::Lazy;
-#[cfg(feature = "ssr")]
-use tokio::sync::{oneshot, RwLock};
-
 use components::status_dialog::StatusDialogState;
 use leptos::prelude::*;
 
 pub mod app;
 pub mod components;
 pub mod db;
+pub mod deletion;
 pub mod error_template;
+pub mod events;
 pub mod image;
 pub mod image_votes;
+pub mod outbox;
 pub mod pages;
+pub mod rpc;
+pub mod serve;
+pub mod transform;
+pub mod transport;
 pub mod user;
 pub mod util;
 
@@ -33,11 +29,6 @@ pub const MAX_DB_CONNECTIONS: u32 = 5;
 pub static DB_CONN: OnceLock<sqlx::PgPool> = OnceLock::new();
 #[cfg(feature = "ssr")]
 pub static APP_SECRET: OnceLock<String> = OnceLock::new();
-#[cfg(feature = "ssr")]
-pub static RABBITMQ_CHANNEL: RwLock<Option<Channel>> = RwLock::const_new(None);
-#[cfg(feature = "ssr")]
-pub static RABBITMQ_RESPONSES: Lazy<DashMap<String, oneshot::Sender<SearchResponse>>> =
-    Lazy::new(|| DashMap::new());
 
 #[derive(Debug, Clone)]
 struct AppState {
@@ -1,28 +1,20 @@
 #[cfg(feature = "ssr")]
-use amqprs::{channel::Channel, consumer::AsyncConsumer, BasicProperties, Deliver};
-#[cfg(feature = "ssr")]
-use common::SearchResponse;
-#[cfg(feature = "ssr")]
-use image_hosting::RABBITMQ_RESPONSES;
-#[cfg(feature = "ssr")]
 use tokio::{signal, sync::oneshot};
 #[cfg(feature = "ssr")]
-use tracing_unwrap::{OptionExt, ResultExt};
-
-#[cfg(feature = "ssr")]
-struct RabbitMQSettings {
-    host: String,
-    port: u16,
-    username: String,
-    password: String,
-}
+use tracing_unwrap::ResultExt;
 
 #[cfg(feature = "ssr")]
 #[tokio::main]
 async fn main() {
     use axum::Router;
-    use common::storage::create_folders;
-    use image_hosting::{app::*, components::image::get_image_file};
+    use common::storage::init_storage;
+    use image_hosting::{
+        app::*,
+        components::image::get_image_file,
+        events::{close_event_streams, get_events},
+        transform::init_transform,
+        transport::{init_transport, transport},
+    };
     use leptos::prelude::*;
     use leptos_axum::{generate_route_list, LeptosRoutes};
     use tracing::level_filters::LevelFilter;
@@ -56,22 +48,15 @@ async fn main() {
         )
         .unwrap();
 
-    let rabbitmq_settings = RabbitMQSettings {
-        host: std::env::var("RABBITMQ_HOST")
-            .expect_or_log("RABBITMQ_HOST environment variable is not set"),
-        port: std::env::var("RABBITMQ_PORT")
-            .expect_or_log("RABBITMQ_PORT environment variable is not set")
-            .parse()
-            .expect_or_log("Can't parse RABBITMQ_PORT"),
-        username: std::env::var("RABBITMQ_USERNAME")
-            .expect_or_log("RABBITMQ_USERNAME environment variable is not set"),
-        password: std::env::var("RABBITMQ_PASSWORD")
-            .expect_or_log("RABBITMQ_PASSWORD environment variable is not set"),
-    };
-
-    create_folders()
+    init_storage()
         .await
-        .expect_or_log("Can't create storage folders");
+        .expect_or_log("Can't initialize storage");
+    init_transport()
+        .await
+        .expect_or_log("Can't initialize transport");
+    init_transform()
+        .await
+        .expect_or_log("Can't initialize image transformation");
 
     let db = sqlx::postgres::PgPoolOptions::new()
         .max_connections(image_hosting::MAX_DB_CONNECTIONS)
@@ -84,9 +69,18 @@ async fn main() {
         .expect_or_log("Database migrations failed");
     image_hosting::DB_CONN.set(db).unwrap();
 
+    tokio::spawn(async {
+        if let Err(err) = migrate_legacy_images().await {
+            tracing::error!("Moving images to content-addressed storage failed: {err:?}");
+        }
+    });
+    tokio::spawn(image_hosting::deletion::run_purge());
+    tokio::spawn(image_hosting::outbox::run_relay());
+
     // build our application with a route
     let app = Router::new()
         .route("/api/image/:file_name", axum::routing::get(get_image_file))
+        .route("/api/events", axum::routing::get(get_events))
         .leptos_routes(&leptos_options, routes, {
             let leptos_options = leptos_options.clone();
             move || shell(leptos_options.clone())
@@ -109,132 +103,78 @@ async fn main() {
             .unwrap();
     });
 
-    let (shutdown_rabbitmq_tx, shutdown_rabbitmq_rx) = oneshot::channel();
-    let rabbitmq_task = tokio::spawn(async {
-        launch_rabbitmq_connection(rabbitmq_settings, shutdown_rabbitmq_rx).await;
-    });
+    let (shutdown_transport_tx, shutdown_transport_rx) = oneshot::channel();
+    let transport_task = tokio::spawn(transport().run(shutdown_transport_rx));
 
     shutdown_signal().await;
-    shutdown_rabbitmq_tx.send(()).unwrap();
-    rabbitmq_task.await.unwrap();
+    shutdown_transport_tx.send(()).unwrap();
+    transport_task.await.unwrap();
+    close_event_streams();
     shutdown_axum_tx.send(()).unwrap();
     axum_task.await.unwrap();
 }
 
+/// Move images stored before content-addressed storage into blobs
 #[cfg(feature = "ssr")]
-async fn launch_rabbitmq_connection(
-    settings: RabbitMQSettings,
-    mut shutdown_rx: oneshot::Receiver<()>,
-) {
-    use std::time::Duration;
+async fn migrate_legacy_images() -> anyhow::Result<()> {
+    use common::storage::{get_blob_hash, get_blob_key, get_image_key, storage};
+    use image_hosting::db::blob::{acquire_blob, get_images_without_blob, set_image_blob_hash};
 
-    loop {
-        match connect_rabbitmq(&settings, &mut shutdown_rx).await {
-            Ok(_) => {
-                tracing::info!("RabbitMQ connection shut down normally");
-                break;
-            }
-            Err(err) => {
-                tracing::error!("RabbitMQ connection returned error: {err:?}");
-                tokio::time::sleep(Duration::from_millis(1000)).await;
-            }
-        }
+    let images = get_images_without_blob().await?;
+    if images.is_empty() {
+        return Ok(());
     }
-}
-
-#[cfg(feature = "ssr")]
-async fn connect_rabbitmq(
-    settings: &RabbitMQSettings,
-    shutdown_rx: &mut oneshot::Receiver<()>,
-) -> anyhow::Result<()> {
-    use amqprs::{
-        callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
-        channel::{BasicConsumeArguments, QueueDeclareArguments},
-        connection::{Connection, OpenConnectionArguments},
-    };
-
-    let connection = Connection::open(&OpenConnectionArguments::new(
-        &settings.host,
-        settings.port,
-        &settings.username,
-        &settings.password,
-    ))
-    .await?;
-    connection
-        .register_callback(DefaultConnectionCallback)
-        .await
-        .unwrap_or_log();
-
-    let channel = connection.open_channel(None).await.unwrap_or_log();
-    channel
-        .register_callback(DefaultChannelCallback)
-        .await
-        .unwrap_or_log();
-    *image_hosting::RABBITMQ_CHANNEL.write().await = Some(channel);
-
-    let (callback_queue_name, _, _) = image_hosting::RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
-        .unwrap()
-        .queue_declare(
-            QueueDeclareArguments::new(common::RABBITMQ_CALLBACK_QUEUE_NAME)
-                .durable(true)
-                .finish(),
-        )
-        .await
-        .unwrap_or_log()
-        .unwrap_or_log();
-
-    let args = BasicConsumeArguments::new(&callback_queue_name, "")
-        .manual_ack(true)
-        .finish();
-    image_hosting::RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
-        .unwrap()
-        .basic_consume(Consumer, args)
-        .await
-        .unwrap();
-
-    tracing::info!("Listening for RabbitMQ messages...");
-
-    tokio::select! {
-        _ = shutdown_rx => {
-            connection.close().await?;
-            Ok(())
-        }
-        result = connection.listen_network_io_failure() => {
-            if result {
-                Err(anyhow::anyhow!("connection failure"))
-            } else {
-                Err(anyhow::anyhow!("connection shut down without IO errors"))
+    tracing::info!(
+        "Moving {} images to content-addressed storage",
+        images.len()
+    );
+
+    for (id, format) in images {
+        let legacy_key = get_image_key(id, &format, false);
+        let image_bytes = match storage().load(&legacy_key).await {
+            Ok(x) => x,
+            Err(err) => {
+                tracing::error!("Can't load image {legacy_key}: {err}");
+                continue;
             }
+        };
+        let blob_hash = get_blob_hash(&image_bytes);
+        let blob_key = get_blob_key(&blob_hash);
+
+        let mut transaction = image_hosting::DB_CONN.get().unwrap().begin().await?;
+        if acquire_blob(&mut transaction, &blob_hash).await? {
+            storage()
+                .store(&blob_key, image_bytes.clone())
+                .await
+                .map_err(anyhow::Error::msg)?;
         }
-    }
-}
+        set_image_blob_hash(&mut transaction, id, &blob_hash).await?;
+        transaction.commit().await?;
 
-#[cfg(feature = "ssr")]
-struct Consumer;
-
-#[cfg(feature = "ssr")]
-#[async_trait::async_trait]
-impl AsyncConsumer for Consumer {
-    async fn consume(
-        &mut self,
-        _channel: &Channel,
-        _deliver: Deliver,
-        basic_properties: BasicProperties,
-        content: Vec<u8>,
-    ) {
-        let message: SearchResponse = serde_json::from_slice(&content).unwrap_or_log();
-        if let Some((_, sender)) =
-            RABBITMQ_RESPONSES.remove(basic_properties.correlation_id().unwrap_or_log())
+        // Thumbnails of small images are links to originals, point them to blobs
+        let thumbnail_key = get_image_key(id, &format, true);
+        if storage()
+            .load(&thumbnail_key)
+            .await
+            .is_ok_and(|x| x == image_bytes)
         {
-            sender.send(message).unwrap_or_log();
+            storage()
+                .delete(&thumbnail_key)
+                .await
+                .map_err(anyhow::Error::msg)?;
+            storage()
+                .link(&blob_key, &thumbnail_key)
+                .await
+                .map_err(anyhow::Error::msg)?;
         }
+        storage()
+            .delete(&legacy_key)
+            .await
+            .map_err(anyhow::Error::msg)?;
     }
+
+    tracing::info!("Moving images to content-addressed storage finished");
+    Ok(())
 }
 
 #[cfg(feature = "ssr")]
@@ -0,0 +1,79 @@
+#![cfg(feature = "ssr")]
+
+use std::time::Duration;
+
+use common::WorkerMessage;
+use once_cell::sync::Lazy;
+use sqlx::{Postgres, Transaction};
+use tokio::sync::Notify;
+
+use crate::{
+    db::outbox::{
+        claim_outbox_messages, delete_outbox_messages, insert_outbox_message,
+        postpone_outbox_messages, release_outbox_messages,
+    },
+    transport::transport,
+};
+
+/// Maximum number of messages published at once
+const BATCH_SIZE: i64 = 64;
+/// Interval of checking for messages which publishing was postponed
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Time after which messages claimed by crashed relay are delivered again
+const CLAIM_SECS: f64 = 600.0;
+
+/// Wakes up relay when new messages are committed
+static NOTIFY: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Add message for worker to outbox, it will be published after transaction is committed
+pub async fn add_to_outbox(
+    transaction: &mut Transaction<'_, Postgres>,
+    message: &WorkerMessage,
+) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_vec(message).unwrap();
+    insert_outbox_message(transaction, common::RABBITMQ_JOBS_QUEUE_NAME, &payload).await
+}
+
+/// Start publishing new outbox messages without waiting for poll interval
+pub fn notify_outbox() {
+    NOTIFY.notify_one();
+}
+
+/// Deliver batch of due messages through transport, returns number of messages.
+/// Messages are claimed and removed in separate short transactions, so no locks are held
+/// while waiting for transport
+async fn relay_pending() -> anyhow::Result<usize> {
+    let messages = claim_outbox_messages(BATCH_SIZE, CLAIM_SECS).await?;
+    if messages.is_empty() {
+        return Ok(0);
+    }
+    let ids: Vec<_> = messages.iter().map(|x| x.id).collect();
+
+    let sent = match transport().send_jobs(&messages).await {
+        Ok(x) => x,
+        Err(err) => {
+            release_outbox_messages(&ids).await?;
+            return Err(err);
+        }
+    };
+    let undelivered: Vec<_> = ids
+        .into_iter()
+        .filter(|id| !sent.delivered.contains(id) && !sent.taken.contains(id))
+        .collect();
+    delete_outbox_messages(&sent.delivered).await?;
+    postpone_outbox_messages(&undelivered).await?;
+    Ok(messages.len())
+}
+
+/// Deliver outbox messages to worker, retrying after errors
+pub async fn run_relay() {
+    loop {
+        match relay_pending().await {
+            // Immediately continue if there can be more due messages
+            Ok(count) if count == BATCH_SIZE as usize => continue,
+            Ok(_) => {}
+            Err(err) => tracing::error!("Outbox relay failed: {err:?}"),
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, NOTIFY.notified()).await;
+    }
+}
@@ -1,19 +1,38 @@
 use leptos::prelude::*;
+use leptos_meta::Meta;
 use leptos_router::{hooks::use_params, params::Params};
 
 #[cfg(feature = "ssr")]
 use axum_extra::extract::CookieJar;
 #[cfg(feature = "ssr")]
+use chrono::{TimeDelta, Utc};
+#[cfg(feature = "ssr")]
+use common::{UpdateMessage, WorkerMessage};
+#[cfg(feature = "ssr")]
 use leptos_axum::extract;
 
 use crate::{
-    components::image::ImageComp, i18n::*, image::Image, image_votes::ImageVotes, user::User,
+    components::{
+        image::ImageComp,
+        similar_images::SimilarImages,
+        status_dialog::{StatusDialog, StatusDialogState},
+    },
+    i18n::*,
+    image::{Image, DELETION_UNDO_MINUTES},
+    image_votes::ImageVotes,
+    pages::upload::{TITLE_MAX_LEN, TITLE_MIN_LEN},
+    user::{AuthState, User},
 };
 
 #[cfg(feature = "ssr")]
 use crate::{
-    db::image::get_image_with_authors_and_votes_by_id,
-    user::{decode_session_token, AuthState},
+    db::image::{
+        get_image_with_authors_and_votes_by_id, restore_image, soft_delete_image,
+        update_image_title,
+    },
+    outbox::{add_to_outbox, notify_outbox},
+    transform::{transformed_image_url, PREVIEW_PARAMS},
+    user::decode_session_token,
     util::{get_lang, get_locale},
 };
 
@@ -25,14 +44,50 @@ struct ImageParams {
 #[component]
 pub fn Image() -> impl IntoView {
     let i18n = use_i18n();
+    let app_state = use_context::<crate::AppState>().unwrap();
     let params = use_params::<ImageParams>();
     let id = move || params.get().map(|x| x.id).ok().flatten();
 
+    let edit_action = ServerAction::<EditImage>::new();
+    let (editing, set_editing) = signal(false);
+    Effect::new(move |_| match edit_action.value().get() {
+        Some(Ok(_)) => set_editing.set(false),
+        Some(Err(e)) => {
+            app_state.status.set(StatusDialogState::Error(
+                t_string!(i18n, editing_error).to_owned() + &e.to_string(),
+            ));
+        }
+        None => {}
+    });
+
+    // Reload image after it is edited
     let image = Resource::new_blocking(
-        id,
-        move |id| async move { get_image(id.unwrap_or(-1)).await },
+        move || (id(), edit_action.version().get()),
+        move |(id, _)| async move { get_image(id.unwrap_or(-1)).await },
     );
 
+    let delete_action = ServerAction::<DeleteImage>::new();
+    let undo_action = ServerAction::<UndoImageDeletion>::new();
+    let (deleted, set_deleted) = signal(false);
+    Effect::new(move |_| match delete_action.value().get() {
+        Some(Ok(_)) => set_deleted.set(true),
+        Some(Err(e)) => {
+            app_state.status.set(StatusDialogState::Error(
+                t_string!(i18n, deletion_error).to_owned() + &e.to_string(),
+            ));
+        }
+        None => {}
+    });
+    Effect::new(move |_| match undo_action.value().get() {
+        Some(Ok(_)) => set_deleted.set(false),
+        Some(Err(e)) => {
+            app_state.status.set(StatusDialogState::Error(
+                t_string!(i18n, restoring_error).to_owned() + &e.to_string(),
+            ));
+        }
+        None => {}
+    });
+
     let show_error = move || match image.get() {
         Some(Err(e)) => view! {
             <main>
@@ -44,13 +99,62 @@ pub fn Image() -> impl IntoView {
     };
 
     view! {
+        <StatusDialog />
         <Suspense fallback=|| ()>
             <Show when=move || matches!(image.get(), Some(Ok(_))) fallback=show_error>
                 <main>
                     {move || {
-                        let x = image.get().unwrap().unwrap();
+                        let (image, author, image_votes, preview_url) = image.get().unwrap().unwrap();
+                        let image_id = image.id;
+                        let title = StoredValue::new(image.title.clone());
+                        let author_id = author.id;
+                        let is_author = move || matches!(
+                            app_state.auth_state.get(),
+                            AuthState::Authorized { user } if user.id == author_id
+                        );
                         view! {
-                            <ImageComp image={x.0} author={x.1} image_votes={x.2} thumbnail=false />
+                            <Meta property="og:title" content=image.title.clone() />
+                            <Meta property="og:image" content=preview_url />
+                            <Show when=move || !deleted.get() fallback=move || view! {
+                                <section class="deleted_image">
+                                    <p>{move || t!(i18n, image_deleted, minutes = DELETION_UNDO_MINUTES)}</p>
+                                    <button on:click=move |_| {
+                                        undo_action.dispatch(UndoImageDeletion { image_id });
+                                    }>
+                                        {move || t!(i18n, undo)}
+                                    </button>
+                                </section>
+                            }>
+                                <ImageComp image={image.clone()} author={author.clone()}
+                                    image_votes={image_votes.clone()} thumbnail=false />
+                                <Show when=is_author fallback=|| ()>
+                                    <div class="image_actions">
+                                        <button on:click=move |_| set_editing.set(!editing.get())>
+                                            {move || t!(i18n, edit)}
+                                        </button>
+                                        <button on:click=move |_| {
+                                            delete_action.dispatch(DeleteImage { image_id });
+                                        }>
+                                            {move || t!(i18n, delete)}
+                                        </button>
+                                    </div>
+                                    <Show when=move || editing.get() fallback=|| ()>
+                                        <ActionForm action=edit_action>
+                                            <input type="hidden" name="image_id" value=image_id />
+                                            <label for="title">
+                                                {move || {
+                                                    t!(i18n, title_with_range, min = TITLE_MIN_LEN, max = TITLE_MAX_LEN)
+                                                }}
+                                            </label>
+                                            <input type="text" id="title" name="title" required=true
+                                                minlength=TITLE_MIN_LEN maxlength=TITLE_MAX_LEN size=25
+                                                value=title.get_value() />
+                                            <button type="submit">{move || t!(i18n, save)}</button>
+                                        </ActionForm>
+                                    </Show>
+                                </Show>
+                                <SimilarImages image_id />
+                            </Show>
                         }
                     }}
                 </main>
@@ -59,16 +163,107 @@ pub fn Image() -> impl IntoView {
     }
 }
 
+/// Image with its author, votes and URL of preview for link previews
 #[server(GetImage)]
-pub async fn get_image(id: i64) -> Result<(Image, User, ImageVotes), ServerFnError<String>> {
+pub async fn get_image(
+    id: i64,
+) -> Result<(Image, User, ImageVotes, String), ServerFnError<String>> {
     let locale = get_locale(get_lang().await.unwrap());
     let cookie_jar: CookieJar = extract().await.unwrap();
     let curr_user_id = match decode_session_token(&cookie_jar) {
         AuthState::Authorized { user } => user.id,
         AuthState::NotAuthorized => -1,
     };
-    get_image_with_authors_and_votes_by_id(id, curr_user_id)
+    let (image, author, image_votes) = get_image_with_authors_and_votes_by_id(id, curr_user_id)
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?
+        .ok_or_else(|| td_string!(locale, nothing_found).to_owned())?;
+    let preview_url = transformed_image_url(image.id, &image.format, &PREVIEW_PARAMS);
+    Ok((image, author, image_votes, preview_url))
+}
+
+#[server(EditImage)]
+pub async fn edit_image(image_id: i64, title: String) -> Result<(), ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    let curr_user_id = match decode_session_token(&cookie_jar) {
+        AuthState::Authorized { user } => user.id,
+        AuthState::NotAuthorized => {
+            return Err(td_string!(locale, not_logged_in).to_owned().into())
+        }
+    };
+
+    if title.len() < TITLE_MIN_LEN {
+        return Err(td_string!(locale, title_too_short).to_owned().into());
+    }
+    if title.len() > TITLE_MAX_LEN {
+        return Err(td_string!(locale, title_too_long).to_owned().into());
+    }
+
+    let mut transaction = crate::DB_CONN
+        .get()
+        .unwrap()
+        .begin()
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    if !update_image_title(&mut transaction, image_id, curr_user_id, &title)
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?
+    {
+        return Err(td_string!(locale, nothing_found).to_owned().into());
+    }
+    add_to_outbox(
+        &mut transaction,
+        &WorkerMessage::Update(UpdateMessage {
+            id: image_id,
+            title: Some(title),
+        }),
+    )
+    .await
+    .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    transaction
+        .commit()
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    notify_outbox();
+    Ok(())
+}
+
+#[server(DeleteImage)]
+pub async fn delete_image(image_id: i64) -> Result<(), ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    let curr_user_id = match decode_session_token(&cookie_jar) {
+        AuthState::Authorized { user } => user.id,
+        AuthState::NotAuthorized => {
+            return Err(td_string!(locale, not_logged_in).to_owned().into())
+        }
+    };
+    if !soft_delete_image(image_id, curr_user_id)
         .await
         .map_err(|_| td_string!(locale, db_error).to_owned())?
-        .ok_or_else(|| td_string!(locale, nothing_found).to_owned().into())
+    {
+        return Err(td_string!(locale, nothing_found).to_owned().into());
+    }
+    Ok(())
+}
+
+#[server(UndoImageDeletion)]
+pub async fn undo_image_deletion(image_id: i64) -> Result<(), ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    let curr_user_id = match decode_session_token(&cookie_jar) {
+        AuthState::Authorized { user } => user.id,
+        AuthState::NotAuthorized => {
+            return Err(td_string!(locale, not_logged_in).to_owned().into())
+        }
+    };
+    let deleted_after = Utc::now() - TimeDelta::minutes(DELETION_UNDO_MINUTES);
+    if !restore_image(image_id, curr_user_id, deleted_after)
+        .await
+        .map_err(|_| td_string!(locale, db_error).to_owned())?
+    {
+        return Err(td_string!(locale, undo_expired).to_owned().into());
+    }
+    Ok(())
 }
@@ -52,7 +52,7 @@ pub fn Index() -> impl IntoView {
     };
 
     view! {
-        <Images images=images query_str=query_str />
+        <Images images=Signal::derive(move || images.get()) query_str=query_str />
     }
 }
 
@@ -1,35 +1,70 @@
 use leptos::prelude::*;
 use leptos_router::{components::Form, hooks::use_query_map};
+use serde::{Deserialize, Serialize};
+use server_fn::codec::{MultipartData, MultipartFormData};
+use web_sys::FormData;
 
-#[cfg(feature = "ssr")]
-use amqprs::{channel::BasicPublishArguments, BasicProperties};
 #[cfg(feature = "ssr")]
 use axum_extra::extract::CookieJar;
 #[cfg(feature = "ssr")]
-use common::{SearchMessage, WorkerMessage};
+use common::{
+    storage::{get_image_format, get_query_image_key, storage, TempFile},
+    RpcRequest, RpcResponse, SearchMessage, SearchQuery,
+};
 #[cfg(feature = "ssr")]
-use leptos_axum::extract;
+use leptos_axum::{extract, redirect};
 #[cfg(feature = "ssr")]
-use tokio::sync::oneshot;
+use std::time::Duration;
 
 use crate::{
-    components::images::Images, i18n::*, image::Image, image_votes::ImageVotes, user::User,
+    components::{
+        images::{Images, ImagesData},
+        status_dialog::{StatusDialog, StatusDialogState},
+    },
+    i18n::*,
+    image::IMAGE_ACCEPT_EXT_MIME,
+    pages::upload::IMAGE_MAX_BYTES,
+    user::AuthState,
 };
 
 #[cfg(feature = "ssr")]
 use crate::{
-    db::image::get_images_with_authors_and_votes_by_ids,
-    user::{decode_session_token, AuthState},
+    components::images::IMAGES_PER_PAGE,
+    db::{
+        image::{
+            get_images_with_authors_and_votes_by_ids, search_images_with_authors_and_votes_by_title,
+        },
+        query_image::{insert_query_image, use_query_image},
+    },
+    image::IMAGE_EXTENSIONS,
+    rpc::{call, RpcError},
+    user::decode_session_token,
     util::{get_lang, get_locale},
 };
 
+/// Time to wait for search results from worker
+#[cfg(feature = "ssr")]
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Last page of search results, further ones are empty
+#[cfg(feature = "ssr")]
+const MAX_PAGE: i64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResults {
+    pub images: ImagesData,
+    /// Worker or search index is unavailable, images are found only by title in database
+    pub degraded: bool,
+}
+
 #[component]
 pub fn Search() -> impl IntoView {
     let i18n = use_i18n();
+    let app_state = use_context::<crate::AppState>().unwrap();
     let query = use_query_map();
-    let query_text_and_page = move || {
+    let search_params = move || {
         (
             query.get().get("query_text"),
+            query.get().get("query_image"),
             query
                 .get()
                 .get("page")
@@ -39,27 +74,100 @@ pub fn Search() -> impl IntoView {
                 .flatten(),
         )
     };
-    let images = Resource::new_blocking(query_text_and_page, move |x| async move {
-        search_images(x.0, x.1).await
+    let results = Resource::new_blocking(search_params, move |x| async move {
+        search_images(x.0, x.1, x.2).await
     });
+    let images = Signal::derive(move || results.get().map(|x| x.map(|x| x.images)));
+    let degraded = move || matches!(results.get(), Some(Ok(x)) if x.degraded);
     let query_str = move || {
-        let (query_text, page) = query_text_and_page();
-        format!(
-            "?{}page={}",
-            query_text
-                .map(|x| format!("query_text={x}&"))
-                .unwrap_or_default(),
-            page.unwrap_or_default() + 1
-        )
+        let (query_text, query_image, page) = search_params();
+        let query = match (query_image, query_text) {
+            (Some(x), _) => format!("query_image={x}&"),
+            (None, Some(x)) => format!("query_text={x}&"),
+            (None, None) => String::new(),
+        };
+        format!("?{}page={}", query, page.unwrap_or_default() + 1)
+    };
+
+    let authorized = move || matches!(app_state.auth_state.get(), AuthState::Authorized { .. });
+    let upload_action =
+        Action::new_local(|data: &FormData| upload_query_image(data.clone().into()));
+    let search_by_image = move |image: web_sys::File| {
+        if !authorized() {
+            return;
+        }
+        if image.size() as usize > IMAGE_MAX_BYTES {
+            app_state.status.set(StatusDialogState::Error(
+                t_string!(i18n, search_error).to_owned() + t_string!(i18n, image_too_big),
+            ));
+            return;
+        }
+        let form_data = FormData::new().unwrap();
+        form_data.append_with_blob("image", &image).unwrap();
+        app_state.status.set(StatusDialogState::Loading);
+        upload_action.dispatch_local(form_data);
+    };
+    let on_submit = move |event: web_sys::SubmitEvent| {
+        use wasm_bindgen::JsCast;
+
+        event.prevent_default();
+        let target = event
+            .target()
+            .unwrap()
+            .unchecked_into::<web_sys::HtmlFormElement>();
+        let form_data = FormData::new_with_form(&target).unwrap();
+        search_by_image(form_data.get("image").unchecked_into::<web_sys::File>());
     };
+    // Search by image pasted anywhere on the page
+    Effect::new(move |_| {
+        let handle = window_event_listener(leptos::ev::paste, move |event| {
+            use wasm_bindgen::JsCast;
+
+            let event = event.unchecked_into::<web_sys::ClipboardEvent>();
+            if let Some(image) = event
+                .clipboard_data()
+                .and_then(|x| x.files())
+                .and_then(|x| x.get(0))
+                .filter(|x| x.type_().starts_with("image/"))
+            {
+                event.prevent_default();
+                search_by_image(image);
+            }
+        });
+        on_cleanup(move || handle.remove());
+    });
+    Effect::new(move |_| match upload_action.value().get() {
+        Some(Ok(_)) => {
+            app_state.status.set(StatusDialogState::None);
+        }
+        Some(Err(e)) => {
+            app_state.status.set(StatusDialogState::Error(
+                t_string!(i18n, search_error).to_owned() + &e.to_string(),
+            ));
+        }
+        None => {}
+    });
 
     view! {
+        <StatusDialog />
         <header>
             <Form action="" method="get" class:search=true>
                 <input type="search" id="query_text" name="query_text" required=true />
                 <button type="submit">{move || { t!(i18n, search) }}</button>
             </Form>
+            <Show when=authorized>
+                <form class="search" on:submit=on_submit>
+                    <input type="file" id="query_image" name="image" accept=IMAGE_ACCEPT_EXT_MIME required=true />
+                    <button type="submit">{move || { t!(i18n, search_by_image) }}</button>
+                </form>
+                <p class="hint">{move || { t!(i18n, paste_image_hint) }}</p>
+            </Show>
         </header>
+        <Suspense fallback=move || ()>
+            <Show when=degraded>
+                <p class="banner">{move || { t!(i18n, semantic_search_unavailable) }}</p>
+            </Show>
+        </Suspense>
         <Images images=images query_str=query_str />
     }
 }
@@ -67,51 +175,133 @@ pub fn Search() -> impl IntoView {
 #[server(GetAllImages)]
 pub async fn search_images(
     query_text: Option<String>,
+    query_image: Option<String>,
     page: Option<i64>,
-) -> Result<(Vec<(Image, User, ImageVotes)>, bool), ServerFnError<String>> {
-    if query_text.is_none() {
-        return Ok((Vec::new(), true));
+) -> Result<SearchResults, ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let page = page.unwrap_or_default();
+    if page < 0 {
+        return Err(td_string!(locale, parsing_error).to_owned().into());
     }
+    if page > MAX_PAGE {
+        return Ok(SearchResults {
+            images: (Vec::new(), true),
+            degraded: false,
+        });
+    }
+    let query = match (query_image, query_text) {
+        (Some(hash), _) => {
+            let is_hash = hash.len() == 64 && hash.bytes().all(|x| x.is_ascii_hexdigit());
+            if !is_hash
+                || !use_query_image(&hash)
+                    .await
+                    .map_err(|_| td_string!(locale, db_error).to_owned())?
+            {
+                return Err(td_string!(locale, nothing_found).to_owned().into());
+            }
+            SearchQuery::Image(hash)
+        }
+        (None, Some(text)) => SearchQuery::Text(text),
+        (None, None) => {
+            return Ok(SearchResults {
+                images: (Vec::new(), true),
+                degraded: false,
+            })
+        }
+    };
 
-    let locale = get_locale(get_lang().await.unwrap());
     let cookie_jar: CookieJar = extract().await.unwrap();
     let curr_user_id = match decode_session_token(&cookie_jar) {
         AuthState::Authorized { user } => user.id,
         AuthState::NotAuthorized => -1,
     };
 
-    let body = serde_json::to_vec(&WorkerMessage::Search(SearchMessage {
-        query_text: query_text.unwrap(),
-        page: page.unwrap_or_default(),
-    }))
-    .unwrap();
-
-    let (tx, rx) = oneshot::channel();
-    let correlation_id = uuid::Uuid::new_v4().to_string();
-    let props = BasicProperties::default()
-        .with_persistence(true)
-        .with_reply_to(common::RABBITMQ_CALLBACK_QUEUE_NAME)
-        .with_correlation_id(&correlation_id)
-        .finish();
-    let args = BasicPublishArguments::default()
-        .routing_key(common::RABBITMQ_QUEUE_NAME.to_owned())
-        .finish();
-    crate::RABBITMQ_RESPONSES.insert(correlation_id, tx);
-    crate::RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
+    let request = RpcRequest::Search(SearchMessage {
+        query: query.clone(),
+        page,
+    });
+    let response = match call(&request, SEARCH_TIMEOUT).await {
+        Ok(RpcResponse::Search(x)) => Ok(x),
+        Ok(_) => Err(RpcError::UnexpectedResponse),
+        Err(e) => Err(e),
+    };
+    let (images, degraded) = match (response, query) {
+        (Ok(response), _) => (
+            get_images_with_authors_and_votes_by_ids(curr_user_id, response.ids)
+                .await
+                .map(|x| (x, response.last_page)),
+            false,
+        ),
+        // Fall back to search by title, so that search works without worker
+        (Err(_), SearchQuery::Text(text)) => (
+            search_images_with_authors_and_votes_by_title(
+                curr_user_id,
+                IMAGES_PER_PAGE,
+                &text,
+                page,
+            )
+            .await,
+            true,
+        ),
+        (Err(_), SearchQuery::Image(_)) => (Ok((Vec::new(), true)), true),
+    };
+    let images = images.map_err(|_| td_string!(locale, db_error).to_owned())?;
+    Ok(SearchResults { images, degraded })
+}
+
+/// Save image for search by image and redirect to search results. Only logged in users can
+/// upload, so that storage isn't available to anyone
+#[server(name = UploadQueryImage, input = MultipartFormData)]
+pub async fn upload_query_image(data: MultipartData) -> Result<(), ServerFnError<String>> {
+    let locale = get_locale(get_lang().await.unwrap());
+    let cookie_jar: CookieJar = extract().await.unwrap();
+    if let AuthState::NotAuthorized = decode_session_token(&cookie_jar) {
+        return Err(td_string!(locale, not_logged_in).to_owned().into());
+    }
+
+    let mut data = data.into_inner().unwrap();
+    let mut image_file = None;
+    while let Ok(Some(mut field)) = data.next_field().await {
+        if field.name() != Some("image") {
+            return Err(td_string!(locale, parsing_error).to_owned().into());
+        }
+        let mut file = TempFile::create().await?;
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => file.write(&chunk).await?,
+                Ok(None) => break,
+                Err(_) => return Err(td_string!(locale, parsing_error).to_owned().into()),
+            }
+            if file.size() > IMAGE_MAX_BYTES as u64 {
+                return Err(td_string!(locale, image_too_big).to_owned().into());
+            }
+        }
+        get_image_format(file.header(), &IMAGE_EXTENSIONS)?;
+        image_file = Some(file);
+    }
+    let mut image_file =
+        image_file.ok_or_else(|| td_string!(locale, no_image_selected).to_owned())?;
+    let hash = image_file.finish().await?;
+
+    // Row stays locked until commit, so image can't be purged before it is stored
+    let mut transaction = crate::DB_CONN
+        .get()
         .unwrap()
-        .basic_publish(props, body, args)
+        .begin()
         .await
         .map_err(|_| td_string!(locale, db_error).to_owned())?;
-
-    let response = rx
+    insert_query_image(&mut transaction, &hash)
         .await
         .map_err(|_| td_string!(locale, db_error).to_owned())?;
-
-    get_images_with_authors_and_votes_by_ids(curr_user_id, response.ids)
+    let key = get_query_image_key(&hash);
+    if storage().metadata(&key).await.is_err() {
+        image_file.store(&key).await?;
+    }
+    transaction
+        .commit()
         .await
-        .map(|x| (x, response.last_page))
-        .map_err(|_| td_string!(locale, db_error).to_owned().into())
+        .map_err(|_| td_string!(locale, db_error).to_owned())?;
+
+    redirect(&format!("/search?query_image={hash}"));
+    Ok(())
 }
@@ -2,13 +2,11 @@ use leptos::prelude::*;
 use server_fn::codec::{MultipartData, MultipartFormData};
 use web_sys::FormData;
 
-#[cfg(feature = "ssr")]
-use amqprs::{channel::BasicPublishArguments, BasicProperties};
 #[cfg(feature = "ssr")]
 pub use axum_extra::extract::CookieJar;
 #[cfg(feature = "ssr")]
 use common::{
-    storage::{get_image_format, get_image_path, store_image},
+    storage::{get_blob_key, get_image_format, storage, TempFile},
     OnUploadMessage, WorkerMessage,
 };
 #[cfg(feature = "ssr")]
@@ -23,16 +21,17 @@ use crate::{
 
 #[cfg(feature = "ssr")]
 use crate::{
-    db::image::insert_image,
+    db::{blob::acquire_blob, image::insert_image},
     image::{Image, IMAGE_EXTENSIONS},
+    outbox::{add_to_outbox, notify_outbox},
     user::decode_session_token,
     util::{get_lang, get_locale},
 };
 
-const TITLE_MIN_LEN: usize = 4;
-const TITLE_MAX_LEN: usize = 256;
-const IMAGE_MAX_MIB: usize = 10;
-const IMAGE_MAX_BYTES: usize = IMAGE_MAX_MIB * 1024 * 1024;
+pub const TITLE_MIN_LEN: usize = 4;
+pub const TITLE_MAX_LEN: usize = 256;
+pub const IMAGE_MAX_MIB: usize = 10;
+pub const IMAGE_MAX_BYTES: usize = IMAGE_MAX_MIB * 1024 * 1024;
 
 #[component]
 pub fn Upload() -> impl IntoView {
@@ -114,42 +113,66 @@ pub async fn upload_image(data: MultipartData) -> Result<(), ServerFnError<Strin
 
     let mut data = data.into_inner().unwrap();
     let mut title = None;
-    let mut image_bytes = None;
+    let mut image_file = None;
+    let mut format = None;
     while let Ok(Some(mut field)) = data.next_field().await {
-        let mut buf = bytes::BytesMut::new();
-        loop {
-            match field.chunk().await {
-                Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
-                Ok(None) => break,
-                Err(_) => return Err(td_string!(locale, parsing_error).to_owned().into()),
-            }
-        }
-
         match field.name().unwrap_or_default() {
             "title" => {
+                let mut buf = bytes::BytesMut::new();
+                loop {
+                    match field.chunk().await {
+                        Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+                        Ok(None) => break,
+                        Err(_) => return Err(td_string!(locale, parsing_error).to_owned().into()),
+                    }
+                    if buf.len() > TITLE_MAX_LEN {
+                        return Err(td_string!(locale, title_too_long).to_owned().into());
+                    }
+                }
                 title = String::from_utf8(buf.to_vec()).ok();
             }
             "image" => {
-                image_bytes = Some(buf.to_vec());
+                if image_file.is_some() {
+                    return Err(td_string!(locale, parsing_error).to_owned().into());
+                }
+                // Write image to temporary file, checking size and format on the fly
+                let mut file = TempFile::create().await?;
+                let mut file_format = None;
+                loop {
+                    match field.chunk().await {
+                        Ok(Some(chunk)) => file.write(&chunk).await?,
+                        Ok(None) => break,
+                        Err(_) => return Err(td_string!(locale, parsing_error).to_owned().into()),
+                    }
+                    if file.size() > IMAGE_MAX_BYTES as u64 {
+                        return Err(td_string!(locale, image_too_big).to_owned().into());
+                    }
+                    if file_format.is_none() && file.header_complete() {
+                        file_format = Some(get_image_format(file.header(), &IMAGE_EXTENSIONS)?);
+                    }
+                }
+                if file_format.is_none() {
+                    file_format = Some(get_image_format(file.header(), &IMAGE_EXTENSIONS)?);
+                }
+                format = file_format;
+                image_file = Some(file);
             }
             _ => return Err(td_string!(locale, parsing_error).to_owned().into()),
         }
     }
 
-    if title.is_none() || image_bytes.is_none() {
+    if title.is_none() || image_file.is_none() {
         return Err(td_string!(locale, parsing_error).to_owned().into());
     }
     let title = title.unwrap();
-    let image_bytes = image_bytes.unwrap();
+    let mut image_file = image_file.unwrap();
+    let format = format.unwrap();
 
     if title.len() < TITLE_MIN_LEN {
         return Err(td_string!(locale, title_too_short).to_owned().into());
     }
-    if title.len() > TITLE_MAX_LEN {
-        return Err(td_string!(locale, title_too_long).to_owned().into());
-    }
 
-    let format = get_image_format(&image_bytes, &IMAGE_EXTENSIONS)?;
+    let blob_hash = image_file.finish().await?;
 
     let mut image_db = Image {
         format: format.to_owned(),
@@ -165,40 +188,43 @@ pub async fn upload_image(data: MultipartData) -> Result<(), ServerFnError<Strin
         .begin()
         .await
         .map_err(|_| td_string!(locale, db_error).to_owned())?;
-    insert_image(&mut transaction, &mut image_db)
+    let new_blob = acquire_blob(&mut transaction, &blob_hash)
         .await
         .map_err(|_| td_string!(locale, db_error).to_owned())?;
-
-    let path = get_image_path(image_db.id, format, false);
-    if let e @ Err(_) = store_image(path, image_bytes).await {
-        let _ = transaction.rollback().await;
-        e?;
-        unreachable!()
-    }
-
-    let body = serde_json::to_vec(&WorkerMessage::OnUpload(OnUploadMessage {
-        id: image_db.id,
-        format: image_db.format,
-        title: image_db.title,
-    }))
-    .unwrap();
-    let props = BasicProperties::default().with_persistence(true).finish();
-    let args = BasicPublishArguments::default()
-        .routing_key(common::RABBITMQ_QUEUE_NAME.to_owned())
-        .finish();
-    crate::RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
-        .unwrap()
-        .basic_publish(props, body, args)
+    insert_image(&mut transaction, &mut image_db, &blob_hash)
         .await
         .map_err(|_| td_string!(locale, db_error).to_owned())?;
 
-    transaction
-        .commit()
-        .await
-        .map_err(|_| td_string!(locale, db_error).to_owned())?;
+    // Message for worker is published by outbox relay only if transaction is committed
+    add_to_outbox(
+        &mut transaction,
+        &WorkerMessage::OnUpload(OnUploadMessage {
+            id: image_db.id,
+            format: image_db.format,
+            blob_hash: Some(blob_hash.clone()),
+            title: image_db.title,
+        }),
+    )
+    .await
+    .map_err(|_| td_string!(locale, db_error).to_owned())?;
+
+    // Move image into storage, unless identical image is already stored
+    let blob_key = get_blob_key(&blob_hash);
+    if new_blob {
+        if let e @ Err(_) = image_file.store(&blob_key).await {
+            let _ = transaction.rollback().await;
+            e?;
+            unreachable!()
+        }
+    }
+
+    if transaction.commit().await.is_err() {
+        if new_blob {
+            let _ = storage().delete(&blob_key).await;
+        }
+        return Err(td_string!(locale, db_error).to_owned().into());
+    }
+    notify_outbox();
 
     redirect("/");
     Ok(())
@@ -10,14 +10,19 @@ use axum_extra::extract::CookieJar;
 #[cfg(feature = "ssr")]
 use leptos_axum::extract;
 
-use crate::{components::images::Images, image::Image, image_votes::ImageVotes, user::User};
+use crate::{
+    components::{failed_images::FailedImages, images::Images, status_dialog::StatusDialog},
+    image::Image,
+    image_votes::ImageVotes,
+    user::{AuthState, User},
+};
 
 #[cfg(feature = "ssr")]
 use crate::{
     components::images::IMAGES_PER_PAGE,
     db::image::get_all_images_with_authors_and_votes_by_author,
     i18n::*,
-    user::{decode_session_token, AuthState},
+    user::decode_session_token,
     util::{get_lang, get_locale},
 };
 
@@ -28,6 +33,7 @@ struct UserParams {
 
 #[component]
 pub fn User() -> impl IntoView {
+    let app_state = use_context::<crate::AppState>().unwrap();
     let params = use_params::<UserParams>();
     let query = use_query_map();
     let id_and_last_timestamp = move || {
@@ -62,8 +68,17 @@ pub fn User() -> impl IntoView {
         )
     };
 
+    let is_own_page = move || match app_state.auth_state.get() {
+        AuthState::Authorized { user } => params.get().ok().and_then(|x| x.id) == Some(user.id),
+        AuthState::NotAuthorized => false,
+    };
+
     view! {
-        <Images images=images query_str=query_str />
+        <Show when=is_own_page fallback=|| ()>
+            <StatusDialog />
+            <FailedImages />
+        </Show>
+        <Images images=Signal::derive(move || images.get()) query_str=query_str />
     }
 }
 
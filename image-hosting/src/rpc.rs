@@ -0,0 +1,24 @@
+#![cfg(feature = "ssr")]
+
+use std::time::Duration;
+
+use common::{RpcRequest, RpcResponse};
+
+use crate::transport::transport;
+
+#[derive(Debug, Clone)]
+pub enum RpcError {
+    /// Request couldn't be sent, or connection was lost before reply
+    Unavailable,
+    /// No reply before deadline
+    Timeout,
+    /// Worker failed to process request
+    Failed(String),
+    /// Reply doesn't match request
+    UnexpectedResponse,
+}
+
+/// Send request to worker and wait for reply until timeout
+pub async fn call(request: &RpcRequest, timeout: Duration) -> Result<RpcResponse, RpcError> {
+    transport().call(request, timeout).await
+}
@@ -0,0 +1,310 @@
+#![cfg(feature = "ssr")]
+
+use std::{
+    num::NonZeroUsize,
+    ops::Range,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use common::storage::{storage, ObjectMetadata};
+use futures::TryStreamExt;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+/// Maximum number of remembered ETags of stored objects without known hash
+const ETAG_CACHE_CAPACITY: usize = 10_000;
+
+/// ETags of stored objects by key, valid while size and modification time are the same
+static ETAG_CACHE: Lazy<Mutex<LruCache<String, (ObjectMetadata, String)>>> = Lazy::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(ETAG_CACHE_CAPACITY).unwrap(),
+    ))
+});
+
+/// Where contents of response are taken from
+pub enum Content {
+    /// Object in storage, streamed
+    Stored(String),
+    /// Data in memory
+    Memory(Vec<u8>),
+}
+
+/// Image file with headers for caching
+pub struct ImageFile {
+    pub content: Content,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Quoted strong ETag
+    pub etag: String,
+    pub content_type: String,
+    /// `max-age` of `Cache-Control` header in seconds
+    pub max_age: u64,
+    /// Response depends on `Accept` header of request
+    pub vary_accept: bool,
+}
+
+/// Strong ETag of contents with given SHA-256 hash
+pub fn hash_etag(hash: &str) -> String {
+    format!("\"{hash}\"")
+}
+
+/// Strong ETag of stored object, computed from its contents once while it's unchanged
+pub async fn stored_etag(key: &str, metadata: ObjectMetadata) -> Result<String, String> {
+    if let Some((cached_metadata, etag)) = ETAG_CACHE.lock().unwrap().get(key) {
+        if *cached_metadata == metadata {
+            return Ok(etag.clone());
+        }
+    }
+    // Object is hashed in chunks to not load it into memory
+    let mut chunks = storage().load_range(key, 0..metadata.size).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = chunks.try_next().await? {
+        hasher.update(&chunk);
+    }
+    let etag = hash_etag(&format!("{:x}", hasher.finalize()));
+    ETAG_CACHE
+        .lock()
+        .unwrap()
+        .put(key.to_owned(), (metadata, etag.clone()));
+    Ok(etag)
+}
+
+/// Whether `Accept` header explicitly lists image format with extension `ext`
+/// (wildcards don't count, because browsers send them for formats they don't support)
+pub fn accepts_image(request: &HeaderMap, ext: &str) -> bool {
+    let mime = format!("image/{ext}");
+    request
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| {
+            let mut params = x.split(';').map(str::trim);
+            params.next().is_some_and(|x| x.eq_ignore_ascii_case(&mime))
+                && !params.any(|x| {
+                    x.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                })
+        })
+}
+
+/// Whether ETag matches any in list of header value, comparing them weakly
+fn etag_matches(list: &HeaderValue, etag: &str) -> bool {
+    let Ok(list) = list.to_str() else {
+        return false;
+    };
+    list.split(',').map(str::trim).any(|x| {
+        x == "*" || x.strip_prefix("W/").unwrap_or(x) == etag.strip_prefix("W/").unwrap_or(etag)
+    })
+}
+
+/// Whether date in header is not earlier than modification time, which is compared with
+/// precision of HTTP dates
+fn not_modified_since(date: &HeaderValue, modified: SystemTime) -> bool {
+    let Some(date) = date
+        .to_str()
+        .ok()
+        .and_then(|x| httpdate::parse_http_date(x).ok())
+    else {
+        return false;
+    };
+    let modified = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| Duration::from_secs(x.as_secs()))
+        .unwrap_or_default();
+    date.duration_since(SystemTime::UNIX_EPOCH)
+        .is_ok_and(|x| x >= modified)
+}
+
+/// Requested range of object with `size` bytes
+enum RangeRequest {
+    /// Whole object
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Parse single byte range from `Range` header, multiple ranges aren't supported so whole
+/// object is returned for them
+fn parse_range(range: &HeaderValue, size: u64) -> RangeRequest {
+    let Some(spec) = range.to_str().ok().and_then(|x| x.strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // Last `suffix` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            size.saturating_sub(suffix)..size
+        }
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+        _ => return RangeRequest::Full,
+    };
+    if range.start >= range.end {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(range)
+    }
+}
+
+impl ImageFile {
+    /// Headers describing the whole file, sent with all responses
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_str(&self.etag).unwrap());
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_str(&format!("max-age={}", self.max_age)).unwrap(),
+        );
+        if let Some(modified) = self.modified {
+            headers.insert(
+                header::LAST_MODIFIED,
+                HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
+            );
+        }
+        if self.vary_accept {
+            headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+        }
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers
+    }
+
+    /// Whether cached copy of client is up to date
+    fn is_not_modified(&self, request: &HeaderMap) -> bool {
+        // `If-Modified-Since` is ignored if `If-None-Match` is present
+        if let Some(if_none_match) = request.get(header::IF_NONE_MATCH) {
+            return etag_matches(if_none_match, &self.etag);
+        }
+        match (request.get(header::IF_MODIFIED_SINCE), self.modified) {
+            (Some(date), Some(modified)) => not_modified_since(date, modified),
+            _ => false,
+        }
+    }
+
+    /// Whether range can be returned, `If-Range` requires file to be unchanged
+    fn is_range_valid(&self, request: &HeaderMap) -> bool {
+        let Some(if_range) = request.get(header::IF_RANGE) else {
+            return true;
+        };
+        let Ok(if_range) = if_range.to_str() else {
+            return false;
+        };
+        if if_range.starts_with('"') {
+            // Strong comparison
+            return if_range == self.etag;
+        }
+        match (httpdate::parse_http_date(if_range), self.modified) {
+            (Ok(date), Some(modified)) => {
+                httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified)
+            }
+            _ => false,
+        }
+    }
+
+    async fn body(self, range: Range<u64>) -> Result<Body, (StatusCode, String)> {
+        Ok(match self.content {
+            Content::Stored(key) => Body::from_stream(
+                storage()
+                    .load_range(&key, range)
+                    .await
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?,
+            ),
+            Content::Memory(mut data) => {
+                data.truncate(range.end as usize);
+                data.drain(..range.start as usize);
+                Body::from(data)
+            }
+        })
+    }
+
+    /// Response to GET or HEAD request, honouring conditional and range headers
+    pub async fn into_response(
+        self,
+        method: &Method,
+        request: &HeaderMap,
+    ) -> Result<Response, (StatusCode, String)> {
+        let mut headers = self.headers();
+        if self.is_not_modified(request) {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+        let range = match request.get(header::RANGE) {
+            Some(range) if self.is_range_valid(request) => parse_range(range, self.size),
+            _ => RangeRequest::Full,
+        };
+        let (status, range) = match range {
+            RangeRequest::Full => (StatusCode::OK, 0..self.size),
+            RangeRequest::Partial(range) => {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!(
+                        "bytes {}-{}/{}",
+                        range.start,
+                        range.end - 1,
+                        self.size
+                    ))
+                    .unwrap(),
+                );
+                (StatusCode::PARTIAL_CONTENT, range)
+            }
+            RangeRequest::Unsatisfiable => {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes */{}", self.size)).unwrap(),
+                );
+                return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+            }
+        };
+        headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from(range.end - range.start),
+        );
+
+        if method == Method::HEAD {
+            return Ok((status, headers).into_response());
+        }
+        let body = self.body(range).await?;
+        Ok((status, headers, body).into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(range: &str, size: u64) -> Option<Range<u64>> {
+        match parse_range(&HeaderValue::from_str(range).unwrap(), size) {
+            RangeRequest::Full => Some(0..size),
+            RangeRequest::Partial(range) => Some(range),
+            RangeRequest::Unsatisfiable => None,
+        }
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse("bytes=0-9", 100), Some(0..10));
+        assert_eq!(parse("bytes=90-", 100), Some(90..100));
+        assert_eq!(parse("bytes=-10", 100), Some(90..100));
+        assert_eq!(parse("bytes=50-200", 100), Some(50..100));
+        assert_eq!(parse("bytes=0-18446744073709551615", 100), Some(0..100));
+        assert_eq!(parse("bytes=0-1,5-6", 100), Some(0..100));
+        assert_eq!(parse("bytes=100-", 100), None);
+        assert_eq!(parse("bytes=-0", 100), None);
+        assert_eq!(parse("bytes=-10", 0), None);
+    }
+}
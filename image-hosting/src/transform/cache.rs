@@ -0,0 +1,204 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lru::LruCache;
+
+/// Folder for files being written, so that partially written files aren't read
+const TEMP_PATH: &str = "tmp";
+
+/// Transformed images stored in local folder, least recently used ones are removed
+/// when total size exceeds limit
+pub struct TransformCache {
+    root: PathBuf,
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+/// Sizes of cached files by name, in order of use
+struct CacheIndex {
+    files: LruCache<String, u64>,
+    total_size: u64,
+}
+
+impl CacheIndex {
+    fn insert(&mut self, name: String, size: u64) {
+        if let Some(old_size) = self.files.put(name, size) {
+            self.total_size -= old_size;
+        }
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(size) = self.files.pop(name) {
+            self.total_size -= size;
+        }
+    }
+
+    /// Remove least recently used files from index until size is within limit, returns their names
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_size > max_size {
+            let Some((name, size)) = self.files.pop_lru() else {
+                break;
+            };
+            self.total_size -= size;
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+impl TransformCache {
+    /// Open cache folder, files already in it are used in order of modification time
+    pub async fn open(root: impl Into<PathBuf>, max_size: u64) -> Result<Self, String> {
+        let root = root.into();
+        // Files left by interrupted writes
+        if let Err(e) = tokio::fs::remove_dir_all(root.join(TEMP_PATH)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(format!("Can't clear transform cache temporary folder: {e}"));
+            }
+        }
+        tokio::fs::create_dir_all(root.join(TEMP_PATH))
+            .await
+            .map_err(|e| format!("Can't create transform cache folder: {e}"))?;
+
+        let mut files = Vec::new();
+        let mut dir = tokio::fs::read_dir(&root)
+            .await
+            .map_err(|e| format!("Can't read transform cache folder: {e}"))?;
+        while let Some(entry) = dir
+            .next_entry()
+            .await
+            .map_err(|e| format!("Can't read transform cache folder: {e}"))?
+        {
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                files.push((metadata.modified().ok(), name, metadata.len()));
+            }
+        }
+        files.sort();
+
+        let mut index = CacheIndex {
+            files: LruCache::unbounded(),
+            total_size: 0,
+        };
+        for (_, name, size) in files {
+            index.insert(name, size);
+        }
+        let cache = Self {
+            root,
+            max_size,
+            index: Mutex::new(index),
+        };
+        let evicted = cache.index.lock().unwrap().evict(max_size);
+        cache.remove_files(evicted).await;
+        Ok(cache)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    /// Contents of cached file, `None` if it's not cached
+    pub async fn get(&self, name: &str) -> Option<Vec<u8>> {
+        self.index.lock().unwrap().files.get(name)?;
+        match tokio::fs::read(self.path(name)).await {
+            Ok(x) => Some(x),
+            Err(_) => {
+                // File was removed outside of cache
+                self.index.lock().unwrap().remove(name);
+                None
+            }
+        }
+    }
+
+    /// Add file to cache, removing least recently used files if it's full
+    pub async fn put(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return Ok(());
+        }
+        // Same file can be written concurrently, so each write has its own temporary file
+        let tmp_path = self
+            .root
+            .join(TEMP_PATH)
+            .join(format!("{name}.{}", uuid::Uuid::new_v4()));
+        let res = match tokio::fs::write(&tmp_path, data).await {
+            Ok(_) => tokio::fs::rename(&tmp_path, self.path(name)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            remove_file(&tmp_path).await;
+            return Err(format!("Can't write to transform cache: {e}"));
+        }
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            index.insert(name.to_owned(), size);
+            index.evict(self.max_size)
+        };
+        self.remove_files(evicted).await;
+        Ok(())
+    }
+
+    /// Remove cached files whose names start with `prefix`
+    pub async fn remove_prefix(&self, prefix: &str) {
+        let removed: Vec<_> = {
+            let mut index = self.index.lock().unwrap();
+            let names: Vec<_> = index
+                .files
+                .iter()
+                .map(|(name, _)| name)
+                .filter(|name| name.starts_with(prefix))
+                .cloned()
+                .collect();
+            for name in &names {
+                index.remove(name);
+            }
+            names
+        };
+        self.remove_files(removed).await;
+    }
+
+    async fn remove_files(&self, names: Vec<String>) {
+        for name in names {
+            remove_file(&self.path(&name)).await;
+        }
+    }
+}
+
+async fn remove_file(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            tracing::error!("Can't remove {} from transform cache: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_writes_of_same_file() {
+        let root = std::env::temp_dir().join(format!("transform-cache-{}", uuid::Uuid::new_v4()));
+        let cache = TransformCache::open(&root, 1 << 20).await.unwrap();
+
+        let data: Vec<_> = (0..8u8).map(|x| vec![x; 4096]).collect();
+        let results =
+            futures::future::join_all(data.iter().map(|x| cache.put("image.webp", x))).await;
+        assert!(results.iter().all(|x| x.is_ok()));
+        let cached = cache.get("image.webp").await.unwrap();
+        assert!(data.contains(&cached));
+        assert_eq!(cache.index.lock().unwrap().total_size, 4096);
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,463 @@
+#![cfg(feature = "ssr")]
+
+use std::{io::Cursor, sync::OnceLock};
+
+use axum::http::StatusCode;
+use common::storage::{get_blob_hash, get_original_key, storage};
+use hmac::{Mac, SimpleHmac};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+};
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+use tracing_unwrap::ResultExt;
+
+use self::cache::TransformCache;
+use crate::{
+    components::image::GetImageFileQuery,
+    db::image::get_image_format_and_blob_hash,
+    serve::{hash_etag, Content, ImageFile},
+};
+
+mod cache;
+
+/// Maximum width and height of transformed image
+const MAX_DIMENSION: u32 = 4096;
+/// Quality of lossy formats if not set
+const DEFAULT_QUALITY: u8 = 80;
+/// Encoding speed of AVIF from 1 (slowest) to 10
+const AVIF_SPEED: u8 = 8;
+/// Maximum number of images transformed at the same time
+const MAX_CONCURRENT_TRANSFORMS: usize = 4;
+/// Formats that can be requested without signature in addition to format of original
+const UNSIGNED_FORMATS: [OutputFormat; 3] =
+    [OutputFormat::Jpeg, OutputFormat::Webp, OutputFormat::Avif];
+
+/// Preview of image for link previews of social networks
+pub const PREVIEW_PARAMS: TransformParams = TransformParams {
+    width: Some(1200),
+    height: Some(630),
+    fit: Fit::Cover,
+    format: OutputFormat::Jpeg,
+    quality: DEFAULT_QUALITY,
+};
+
+static TRANSFORMER: OnceLock<Transformer> = OnceLock::new();
+
+/// How image is fitted into requested width and height
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Whole image is scaled down to fit inside, keeping aspect ratio
+    Contain,
+    /// Image is scaled to cover the whole area and cropped at center
+    Cover,
+}
+
+/// Format of transformed image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    fn from_extension(ext: &str) -> Option<Self> {
+        Some(match ext {
+            "jpg" | "jpeg" => Self::Jpeg,
+            "png" => Self::Png,
+            "gif" => Self::Gif,
+            "webp" => Self::Webp,
+            "avif" => Self::Avif,
+            _ => return None,
+        })
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// Whether quality affects encoding
+    fn is_lossy(self) -> bool {
+        matches!(self, Self::Jpeg | Self::Avif)
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+}
+
+/// Validated parameters of image transformation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformParams {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: OutputFormat,
+    /// Quality of lossy formats from 1 to 100
+    pub quality: u8,
+}
+
+impl TransformParams {
+    /// Parameters from query of image with extension `ext`, `None` if image isn't transformed
+    pub fn from_query(q: &GetImageFileQuery, ext: &str) -> Result<Option<Self>, String> {
+        if q.w.is_none() && q.h.is_none() && q.fit.is_none() && q.format.is_none() && q.q.is_none()
+        {
+            return Ok(None);
+        }
+        for size in [q.w, q.h].into_iter().flatten() {
+            if size == 0 || size > MAX_DIMENSION {
+                return Err(format!("Size must be from 1 to {MAX_DIMENSION}"));
+            }
+        }
+        let fit = match q.fit.as_deref() {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") => Fit::Cover,
+            Some(_) => return Err("Unknown fit".to_owned()),
+        };
+        // Image is only cropped if both width and height are set
+        let fit = if q.w.is_some() && q.h.is_some() {
+            fit
+        } else {
+            Fit::Contain
+        };
+        let format = q.format.as_deref().unwrap_or(ext);
+        let format = OutputFormat::from_extension(format).ok_or("Unknown format")?;
+        let quality = q.q.unwrap_or(DEFAULT_QUALITY);
+        if !(1..=100).contains(&quality) {
+            return Err("Quality must be from 1 to 100".to_owned());
+        }
+        // Quality of lossless formats is ignored, so the same image has the same parameters
+        let quality = if format.is_lossy() {
+            quality
+        } else {
+            DEFAULT_QUALITY
+        };
+        Ok(Some(Self {
+            width: q.w,
+            height: q.h,
+            fit,
+            format,
+            quality,
+        }))
+    }
+
+    /// Unique name of transformed image, also signed to allow arbitrary parameters
+    fn name(&self, id: i64) -> String {
+        let fit = match self.fit {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        };
+        let size = format!("{}x{}", self.width.unwrap_or(0), self.height.unwrap_or(0));
+        let ext = self.format.extension();
+        if self.format.is_lossy() {
+            format!("{id}-{size}-{fit}-q{}.{ext}", self.quality)
+        } else {
+            format!("{id}-{size}-{fit}.{ext}")
+        }
+    }
+
+    /// Query of URL of image with these parameters, without signature
+    fn query(&self) -> String {
+        let mut query = Vec::new();
+        if let Some(width) = self.width {
+            query.push(format!("w={width}"));
+        }
+        if let Some(height) = self.height {
+            query.push(format!("h={height}"));
+        }
+        if self.fit == Fit::Cover {
+            query.push("fit=cover".to_owned());
+        }
+        query.push(format!("format={}", self.format.extension()));
+        if self.format.is_lossy() {
+            query.push(format!("q={}", self.quality));
+        }
+        query.join("&")
+    }
+
+    /// Whether parameters can be requested without signature: one of allowed sizes,
+    /// one of common formats and default quality
+    fn is_preset(&self, ext: &str, allowed_sizes: &[u32]) -> bool {
+        let size = match (self.width, self.height) {
+            (Some(size), None) | (None, Some(size)) => size,
+            _ => return false,
+        };
+        allowed_sizes.contains(&size)
+            && (UNSIGNED_FORMATS.contains(&self.format)
+                || OutputFormat::from_extension(ext) == Some(self.format))
+            && self.quality == DEFAULT_QUALITY
+    }
+
+    /// Apply transformation to encoded image, returns encoded result
+    fn apply(&self, image_buf: &[u8]) -> Result<Vec<u8>, String> {
+        let mut decoder = ImageReader::new(Cursor::new(image_buf))
+            .with_guessed_format()
+            .map_err(|e| format!("Can't read image: {e}"))?
+            .into_decoder()
+            .map_err(|e| format!("Can't read image: {e}"))?;
+        let orientation = decoder.orientation();
+        let mut image =
+            DynamicImage::from_decoder(decoder).map_err(|e| format!("Can't read image: {e}"))?;
+        if let Ok(orientation) = orientation {
+            image.apply_orientation(orientation);
+        }
+
+        let image = self.resize(image);
+        let image = if image.color().has_alpha() && self.format != OutputFormat::Jpeg {
+            DynamicImage::ImageRgba8(image.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        };
+
+        let mut buf = Cursor::new(Vec::new());
+        match self.format {
+            OutputFormat::Jpeg => {
+                image.write_with_encoder(JpegEncoder::new_with_quality(&mut buf, self.quality))
+            }
+            OutputFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut buf,
+                AVIF_SPEED,
+                self.quality,
+            )),
+            OutputFormat::Png => image.write_to(&mut buf, ImageFormat::Png),
+            OutputFormat::Gif => image.write_to(&mut buf, ImageFormat::Gif),
+            // Only lossless WebP encoding is supported
+            OutputFormat::Webp => image.write_to(&mut buf, ImageFormat::WebP),
+        }
+        .map_err(|e| format!("Can't encode image: {e}"))?;
+        Ok(buf.into_inner())
+    }
+
+    fn resize(&self, image: DynamicImage) -> DynamicImage {
+        match (self.width, self.height, self.fit) {
+            (None, None, _) => image,
+            (Some(width), Some(height), Fit::Cover) => {
+                image.resize_to_fill(width, height, FilterType::Lanczos3)
+            }
+            (width, height, _) => {
+                let width = width.unwrap_or(u32::MAX);
+                let height = height.unwrap_or(u32::MAX);
+                // Images are only scaled down
+                if image.width() <= width && image.height() <= height {
+                    image
+                } else {
+                    image.resize(width, height, FilterType::Lanczos3)
+                }
+            }
+        }
+    }
+}
+
+/// Image transformation settings, read from environment variables
+#[derive(Debug, Clone)]
+pub struct TransformSettings {
+    /// Folder of cached transformed images
+    pub cache_path: String,
+    /// Maximum total size of cached images in bytes
+    pub cache_max_size: u64,
+    /// Widths or heights that can be requested without signature
+    pub allowed_sizes: Vec<u32>,
+}
+
+impl TransformSettings {
+    /// Read settings from `TRANSFORM_CACHE_PATH`, `TRANSFORM_CACHE_MAX_MB`
+    /// and `TRANSFORM_SIZES` (comma-separated list)
+    pub fn from_env() -> Result<Self, String> {
+        let cache_max_mb = match std::env::var("TRANSFORM_CACHE_MAX_MB") {
+            Ok(x) => x
+                .parse::<u64>()
+                .map_err(|_| "Can't parse TRANSFORM_CACHE_MAX_MB".to_owned())?,
+            Err(_) => 1024,
+        };
+        let allowed_sizes = std::env::var("TRANSFORM_SIZES")
+            .unwrap_or_else(|_| "64,128,256,320,480,640,800,1024,1280,1920".to_owned())
+            .split(',')
+            .map(|x| x.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "Can't parse TRANSFORM_SIZES".to_owned())?;
+        Ok(Self {
+            cache_path: std::env::var("TRANSFORM_CACHE_PATH")
+                .unwrap_or_else(|_| "transform_cache".to_owned()),
+            cache_max_size: cache_max_mb * 1024 * 1024,
+            allowed_sizes,
+        })
+    }
+}
+
+struct Transformer {
+    allowed_sizes: Vec<u32>,
+    cache: TransformCache,
+    permits: Semaphore,
+}
+
+/// Open cache of transformed images with settings from environment variables
+pub async fn init_transform() -> Result<(), String> {
+    let settings = TransformSettings::from_env()?;
+    let transformer = Transformer {
+        allowed_sizes: settings.allowed_sizes,
+        cache: TransformCache::open(settings.cache_path, settings.cache_max_size).await?,
+        permits: Semaphore::new(MAX_CONCURRENT_TRANSFORMS),
+    };
+    TRANSFORMER
+        .set(transformer)
+        .map_err(|_| "Image transformation is already initialized".to_owned())
+}
+
+fn transformer() -> &'static Transformer {
+    TRANSFORMER
+        .get()
+        .expect("Image transformation is not initialized")
+}
+
+fn new_mac() -> SimpleHmac<Sha256> {
+    SimpleHmac::new_from_slice(crate::APP_SECRET.get().unwrap().as_bytes()).unwrap_or_log()
+}
+
+/// Hex-encoded signature allowing any parameters of transformation
+fn sign(id: i64, params: &TransformParams) -> String {
+    let mut mac = new_mac();
+    mac.update(params.name(id).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// URL of transformed image with extension `ext`, signed if parameters aren't allowed without it
+pub fn transformed_image_url(id: i64, ext: &str, params: &TransformParams) -> String {
+    let mut url = format!("/api/image/{id}.{ext}?{}", params.query());
+    if !params.is_preset(ext, &transformer().allowed_sizes) {
+        url += &format!("&sig={}", sign(id, params));
+    }
+    url
+}
+
+fn verify_signature(id: i64, params: &TransformParams, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = new_mac();
+    mac.update(params.name(id).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Remove cached transformations of deleted image
+pub async fn remove_cached(id: i64) {
+    transformer().cache.remove_prefix(&format!("{id}-")).await;
+}
+
+/// Transformed image of image with extension `ext`. Parameters must be signed or be a preset,
+/// so that number of different transformations of each image is limited
+pub async fn get_transformed_image(
+    id: i64,
+    ext: &str,
+    params: TransformParams,
+    signature: Option<&str>,
+) -> Result<ImageFile, (StatusCode, String)> {
+    let transformer = transformer();
+    let allowed = match signature {
+        Some(signature) => verify_signature(id, &params, signature),
+        None => params.is_preset(ext, &transformer.allowed_sizes),
+    };
+    if !allowed {
+        return Err((StatusCode::FORBIDDEN, String::new()));
+    }
+
+    let (db_format, blob_hash) = get_image_format_and_blob_hash(id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db_error".to_owned()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, String::new()))?;
+    if db_format != ext {
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    }
+
+    let name = params.name(id);
+    let data = match transformer.cache.get(&name).await {
+        Some(x) => x,
+        None => {
+            let _permit = transformer.permits.acquire().await.unwrap();
+            let image_buf = storage()
+                .load(&get_original_key(id, ext, blob_hash.as_deref()))
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+            let data = tokio::task::spawn_blocking(move || params.apply(&image_buf))
+                .await
+                .unwrap_or_log()
+                .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+            if let Err(e) = transformer.cache.put(&name, &data).await {
+                tracing::error!("{e}");
+            }
+            data
+        }
+    };
+
+    Ok(ImageFile {
+        size: data.len() as u64,
+        etag: hash_etag(&get_blob_hash(&data)),
+        content: Content::Memory(data),
+        modified: None,
+        content_type: params.format.mime().to_owned(),
+        max_age: 31536000,
+        vary_accept: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsigned_presets() {
+        let sizes = [320, 640];
+        let params = |width, height, format, quality| TransformParams {
+            width,
+            height,
+            fit: Fit::Contain,
+            format,
+            quality,
+        };
+        assert!(
+            params(Some(320), None, OutputFormat::Webp, DEFAULT_QUALITY).is_preset("png", &sizes)
+        );
+        assert!(
+            params(None, Some(640), OutputFormat::Png, DEFAULT_QUALITY).is_preset("png", &sizes)
+        );
+        assert!(
+            !params(None, Some(640), OutputFormat::Png, DEFAULT_QUALITY).is_preset("gif", &sizes)
+        );
+        assert!(
+            !params(Some(320), Some(320), OutputFormat::Webp, DEFAULT_QUALITY)
+                .is_preset("png", &sizes)
+        );
+        assert!(
+            !params(Some(321), None, OutputFormat::Webp, DEFAULT_QUALITY).is_preset("png", &sizes)
+        );
+        assert!(!params(Some(320), None, OutputFormat::Jpeg, 81).is_preset("png", &sizes));
+        assert!(!PREVIEW_PARAMS.is_preset("jpg", &sizes));
+    }
+
+    #[test]
+    fn lossless_names_ignore_quality() {
+        assert_eq!(PREVIEW_PARAMS.name(1), "1-1200x630-cover-q80.jpg");
+        let params = TransformParams {
+            format: OutputFormat::Png,
+            ..PREVIEW_PARAMS
+        };
+        assert_eq!(params.name(1), "1-1200x630-cover.png");
+        assert_eq!(params.query(), "w=1200&h=630&fit=cover&format=png");
+    }
+}
@@ -0,0 +1,359 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use common::{CallbackMessage, RpcRequest, RpcResponse, WorkerMessage};
+use tokio::sync::{mpsc, oneshot, Mutex, OnceCell, OwnedSemaphorePermit, Semaphore};
+use worker::{
+    search_backend::init_search_backend,
+    status::{set_callback, Callback},
+    ModelSettings,
+};
+
+use super::{handle_callback, SentJobs, Transport};
+use crate::{
+    db::outbox::{delete_outbox_messages, release_other_outbox_messages, OutboxMessage},
+    rpc::RpcError,
+};
+
+/// Maximum number of jobs waiting for processing, others wait in outbox
+const JOBS_QUEUE_CAPACITY: usize = 16;
+/// Maximum number of jobs processed at the same time
+const MAX_JOBS_IN_FLIGHT: usize = 4;
+/// Maximum number of retries of failed job
+const MAX_RETRIES: u32 = 5;
+/// Delay before first retry, doubled for each next retry
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum time of waiting for jobs in progress on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Processing of jobs and removal of their outbox messages
+#[async_trait]
+trait JobHandler: Send + Sync + 'static {
+    async fn process(&self, job: WorkerMessage) -> Result<(), ()>;
+    /// Remove outbox message of finished job
+    async fn complete(&self, outbox_id: i64);
+    /// Make messages claimed by previous run deliverable again, except `except_ids`
+    async fn release_unfinished(&self, except_ids: &[i64]);
+}
+
+/// Processes jobs with worker and removes them from database outbox
+struct WorkerJobHandler;
+
+#[async_trait]
+impl JobHandler for WorkerJobHandler {
+    async fn process(&self, job: WorkerMessage) -> Result<(), ()> {
+        worker::process_job(job).await
+    }
+
+    async fn complete(&self, outbox_id: i64) {
+        // Message is delivered again after its claim expires
+        if let Err(e) = delete_outbox_messages(&[outbox_id]).await {
+            tracing::error!("Can't remove outbox message {outbox_id}: {e}");
+        }
+    }
+
+    async fn release_unfinished(&self, except_ids: &[i64]) {
+        if let Err(e) = release_other_outbox_messages(except_ids).await {
+            tracing::error!("Can't release unfinished outbox messages: {e}");
+        }
+    }
+}
+
+/// Worker pipeline running inside web server, jobs are passed through in-process queue.
+/// Messages stay claimed in outbox until their jobs are finished, so jobs that were queued
+/// or in progress when server stopped are processed again after restart.
+/// All web servers sharing database must use embedded worker
+pub struct EmbeddedTransport {
+    handler: Arc<dyn JobHandler>,
+    jobs_tx: mpsc::Sender<(i64, WorkerMessage)>,
+    jobs_rx: Mutex<Option<mpsc::Receiver<(i64, WorkerMessage)>>>,
+    /// Set after claims of previous run were released
+    recovered: OnceCell<()>,
+}
+
+impl EmbeddedTransport {
+    /// Initialize worker (search backend, models, callback) with settings from environment variables
+    pub async fn new() -> anyhow::Result<Self> {
+        init_search_backend().await.map_err(anyhow::Error::msg)?;
+        worker::initialize_models(&ModelSettings::from_env().map_err(anyhow::Error::msg)?)?;
+        set_callback(Box::new(EmbeddedCallback)).map_err(anyhow::Error::msg)?;
+        Ok(Self::with_handler(Arc::new(WorkerJobHandler)))
+    }
+
+    fn with_handler(handler: Arc<dyn JobHandler>) -> Self {
+        let (jobs_tx, jobs_rx) = mpsc::channel(JOBS_QUEUE_CAPACITY);
+        Self {
+            handler,
+            jobs_tx,
+            jobs_rx: Mutex::const_new(Some(jobs_rx)),
+            recovered: OnceCell::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for EmbeddedTransport {
+    async fn send_jobs(&self, messages: &[OutboxMessage]) -> anyhow::Result<SentJobs> {
+        // Jobs claimed before restart aren't in queue, messages being sent are claimed by this run
+        self.recovered
+            .get_or_init(|| async {
+                let ids: Vec<_> = messages.iter().map(|x| x.id).collect();
+                self.handler.release_unfinished(&ids).await;
+            })
+            .await;
+
+        let mut sent = SentJobs::default();
+        for message in messages {
+            match serde_json::from_slice(&message.payload) {
+                Ok(job) => {
+                    // Queue is closed on shutdown, remaining messages stay in outbox
+                    if self.jobs_tx.send((message.id, job)).await.is_err() {
+                        break;
+                    }
+                    sent.taken.push(message.id);
+                }
+                // Malformed message stays in outbox, so it can be inspected
+                Err(e) => tracing::error!("Can't parse outbox message {}: {e}", message.id),
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn call(&self, request: &RpcRequest, timeout: Duration) -> Result<RpcResponse, RpcError> {
+        match tokio::time::timeout(timeout, worker::process_request(request.clone())).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(error)) => Err(RpcError::Failed(error)),
+            Err(_) => Err(RpcError::Timeout),
+        }
+    }
+
+    async fn run(&self, mut shutdown_rx: oneshot::Receiver<()>) {
+        let Some(mut jobs_rx) = self.jobs_rx.lock().await.take() else {
+            tracing::error!("Embedded worker is already running");
+            return;
+        };
+        let in_flight = Arc::new(Semaphore::new(MAX_JOBS_IN_FLIGHT));
+
+        tracing::info!("Embedded worker is processing jobs...");
+        loop {
+            let job = tokio::select! {
+                _ = &mut shutdown_rx => break,
+                job = jobs_rx.recv() => job,
+            };
+            let Some(job) = job else {
+                break;
+            };
+            let permit = in_flight.clone().acquire_owned().await.unwrap();
+            tokio::spawn(run_job(Arc::clone(&self.handler), job, permit));
+        }
+
+        // Process jobs that were already queued, new jobs stay in outbox
+        jobs_rx.close();
+        let drain = async {
+            while let Some(job) = jobs_rx.recv().await {
+                let permit = in_flight.clone().acquire_owned().await.unwrap();
+                tokio::spawn(run_job(Arc::clone(&self.handler), job, permit));
+            }
+            let _ = in_flight.acquire_many(MAX_JOBS_IN_FLIGHT as u32).await;
+        };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+            tracing::warn!("Embedded worker shut down with unfinished jobs");
+        }
+    }
+}
+
+/// Process job, retrying with exponential delay if it fails, then remove it from outbox
+async fn run_job(
+    handler: Arc<dyn JobHandler>,
+    (outbox_id, job): (i64, WorkerMessage),
+    permit: OwnedSemaphorePermit,
+) {
+    let mut retry = 0;
+    while handler.process(job.clone()).await.is_err() {
+        if retry == MAX_RETRIES {
+            // Failure is reported to user, who can retry it
+            tracing::error!("Job failed after {MAX_RETRIES} retries: {job:?}");
+            break;
+        }
+        tokio::time::sleep(RETRY_DELAY * 2u32.pow(retry)).await;
+        retry += 1;
+    }
+    handler.complete(outbox_id).await;
+    drop(permit);
+}
+
+/// Passes messages from worker directly to web server
+struct EmbeddedCallback;
+
+#[async_trait]
+impl Callback for EmbeddedCallback {
+    async fn send(&self, message: CallbackMessage) -> Result<(), String> {
+        handle_callback(message)
+            .await
+            .map_err(|_| "can't handle message".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use common::DeleteMessage;
+
+    use super::*;
+
+    /// Records handled jobs, processing of each job waits for permit of `gate`
+    struct TestHandler {
+        events: StdMutex<Vec<String>>,
+        gate: Semaphore,
+        fail: bool,
+    }
+
+    impl TestHandler {
+        fn new(fail: bool) -> Arc<Self> {
+            Arc::new(Self {
+                events: StdMutex::new(Vec::new()),
+                gate: Semaphore::new(0),
+                fail,
+            })
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+
+        async fn wait_for(&self, event: &str) {
+            while !self.events().iter().any(|x| x == event) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    #[async_trait]
+    impl JobHandler for TestHandler {
+        async fn process(&self, job: WorkerMessage) -> Result<(), ()> {
+            self.gate.acquire().await.unwrap().forget();
+            let WorkerMessage::Delete(message) = job else {
+                panic!("unexpected job");
+            };
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("process {}", message.id));
+            if self.fail {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }
+
+        async fn complete(&self, outbox_id: i64) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("complete {outbox_id}"));
+        }
+
+        async fn release_unfinished(&self, except_ids: &[i64]) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("release except {except_ids:?}"));
+        }
+    }
+
+    fn outbox_message(id: i64) -> OutboxMessage {
+        let job = WorkerMessage::Delete(DeleteMessage {
+            id,
+            format: "png".to_owned(),
+            thumbnail_widths: Vec::new(),
+        });
+        OutboxMessage {
+            id,
+            routing_key: String::new(),
+            payload: serde_json::to_vec(&job).unwrap(),
+        }
+    }
+
+    fn start(handler: Arc<TestHandler>) -> (Arc<EmbeddedTransport>, oneshot::Sender<()>) {
+        let transport = Arc::new(EmbeddedTransport::with_handler(handler));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let transport_ = Arc::clone(&transport);
+        tokio::spawn(async move { transport_.run(shutdown_rx).await });
+        (transport, shutdown_tx)
+    }
+
+    #[tokio::test]
+    async fn messages_are_removed_after_processing() {
+        let handler = TestHandler::new(false);
+        let (transport, _shutdown_tx) = start(Arc::clone(&handler));
+
+        let malformed = OutboxMessage {
+            id: 3,
+            routing_key: String::new(),
+            payload: b"{".to_vec(),
+        };
+        let sent = transport
+            .send_jobs(&[outbox_message(1), outbox_message(2), malformed])
+            .await
+            .unwrap();
+        assert!(sent.delivered.is_empty());
+        assert_eq!(sent.taken, [1, 2]);
+        assert_eq!(handler.events(), ["release except [1, 2, 3]"]);
+
+        // Messages stay in outbox while jobs are in progress
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(handler.events().len(), 1);
+
+        handler.gate.add_permits(2);
+        handler.wait_for("complete 1").await;
+        handler.wait_for("complete 2").await;
+        let events = handler.events();
+        for id in [1, 2] {
+            let processed = events.iter().position(|x| *x == format!("process {id}"));
+            let completed = events.iter().position(|x| *x == format!("complete {id}"));
+            assert!(processed < completed);
+        }
+        assert!(!events.contains(&"complete 3".to_owned()));
+
+        // Claims of previous run are released only once
+        transport.send_jobs(&[outbox_message(4)]).await.unwrap();
+        assert_eq!(
+            handler
+                .events()
+                .iter()
+                .filter(|x| x.starts_with("release"))
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_job_is_retried_before_removal() {
+        let handler = TestHandler::new(true);
+        handler.gate.add_permits(MAX_RETRIES as usize + 1);
+        let (transport, _shutdown_tx) = start(Arc::clone(&handler));
+
+        transport.send_jobs(&[outbox_message(1)]).await.unwrap();
+        handler.wait_for("complete 1").await;
+        let processed = handler
+            .events()
+            .iter()
+            .filter(|x| *x == "process 1")
+            .count();
+        assert_eq!(processed, MAX_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn unfinished_jobs_stay_in_outbox_on_shutdown() {
+        let handler = TestHandler::new(false);
+        let (transport, shutdown_tx) = start(Arc::clone(&handler));
+        transport.send_jobs(&[outbox_message(1)]).await.unwrap();
+
+        // Job can't finish, so it's left for next run
+        tokio::time::pause();
+        shutdown_tx.send(()).unwrap();
+        tokio::time::sleep(SHUTDOWN_TIMEOUT * 2).await;
+        assert!(!handler.events().contains(&"complete 1".to_owned()));
+    }
+}
@@ -0,0 +1,131 @@
+#![cfg(feature = "ssr")]
+
+use std::{sync::OnceLock, time::Duration};
+
+use async_trait::async_trait;
+use common::{
+    CallbackMessage, ProcessingStatus, RpcRequest, RpcResponse, StatusMessage, ThumbnailsMessage,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    db::{
+        image::{set_image_status, set_image_thumbnails},
+        outbox::OutboxMessage,
+    },
+    events::{publish_event, ServerEvent},
+    image::ImageStatus,
+    rpc::RpcError,
+};
+
+pub use rabbitmq::{RabbitMQSettings, RabbitMQTransport};
+
+#[cfg(feature = "embedded")]
+pub use embedded::EmbeddedTransport;
+
+#[cfg(feature = "embedded")]
+mod embedded;
+mod rabbitmq;
+
+static TRANSPORT: OnceLock<Box<dyn Transport>> = OnceLock::new();
+
+/// Outcome of delivering outbox messages, messages that are in neither list are postponed
+#[derive(Debug, Default)]
+pub struct SentJobs {
+    /// IDs of messages accepted by worker, they are removed from outbox
+    pub delivered: Vec<i64>,
+    /// IDs of messages taken for processing, they stay claimed in outbox until transport
+    /// removes them after processing
+    pub taken: Vec<i64>,
+}
+
+/// Delivery of messages between web server and worker
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Deliver background jobs from outbox
+    async fn send_jobs(&self, messages: &[OutboxMessage]) -> anyhow::Result<SentJobs>;
+    /// Send request to worker and wait for reply until timeout
+    async fn call(&self, request: &RpcRequest, timeout: Duration) -> Result<RpcResponse, RpcError>;
+    /// Receive messages from worker until shutdown signal
+    async fn run(&self, shutdown_rx: oneshot::Receiver<()>);
+}
+
+/// Create transport selected by `EMBEDDED_WORKER` environment variable and make it globally available
+pub async fn init_transport() -> anyhow::Result<()> {
+    let embedded_worker = std::env::var("EMBEDDED_WORKER")
+        .map(|x| x == "true")
+        .unwrap_or(false);
+    let transport: Box<dyn Transport> = if embedded_worker {
+        #[cfg(feature = "embedded")]
+        {
+            Box::new(EmbeddedTransport::new().await?)
+        }
+        #[cfg(not(feature = "embedded"))]
+        {
+            anyhow::bail!("Embedded worker requires building with `embedded` feature");
+        }
+    } else {
+        Box::new(RabbitMQTransport::new(RabbitMQSettings::from_env()?))
+    };
+    TRANSPORT
+        .set(transport)
+        .map_err(|_| anyhow::anyhow!("Transport is already initialized"))
+}
+
+/// Global transport, panics if `init_transport` wasn't called
+pub fn transport() -> &'static dyn Transport {
+    TRANSPORT
+        .get()
+        .expect("Transport is not initialized")
+        .as_ref()
+}
+
+/// Handle message from worker, error means that it should be delivered again
+pub async fn handle_callback(message: CallbackMessage) -> Result<(), ()> {
+    match message {
+        CallbackMessage::Status(message) => update_image_status(message).await,
+        CallbackMessage::Thumbnails(message) => update_image_thumbnails(message).await,
+    }
+}
+
+async fn update_image_thumbnails(message: ThumbnailsMessage) -> Result<(), ()> {
+    let thumbnail_widths: Vec<_> = message
+        .thumbnail_widths
+        .into_iter()
+        .map(|x| x as i32)
+        .collect();
+    set_image_thumbnails(
+        message.id,
+        message.width as i32,
+        message.height as i32,
+        &thumbnail_widths,
+    )
+    .await
+    .map_err(|e| tracing::error!("Can't update image thumbnails: {e}"))
+}
+
+async fn update_image_status(message: StatusMessage) -> Result<(), ()> {
+    let image_id = message.id;
+    let (status, event) = match message.status {
+        ProcessingStatus::Processing => (Some(ImageStatus::Processing), None),
+        ProcessingStatus::ThumbnailReady => (None, Some(ServerEvent::ThumbnailReady { image_id })),
+        ProcessingStatus::Indexed => (None, Some(ServerEvent::Indexed { image_id })),
+        ProcessingStatus::Done => (
+            Some(ImageStatus::Done),
+            Some(ServerEvent::Processed { image_id }),
+        ),
+        ProcessingStatus::Failed => (
+            Some(ImageStatus::Failed),
+            Some(ServerEvent::ProcessingFailed { image_id }),
+        ),
+    };
+    if let Some(status) = status {
+        set_image_status(image_id, status, message.error.as_deref())
+            .await
+            .map_err(|e| tracing::error!("Can't update image status: {e}"))?;
+    }
+    if let Some(event) = event {
+        publish_event(event);
+    }
+    Ok(())
+}
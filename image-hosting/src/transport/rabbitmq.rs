@@ -0,0 +1,413 @@
+use std::time::Duration;
+
+use amqprs::{
+    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
+    channel::{
+        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicPublishArguments,
+        Channel, QueueDeclareArguments,
+    },
+    connection::{Connection, OpenConnectionArguments},
+    consumer::AsyncConsumer,
+    BasicProperties, Deliver,
+};
+use async_trait::async_trait;
+use common::{
+    confirm::ConfirmChannel,
+    retry::{dead_letter, declare_retry_queues, retry_or_dead_letter, RetryPolicy, RetryPublisher},
+    CallbackMessage, RpcReply, RpcRequest, RpcResponse, RABBITMQ_CALLBACK_QUEUE_NAME,
+    RABBITMQ_JOBS_QUEUE_NAME, RABBITMQ_RPC_QUEUE_NAME,
+};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use tokio::{
+    sync::{oneshot, Mutex, RwLock},
+    time::Instant,
+};
+
+use super::{handle_callback, SentJobs, Transport};
+use crate::{db::outbox::OutboxMessage, rpc::RpcError};
+
+/// Pseudo-queue of RabbitMQ that delivers replies directly to consuming channel
+const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+/// Delay before reconnecting after connection failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Maximum time of waiting for publisher confirms
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+/// Retries of messages from worker that couldn't be handled, e.g. because database is unavailable
+const CALLBACK_RETRY: RetryPolicy = RetryPolicy {
+    max_retries: 10,
+    base_delay: Duration::from_secs(1),
+};
+
+/// Channel for consuming and sending requests, set after connecting to RabbitMQ
+static RABBITMQ_CHANNEL: RwLock<Option<Channel>> = RwLock::const_new(None);
+/// Queue to which worker sends replies, set after connecting to RabbitMQ
+static REPLY_QUEUE: RwLock<Option<String>> = RwLock::const_new(None);
+/// Calls waiting for reply by correlation ID
+static PENDING_CALLS: Lazy<DashMap<String, oneshot::Sender<RpcReply>>> = Lazy::new(DashMap::new);
+
+#[derive(Debug, Clone)]
+pub struct RabbitMQSettings {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    /// Receive worker replies through direct reply-to instead of separate queue
+    direct_reply_to: bool,
+}
+
+impl RabbitMQSettings {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .map_err(|_| anyhow::anyhow!("{name} environment variable is not set"))
+        };
+        Ok(Self {
+            host: var("RABBITMQ_HOST")?,
+            port: var("RABBITMQ_PORT")?
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Can't parse RABBITMQ_PORT"))?,
+            username: var("RABBITMQ_USERNAME")?,
+            password: var("RABBITMQ_PASSWORD")?,
+            direct_reply_to: std::env::var("RABBITMQ_DIRECT_REPLY_TO")
+                .map(|x| x == "true")
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// Communication with separate worker through RabbitMQ
+pub struct RabbitMQTransport {
+    settings: RabbitMQSettings,
+    /// Current connection, used for opening publisher
+    connection: RwLock<Option<Connection>>,
+    /// Channel for outbox messages, opened on first use after connecting
+    publisher: Mutex<Option<ConfirmChannel>>,
+}
+
+impl RabbitMQTransport {
+    pub fn new(settings: RabbitMQSettings) -> Self {
+        Self {
+            settings,
+            connection: RwLock::const_new(None),
+            publisher: Mutex::const_new(None),
+        }
+    }
+
+    async fn connect(&self, shutdown_rx: &mut oneshot::Receiver<()>) -> anyhow::Result<()> {
+        let connection = Connection::open(&OpenConnectionArguments::new(
+            &self.settings.host,
+            self.settings.port,
+            &self.settings.username,
+            &self.settings.password,
+        ))
+        .await?;
+        connection
+            .register_callback(DefaultConnectionCallback)
+            .await?;
+
+        let channel = connection.open_channel(None).await?;
+        channel.register_callback(DefaultChannelCallback).await?;
+
+        let (callback_queue_name, _, _) = channel
+            .queue_declare(
+                QueueDeclareArguments::new(RABBITMQ_CALLBACK_QUEUE_NAME)
+                    .durable(true)
+                    .finish(),
+            )
+            .await?
+            .unwrap();
+        declare_retry_queues(&channel, &callback_queue_name, CALLBACK_RETRY).await?;
+
+        // Declare worker queues so that requests and messages from outbox are always routable
+        for queue_name in [RABBITMQ_JOBS_QUEUE_NAME, RABBITMQ_RPC_QUEUE_NAME] {
+            channel
+                .queue_declare(
+                    QueueDeclareArguments::new(queue_name)
+                        .durable(true)
+                        .finish(),
+                )
+                .await?;
+        }
+
+        let args = BasicConsumeArguments::new(&callback_queue_name, "")
+            .manual_ack(true)
+            .finish();
+        let consumer = CallbackConsumer {
+            retry_publisher: RetryPublisher::new(&connection),
+        };
+        channel.basic_consume(consumer, args).await?;
+        start_reply_consumer(&channel, self.settings.direct_reply_to).await?;
+
+        *RABBITMQ_CHANNEL.write().await = Some(channel);
+        *self.connection.write().await = Some(connection.clone());
+        *self.publisher.lock().await = None;
+
+        tracing::info!("Listening for RabbitMQ messages...");
+
+        tokio::select! {
+            _ = shutdown_rx => {
+                connection.close().await?;
+                Ok(())
+            }
+            result = connection.listen_network_io_failure() => {
+                if result {
+                    Err(anyhow::anyhow!("connection failure"))
+                } else {
+                    Err(anyhow::anyhow!("connection shut down without IO errors"))
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for RabbitMQTransport {
+    async fn send_jobs(&self, messages: &[OutboxMessage]) -> anyhow::Result<SentJobs> {
+        let mut publisher = self.publisher.lock().await;
+        if publisher.as_ref().is_none_or(|x| !x.is_open()) {
+            let connection = self.connection.read().await.clone();
+            let connection = connection.ok_or_else(|| anyhow::anyhow!("not connected"))?;
+            *publisher = Some(ConfirmChannel::open(&connection).await?);
+        }
+        // Channel is closed after error, so it's reopened on next send
+        let delivered = publish_jobs(publisher.as_ref().unwrap(), messages).await?;
+        Ok(SentJobs {
+            delivered,
+            taken: Vec::new(),
+        })
+    }
+
+    async fn call(&self, request: &RpcRequest, timeout: Duration) -> Result<RpcResponse, RpcError> {
+        let body = serde_json::to_vec(request).unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        PENDING_CALLS.insert(correlation_id.clone(), tx);
+        let _pending_call = PendingCall(correlation_id.clone());
+
+        let reply_queue = REPLY_QUEUE
+            .read()
+            .await
+            .clone()
+            .ok_or(RpcError::Unavailable)?;
+        let props = BasicProperties::default()
+            .with_reply_to(&reply_queue)
+            .with_correlation_id(&correlation_id)
+            // Request is dropped by RabbitMQ if worker doesn't take it before deadline
+            .with_expiration(&timeout.as_millis().to_string())
+            .finish();
+        let args = BasicPublishArguments::default()
+            .routing_key(RABBITMQ_RPC_QUEUE_NAME.to_owned())
+            .finish();
+        RABBITMQ_CHANNEL
+            .read()
+            .await
+            .as_ref()
+            .ok_or(RpcError::Unavailable)?
+            .basic_publish(props, body, args)
+            .await
+            .map_err(|e| {
+                tracing::error!("Can't send request to worker: {e}");
+                RpcError::Unavailable
+            })?;
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(Ok(response))) => Ok(response),
+            Ok(Ok(Err(error))) => Err(RpcError::Failed(error)),
+            Ok(Err(_)) => Err(RpcError::Unavailable),
+            Err(_) => Err(RpcError::Timeout),
+        }
+    }
+
+    async fn run(&self, mut shutdown_rx: oneshot::Receiver<()>) {
+        loop {
+            match self.connect(&mut shutdown_rx).await {
+                Ok(_) => {
+                    tracing::info!("RabbitMQ connection shut down normally");
+                    break;
+                }
+                Err(err) => {
+                    tracing::error!("RabbitMQ connection returned error: {err:?}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+/// Consumer of messages sent by worker to callback queue
+struct CallbackConsumer {
+    retry_publisher: RetryPublisher,
+}
+
+#[async_trait]
+impl AsyncConsumer for CallbackConsumer {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let queue_name = RABBITMQ_CALLBACK_QUEUE_NAME;
+        // Failed message is retried after delay instead of being redelivered immediately
+        let res = match serde_json::from_slice::<CallbackMessage>(&content) {
+            Ok(message) => match handle_callback(message).await {
+                Ok(_) => Ok(()),
+                Err(_) => {
+                    let error = "Can't handle message from worker";
+                    retry_or_dead_letter(
+                        &self.retry_publisher,
+                        queue_name,
+                        CALLBACK_RETRY,
+                        basic_properties,
+                        content,
+                        error,
+                    )
+                    .await
+                }
+            },
+            Err(e) => {
+                let error = format!("Can't parse message from worker: {e}");
+                dead_letter(
+                    &self.retry_publisher,
+                    queue_name,
+                    basic_properties,
+                    content,
+                    &error,
+                )
+                .await
+            }
+        };
+        let res = match res {
+            Ok(_) => {
+                channel
+                    .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
+                    .await
+            }
+            // Message couldn't be moved to another queue, so it's redelivered
+            Err(e) => {
+                tracing::error!("Can't retry or dead-letter message: {e}");
+                channel
+                    .basic_nack(BasicNackArguments::new(deliver.delivery_tag(), false, true))
+                    .await
+            }
+        };
+        if let Err(e) = res {
+            tracing::error!("Can't acknowledge message: {e}");
+        }
+    }
+}
+
+/// Removes pending call when it finishes, times out or is cancelled
+struct PendingCall(String);
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        PENDING_CALLS.remove(&self.0);
+    }
+}
+
+/// Start receiving replies on channel used for sending requests,
+/// calls waiting for replies on previous connection fail
+async fn start_reply_consumer(channel: &Channel, direct_reply_to: bool) -> anyhow::Result<()> {
+    *REPLY_QUEUE.write().await = None;
+    PENDING_CALLS.clear();
+
+    let reply_queue = if direct_reply_to {
+        DIRECT_REPLY_TO.to_owned()
+    } else {
+        // Separate queue for each server, so that reply reaches server waiting for it
+        let (queue_name, _, _) = channel
+            .queue_declare(QueueDeclareArguments::exclusive_server_named())
+            .await?
+            .unwrap();
+        queue_name
+    };
+    // Replies from direct reply-to must be consumed without acknowledgements
+    let args = BasicConsumeArguments::new(&reply_queue, "")
+        .manual_ack(!direct_reply_to)
+        .finish();
+    channel
+        .basic_consume(
+            ReplyConsumer {
+                manual_ack: !direct_reply_to,
+            },
+            args,
+        )
+        .await?;
+    *REPLY_QUEUE.write().await = Some(reply_queue);
+    Ok(())
+}
+
+/// Consumer that passes worker replies to waiting calls
+struct ReplyConsumer {
+    manual_ack: bool,
+}
+
+#[async_trait]
+impl AsyncConsumer for ReplyConsumer {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        match serde_json::from_slice::<RpcReply>(&content) {
+            Ok(reply) => {
+                let sender = basic_properties
+                    .correlation_id()
+                    .and_then(|x| PENDING_CALLS.remove(x));
+                match sender {
+                    Some((_, sender)) => {
+                        // Call can be cancelled after reply was received
+                        sender.send(reply).ok();
+                    }
+                    None => tracing::debug!("Received reply for abandoned call"),
+                }
+            }
+            Err(e) => tracing::error!("Can't parse reply from worker: {e}"),
+        }
+
+        if self.manual_ack {
+            if let Err(e) = channel
+                .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
+                .await
+            {
+                tracing::error!("Can't acknowledge reply: {e}");
+            }
+        }
+    }
+}
+
+/// Publish messages and wait for confirms, returns IDs of acknowledged messages.
+/// Messages that weren't confirmed before timeout are published again later
+async fn publish_jobs(
+    channel: &ConfirmChannel,
+    messages: &[OutboxMessage],
+) -> Result<Vec<i64>, amqprs::error::Error> {
+    let mut confirms = Vec::new();
+    for message in messages {
+        let props = BasicProperties::default().with_persistence(true).finish();
+        let confirm = channel
+            .publish(&message.routing_key, props, message.payload.clone())
+            .await?;
+        confirms.push((message.id, confirm));
+    }
+
+    let deadline = Instant::now() + CONFIRM_TIMEOUT;
+    let mut acked = Vec::new();
+    let mut unconfirmed = 0;
+    for (id, confirm) in confirms {
+        match confirm.wait_until(deadline).await {
+            Ok(_) => acked.push(id),
+            Err(_) => unconfirmed += 1,
+        }
+    }
+    if unconfirmed > 0 {
+        tracing::warn!("{unconfirmed} messages weren't confirmed by broker, they will be retried");
+    }
+    Ok(acked)
+}
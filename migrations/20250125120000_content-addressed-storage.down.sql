@@ -0,0 +1,2 @@
+alter table "images" drop column "blob_hash";
+drop table "blobs";
\ No newline at end of file
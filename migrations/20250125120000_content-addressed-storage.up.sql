@@ -0,0 +1,7 @@
+create table "blobs" (
+    "hash" varchar primary key,
+    "ref_count" bigint not null
+);
+alter table "images" add column "blob_hash" varchar;
+alter table "images" add constraint "fk_blob_hash" foreign key ("blob_hash") references "blobs" ("hash");
+create index "idx_images_blob_hash" on "images" ("blob_hash");
\ No newline at end of file
@@ -0,0 +1,8 @@
+create table "outbox" (
+    "id" bigint primary key generated by default as identity,
+    "routing_key" varchar not null,
+    "payload" bytea not null,
+    "attempts" integer not null default 0,
+    "next_attempt" timestamptz not null default now()
+);
+create index "idx_outbox_next_attempt" on "outbox" ("next_attempt");
\ No newline at end of file
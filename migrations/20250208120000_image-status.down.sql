@@ -0,0 +1,3 @@
+alter table "images" drop column "status_error";
+alter table "images" drop column "status";
+drop type "image_status";
\ No newline at end of file
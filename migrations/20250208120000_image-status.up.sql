@@ -0,0 +1,6 @@
+create type "image_status" as enum ('pending', 'processing', 'done', 'failed');
+-- Existing images are assumed to be processed
+alter table "images" add column "status" "image_status" not null default 'done';
+alter table "images" alter column "status" set default 'pending';
+alter table "images" add column "status_error" varchar;
+create index "idx_images_author_status" on "images" ("author", "status");
\ No newline at end of file
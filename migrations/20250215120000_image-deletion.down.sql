@@ -0,0 +1,2 @@
+drop index "idx_images_deleted_at";
+alter table "images" drop column "deleted_at";
\ No newline at end of file
@@ -0,0 +1,2 @@
+alter table "images" add column "deleted_at" timestamptz;
+create index "idx_images_deleted_at" on "images" ("deleted_at") where "deleted_at" is not null;
\ No newline at end of file
@@ -0,0 +1 @@
+drop table "query_images";
\ No newline at end of file
@@ -0,0 +1,5 @@
+create table "query_images" (
+    "hash" varchar primary key,
+    "last_used" timestamptz not null default now()
+);
+create index "idx_query_images_last_used" on "query_images" ("last_used");
\ No newline at end of file
@@ -0,0 +1,2 @@
+drop index "idx_images_title_tsv";
+alter table "images" drop column "title_tsv";
\ No newline at end of file
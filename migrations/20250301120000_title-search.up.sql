@@ -0,0 +1,5 @@
+-- Words of title for fallback search when search index is unavailable.
+-- Titles can be in any language, so words are not stemmed
+alter table "images" add column "title_tsv" tsvector
+    generated always as (to_tsvector('simple', "title")) stored;
+create index "idx_images_title_tsv" on "images" using gin ("title_tsv");
\ No newline at end of file
@@ -0,0 +1,3 @@
+alter table "images" drop column "thumbnail_widths";
+alter table "images" drop column "height";
+alter table "images" drop column "width";
@@ -0,0 +1,4 @@
+-- Dimensions after applying EXIF orientation, unknown for images processed before
+alter table "images" add column "width" integer;
+alter table "images" add column "height" integer;
+alter table "images" add column "thumbnail_widths" integer[] not null default '{}';
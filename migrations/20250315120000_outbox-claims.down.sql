@@ -0,0 +1 @@
+alter table "outbox" drop column "claimed_until";
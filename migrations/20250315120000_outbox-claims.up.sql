@@ -0,0 +1,2 @@
+-- Messages are claimed by relay while they are being delivered, claim expires if relay crashes
+alter table "outbox" add column "claimed_until" timestamptz;
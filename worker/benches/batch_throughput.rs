@@ -0,0 +1,70 @@
+//! Throughput of batch processing with simulated model when items are sent one at a time
+//! and concurrently with in-flight limit, like messages processed by consumer.
+//! RabbitMQ and real models aren't involved.
+//!
+//! Run with `cargo bench -p worker --bench batch_throughput`
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, Semaphore};
+use worker::batch_processing::{batch_process, start_batch_process, Command};
+
+const MESSAGES: usize = 128;
+const BATCH_SIZE: usize = 16;
+const MAX_DELAY: Duration = Duration::from_millis(100);
+const MAX_IN_FLIGHT: usize = 32;
+
+type Sender = mpsc::Sender<Command<usize, usize>>;
+
+/// Model with fixed cost per batch and cost per item, like ONNX inference
+fn simulated_model(batch: Vec<usize>) -> Vec<usize> {
+    std::thread::sleep(Duration::from_millis(20) + Duration::from_millis(2) * batch.len() as u32);
+    batch
+}
+
+async fn sequential(sender: &Sender) -> Duration {
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        batch_process(sender, i, false).await;
+    }
+    start.elapsed()
+}
+
+async fn concurrent(sender: &Sender) -> Duration {
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(MESSAGES);
+    for i in 0..MESSAGES {
+        let permit = Arc::clone(&in_flight).acquire_owned().await.unwrap();
+        let sender = sender.clone();
+        tasks.push(tokio::spawn(async move {
+            batch_process(&sender, i, false).await;
+            drop(permit);
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let sender = start_batch_process(BATCH_SIZE, MAX_DELAY, simulated_model);
+    println!(
+        "{MESSAGES} messages, batch size {BATCH_SIZE}, max delay {MAX_DELAY:?}, \
+        max in flight {MAX_IN_FLIGHT}"
+    );
+    for (name, elapsed) in [
+        ("sequential", sequential(&sender).await),
+        ("concurrent", concurrent(&sender).await),
+    ] {
+        println!(
+            "{name}: {elapsed:.2?}, {:.1} messages/s",
+            MESSAGES as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use std::sync::Arc;
+
+use crate::{clip_image, clip_text, Embedding};
+
+fn print_embedding(embedding: &Embedding) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(embedding)?);
+    Ok(())
+}
+
+/// Print embedding of text query as JSON
+pub async fn embed_text(text: String) -> anyhow::Result<()> {
+    print_embedding(&clip_text::process_request(text).await)
+}
+
+/// Print embedding of image file as JSON
+pub async fn embed_image(path: &str) -> anyhow::Result<()> {
+    let image = image::open(path)?;
+    print_embedding(&clip_image::process_request(Arc::new(image)).await)
+}
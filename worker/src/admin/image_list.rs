@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use common::OnUploadMessage;
+
+use super::connect_db;
+
+/// Images processed by maintenance command
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImageSelection {
+    /// File with JSON of image on each line, images that aren't deleted are taken from database
+    /// with `DATABASE_URL` if not set
+    #[arg(long)]
+    pub manifest: Option<String>,
+    /// Smallest ID of processed images
+    #[arg(long)]
+    pub from_id: Option<i64>,
+    /// Largest ID of processed images
+    #[arg(long)]
+    pub to_id: Option<i64>,
+    /// Number of images processed at once
+    #[arg(long, default_value_t = 64)]
+    pub batch: usize,
+}
+
+impl ImageSelection {
+    /// Whether only part of images is selected by ID
+    pub fn is_range(&self) -> bool {
+        self.from_id.is_some() || self.to_id.is_some()
+    }
+
+    fn min_id(&self) -> i64 {
+        self.from_id.unwrap_or(i64::MIN)
+    }
+
+    fn max_id(&self) -> i64 {
+        self.to_id.unwrap_or(i64::MAX)
+    }
+}
+
+/// Selected images sorted by ID, listed in batches
+pub(super) struct ImageList {
+    source: Source,
+    min_id: i64,
+    max_id: i64,
+}
+
+enum Source {
+    Database(sqlx::PgPool),
+    Manifest(Vec<OnUploadMessage>),
+}
+
+impl ImageList {
+    pub(super) async fn open(selection: &ImageSelection) -> anyhow::Result<Self> {
+        let source =
+            match &selection.manifest {
+                None => Source::Database(connect_db().await?.ok_or_else(|| {
+                    anyhow::anyhow!("DATABASE_URL environment variable is not set")
+                })?),
+                Some(path) => Source::Manifest(read_manifest(Path::new(path)).await?),
+            };
+        Ok(Self {
+            source,
+            min_id: selection.min_id(),
+            max_id: selection.max_id(),
+        })
+    }
+
+    /// Total number of selected images
+    pub(super) async fn count(&self) -> anyhow::Result<usize> {
+        Ok(match &self.source {
+            Source::Database(db) => {
+                sqlx::query_scalar!(
+                    r#"
+                    select count(*) as "count!" from "images"
+                    where "deleted_at" is null and "id" between $1 and $2
+                    "#,
+                    self.min_id,
+                    self.max_id
+                )
+                .fetch_one(db)
+                .await? as usize
+            }
+            Source::Manifest(images) => images
+                .iter()
+                .filter(|x| (self.min_id..=self.max_id).contains(&x.id))
+                .count(),
+        })
+    }
+
+    /// Up to `count` selected images with IDs greater than `after`
+    pub(super) async fn next_batch(
+        &self,
+        after: Option<i64>,
+        count: usize,
+    ) -> anyhow::Result<Vec<OnUploadMessage>> {
+        // Smallest ID of batch
+        let start = after.map_or(self.min_id, |x| x.saturating_add(1).max(self.min_id));
+        if after == Some(i64::MAX) || start > self.max_id {
+            return Ok(Vec::new());
+        }
+        Ok(match &self.source {
+            Source::Database(db) => {
+                sqlx::query_as!(
+                    OnUploadMessage,
+                    r#"
+                    select "id", "format", "blob_hash", "title" from "images"
+                    where "deleted_at" is null and "id" between $1 and $2
+                    order by "id"
+                    limit $3
+                    "#,
+                    start,
+                    self.max_id,
+                    count as i64
+                )
+                .fetch_all(db)
+                .await?
+            }
+            Source::Manifest(images) => {
+                let skip = images.partition_point(|x| x.id < start);
+                images
+                    .iter()
+                    .skip(skip)
+                    .take_while(|x| x.id <= self.max_id)
+                    .take(count)
+                    .cloned()
+                    .collect()
+            }
+        })
+    }
+
+    /// IDs of given images that were deleted after they were listed
+    pub(super) async fn deleted_since_listed(&self, ids: &[i64]) -> anyhow::Result<Vec<i64>> {
+        Ok(match &self.source {
+            Source::Database(db) => {
+                sqlx::query_scalar!(
+                    r#"
+                    select "ids"."id" as "id!" from unnest($1::bigint[]) as "ids"("id")
+                    where not exists(
+                        select 1 from "images"
+                        where "images"."id" = "ids"."id" and "deleted_at" is null
+                    )
+                    "#,
+                    ids
+                )
+                .fetch_all(db)
+                .await?
+            }
+            Source::Manifest(_) => Vec::new(),
+        })
+    }
+}
+
+async fn read_manifest(path: &Path) -> anyhow::Result<Vec<OnUploadMessage>> {
+    let manifest = tokio::fs::read_to_string(path).await?;
+    let mut images = manifest
+        .lines()
+        .filter(|x| !x.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<OnUploadMessage>, _>>()?;
+    images.sort_by_key(|x| x.id);
+    Ok(images)
+}
@@ -0,0 +1,91 @@
+//! Maintenance commands of worker, run from command line instead of consuming queues
+
+use std::future::Future;
+
+use common::{
+    storage::{get_original_key, storage},
+    OnUploadMessage,
+};
+use image::DynamicImage;
+use tokio::task::JoinSet;
+
+use image_list::ImageList;
+pub use image_list::ImageSelection;
+
+pub mod embed;
+mod image_list;
+pub mod regen_thumbnails;
+pub mod reindex;
+pub mod verify;
+
+/// Connect to database with `DATABASE_URL`, `None` if it's not set
+async fn connect_db() -> anyhow::Result<Option<sqlx::PgPool>> {
+    match std::env::var("DATABASE_URL") {
+        Ok(db_url) => Ok(Some(sqlx::PgPool::connect(&db_url).await?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Widths of responsive thumbnails of image saved in database
+async fn get_thumbnail_widths(db: &sqlx::PgPool, id: i64) -> Result<Vec<u32>, String> {
+    sqlx::query_scalar!(
+        r#"select "thumbnail_widths" from "images" where "id" = $1"#,
+        id
+    )
+    .fetch_optional(db)
+    .await
+    .map(|x| {
+        x.unwrap_or_default()
+            .into_iter()
+            .map(|x| x as u32)
+            .collect()
+    })
+    .map_err(|e| format!("Can't get thumbnail widths of image {id}: {e}"))
+}
+
+/// Load original of image from storage, returns encoded and decoded image
+async fn load_image(image: &OnUploadMessage) -> Result<(Vec<u8>, DynamicImage), String> {
+    let key = get_original_key(image.id, &image.format, image.blob_hash.as_deref());
+    let image_buf = storage()
+        .load(&key)
+        .await
+        .map_err(|e| format!("Can't load image {}: {e}", image.id))?;
+    let decoded = image::load_from_memory(&image_buf)
+        .map_err(|e| format!("Can't read image {}: {e}", image.id))?;
+    Ok((image_buf, decoded))
+}
+
+/// Run `f` for each selected image, one batch at a time, printing errors and progress.
+/// Returns number of images for which `f` failed
+async fn for_each_image<F, Fut>(
+    selection: &ImageSelection,
+    verb: &str,
+    f: F,
+) -> anyhow::Result<usize>
+where
+    F: Fn(OnUploadMessage) -> Fut,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let images = ImageList::open(selection).await?;
+    let total = images.count().await?;
+    let (mut done, mut failed) = (0, 0);
+    let mut last_id = None;
+    loop {
+        let batch = images.next_batch(last_id, selection.batch).await?;
+        let Some(batch_last_id) = batch.last().map(|x| x.id) else {
+            break;
+        };
+
+        let mut tasks: JoinSet<_> = batch.into_iter().map(&f).collect();
+        while let Some(res) = tasks.join_next().await {
+            if let Err(e) = res? {
+                println!("{e}");
+                failed += 1;
+            }
+            done += 1;
+        }
+        last_id = Some(batch_last_id);
+        println!("{verb} {done}/{total} images ({failed} failed)");
+    }
+    Ok(failed)
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use common::{
+    storage::{get_image_key, get_original_key, storage},
+    OnUploadMessage, ThumbnailsMessage,
+};
+
+use super::{connect_db, for_each_image, get_thumbnail_widths, load_image, ImageSelection};
+use crate::on_upload::{create_thumbnail, remove_sized_thumbnails, thumbnail_widths};
+
+/// Save dimensions of image and widths of its responsive thumbnails
+async fn save_thumbnails(db: &sqlx::PgPool, thumbnails: &ThumbnailsMessage) -> Result<(), String> {
+    let widths: Vec<_> = thumbnails
+        .thumbnail_widths
+        .iter()
+        .map(|&x| x as i32)
+        .collect();
+    sqlx::query!(
+        r#"update "images" set "width" = $2, "height" = $3, "thumbnail_widths" = $4 where "id" = $1"#,
+        thumbnails.id,
+        thumbnails.width as i32,
+        thumbnails.height as i32,
+        &widths
+    )
+    .execute(db)
+    .await
+    .map_err(|e| format!("Can't save thumbnails of image {}: {e}", thumbnails.id))?;
+    Ok(())
+}
+
+/// Create thumbnail of image again, replacing existing one
+async fn regen_thumbnail(image: OnUploadMessage, db: Option<sqlx::PgPool>) -> Result<(), String> {
+    let (image_buf, decoded) = load_image(&image).await?;
+    let original_key = get_original_key(image.id, &image.format, image.blob_hash.as_deref());
+    let old_widths = match &db {
+        Some(db) => get_thumbnail_widths(db, image.id).await?,
+        None => Vec::new(),
+    };
+    // Thumbnail can be a link to original, writing into it would overwrite original.
+    // Thumbnail can also be missing, so error is ignored
+    storage()
+        .delete(&get_image_key(image.id, &image.format, true))
+        .await
+        .ok();
+    let (id, format) = (image.id, image.format.clone());
+    let thumbnails = create_thumbnail(Arc::new(image), &original_key, Arc::new(decoded), image_buf)
+        .await
+        .map_err(|e| format!("Image {id}: {e}"))?;
+    // Widths removed from ladder, current ones are handled when creating thumbnails
+    let removed_widths = old_widths
+        .into_iter()
+        .filter(|x| !thumbnail_widths().contains(x));
+    remove_sized_thumbnails(id, &format, removed_widths)
+        .await
+        .map_err(|e| format!("Image {id}: {e}"))?;
+    if let Some(db) = &db {
+        save_thumbnails(db, &thumbnails).await?;
+    }
+    Ok(())
+}
+
+/// Rebuild thumbnails of selected images with current size, resize filter and widths,
+/// saving dimensions and widths in database if `DATABASE_URL` is set
+pub async fn run(selection: &ImageSelection) -> anyhow::Result<()> {
+    let db = connect_db().await?;
+    if db.is_none() {
+        println!("DATABASE_URL is not set, thumbnail widths won't be saved");
+    }
+    let failed = for_each_image(selection, "Regenerated thumbnails of", |image| {
+        regen_thumbnail(image, db.clone())
+    })
+    .await?;
+    if failed > 0 {
+        anyhow::bail!("{failed} thumbnails weren't regenerated");
+    }
+    Ok(())
+}
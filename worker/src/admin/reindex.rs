@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use common::OnUploadMessage;
+use tokio::task::JoinSet;
+
+use super::{for_each_image, load_image, ImageList, ImageSelection};
+use crate::{
+    clip_image,
+    search_backend::{
+        init_search_backend, search_backend, ElasticsearchBackend, RebuildState, SearchBackend,
+        SearchBackendSettings,
+    },
+    Embedding,
+};
+
+/// Compute embedding of image from storage
+async fn embed_image(image: OnUploadMessage) -> Result<(i64, String, Embedding), String> {
+    let (_, decoded) = load_image(&image).await?;
+    let embedding = clip_image::process_request(Arc::new(decoded)).await;
+    Ok((image.id, image.title, embedding))
+}
+
+/// Re-embed and re-index selected images. If only range of IDs is selected, images are
+/// replaced in current index, otherwise index is rebuilt
+pub async fn run(selection: &ImageSelection, restart: bool) -> anyhow::Result<()> {
+    if selection.is_range() {
+        reindex_range(selection).await
+    } else {
+        rebuild(selection, restart).await
+    }
+}
+
+/// Replace selected images in current index of any search backend
+async fn reindex_range(selection: &ImageSelection) -> anyhow::Result<()> {
+    init_search_backend().await.map_err(anyhow::Error::msg)?;
+    let failed = for_each_image(selection, "Reindexed", |image| async move {
+        let (id, title, embedding) = embed_image(image).await?;
+        search_backend()
+            .index(id, &title, &embedding)
+            .await
+            .map_err(|e| format!("Can't index image {id}: {e}"))
+    })
+    .await?;
+    if failed > 0 {
+        anyhow::bail!("{failed} images weren't reindexed");
+    }
+    Ok(())
+}
+
+/// Rebuild Elasticsearch index with current version by computing embeddings of all images,
+/// then switch alias to it. Progress is saved after each batch, so interrupted rebuild continues.
+/// If some images fail, progress stops before the first of them and index isn't switched
+async fn rebuild(selection: &ImageSelection, restart: bool) -> anyhow::Result<()> {
+    if selection.manifest.is_some() {
+        // Images missing from manifest would be missing from rebuilt index
+        anyhow::bail!(
+            "Rebuilding index from manifest isn't supported, \
+            select images with --from-id or --to-id to reindex them in place"
+        );
+    }
+    let SearchBackendSettings::Elasticsearch {
+        url,
+        username,
+        password,
+    } = SearchBackendSettings::from_env().map_err(anyhow::Error::msg)?
+    else {
+        anyhow::bail!(
+            "Rebuilding index is only supported for Elasticsearch search backend, \
+            select images with --from-id or --to-id to reindex them in place"
+        );
+    };
+    let backend =
+        ElasticsearchBackend::new(&url, username, password).map_err(anyhow::Error::msg)?;
+
+    let mut last_id = match backend
+        .start_rebuild(restart)
+        .await
+        .map_err(anyhow::Error::msg)?
+    {
+        RebuildState::UpToDate => {
+            println!("Search index is up to date");
+            return Ok(());
+        }
+        RebuildState::InProgress { last_id } => last_id,
+    };
+    if let Some(last_id) = last_id {
+        println!("Continuing after image {last_id}");
+    }
+
+    let images = ImageList::open(selection).await?;
+    let total = images.count().await?;
+    let (mut added, mut failed) = (0, 0);
+    let mut saved_progress = last_id;
+    let mut first_failed = None;
+    loop {
+        let batch = images.next_batch(last_id, selection.batch).await?;
+        let Some(batch_last_id) = batch.last().map(|x| x.id) else {
+            break;
+        };
+
+        let mut tasks: JoinSet<_> = batch
+            .into_iter()
+            .map(|image| async move { (image.id, embed_image(image).await) })
+            .collect();
+        let mut docs = Vec::new();
+        while let Some(res) = tasks.join_next().await {
+            match res? {
+                (_, Ok(doc)) => docs.push(doc),
+                (id, Err(e)) => {
+                    tracing::error!("{e}");
+                    failed += 1;
+                    first_failed = Some(first_failed.map_or(id, |x: i64| x.min(id)));
+                }
+            }
+        }
+        added += docs.len();
+        backend
+            .add_to_rebuild(&docs)
+            .await
+            .map_err(anyhow::Error::msg)?;
+        // Image deleted while batch was embedded would be added back, so it's removed again.
+        // Checked after adding, because later deletion removes already added document itself
+        let ids: Vec<_> = docs.iter().map(|x| x.0).collect();
+        for id in images.deleted_since_listed(&ids).await? {
+            backend.delete(id).await.map_err(anyhow::Error::msg)?;
+            added -= 1;
+        }
+        // Failed images are retried when rebuild is continued
+        let progress = match first_failed {
+            None => Some(batch_last_id),
+            Some(id) => id.checked_sub(1),
+        };
+        if let Some(progress) = progress.filter(|&x| Some(x) > saved_progress) {
+            backend
+                .set_rebuild_progress(progress)
+                .await
+                .map_err(anyhow::Error::msg)?;
+            saved_progress = Some(progress);
+        }
+        last_id = Some(batch_last_id);
+        println!("Added {added} images ({failed} failed), {total} images total");
+    }
+
+    if let Some(id) = first_failed {
+        anyhow::bail!(
+            "{failed} images weren't added, search index isn't switched, \
+            run command again to continue from image {id}"
+        );
+    }
+    let previous = backend.finish_rebuild().await.map_err(anyhow::Error::msg)?;
+    println!("Search index is switched to rebuilt index");
+    if !previous.is_empty() {
+        println!("Previous indices can be deleted: {}", previous.join(", "));
+    }
+    Ok(())
+}
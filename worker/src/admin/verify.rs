@@ -0,0 +1,90 @@
+use common::{
+    storage::{get_original_key, get_thumbnail_key, get_thumbnail_variant_keys, storage},
+    OnUploadMessage,
+};
+
+use super::{connect_db, for_each_image, get_thumbnail_widths, ImageSelection};
+use crate::{
+    on_upload::thumbnail_widths,
+    search_backend::{init_search_backend, search_backend},
+};
+
+/// Check thumbnail of `width` and its variants, returns descriptions of problems
+async fn verify_thumbnail(image: &OnUploadMessage, width: Option<u32>) -> Vec<String> {
+    let name = match width {
+        Some(width) => format!("thumbnail of width {width}"),
+        None => "thumbnail".to_owned(),
+    };
+    let mut problems = Vec::new();
+    let fallback = storage()
+        .metadata(&get_thumbnail_key(image.id, &image.format, width))
+        .await
+        .ok();
+    if fallback.is_none() {
+        problems.push(format!("missing {name}"));
+    }
+    // Variants are optional, but they are only kept if they are smaller than fallback
+    for (variant, key) in get_thumbnail_variant_keys(image.id, &image.format, width) {
+        let Ok(metadata) = storage().metadata(&key).await else {
+            continue;
+        };
+        if fallback.is_none_or(|x| metadata.size == 0 || metadata.size >= x.size) {
+            problems.push(format!("outdated {variant} variant of {name}"));
+        }
+    }
+    problems
+}
+
+/// Check that original, thumbnails and search index entry of image exist
+async fn verify_image(image: OnUploadMessage, db: Option<sqlx::PgPool>) -> Result<(), String> {
+    let original_key = get_original_key(image.id, &image.format, image.blob_hash.as_deref());
+    let mut problems = Vec::new();
+    if storage().metadata(&original_key).await.is_err() {
+        problems.push("missing original".to_owned());
+    }
+    problems.extend(verify_thumbnail(&image, None).await);
+    if let Some(db) = &db {
+        let widths = get_thumbnail_widths(db, image.id).await?;
+        for &width in &widths {
+            problems.extend(verify_thumbnail(&image, Some(width)).await);
+        }
+        for &width in thumbnail_widths() {
+            if !widths.contains(&width)
+                && storage()
+                    .metadata(&get_thumbnail_key(image.id, &image.format, Some(width)))
+                    .await
+                    .is_ok()
+            {
+                problems.push(format!("unused thumbnail of width {width}"));
+            }
+        }
+    }
+    match search_backend().get_embedding(image.id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => problems.push("missing search index document".to_owned()),
+        Err(e) => return Err(format!("Image {}: can't check search index: {e}", image.id)),
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Image {}: {}", image.id, problems.join(", ")))
+    }
+}
+
+/// Cross-check that every selected image has original, thumbnails and search index entry.
+/// Responsive thumbnails are checked if `DATABASE_URL` is set.
+/// Problems are printed, fails if any were found
+pub async fn run(selection: &ImageSelection) -> anyhow::Result<()> {
+    init_search_backend().await.map_err(anyhow::Error::msg)?;
+    let db = connect_db().await?;
+    let failed = for_each_image(selection, "Checked", |image| {
+        verify_image(image, db.clone())
+    })
+    .await?;
+    if failed > 0 {
+        anyhow::bail!("{failed} images are incomplete");
+    }
+    println!("All images are complete");
+    Ok(())
+}
@@ -0,0 +1,291 @@
+use std::{io::Cursor, sync::OnceLock};
+
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        webp::{WebPDecoder, WebPEncoder},
+    },
+    imageops::{self, FilterType},
+    metadata::LoopCount,
+    AnimationDecoder, ExtendedColorType, Frame, ImageDecoder, ImageFormat,
+};
+
+use tracing_unwrap::ResultExt;
+
+use crate::on_upload::apply_orientation;
+
+/// Encoding speed of GIF from 1 (slowest) to 30
+const GIF_SPEED: i32 = 10;
+/// Maximum duration of frame in animated WebP
+const MAX_WEBP_DURATION: u32 = (1 << 24) - 1;
+
+struct AnimationLimits {
+    /// Maximum number of frames
+    max_frames: usize,
+    /// Maximum number of pixels in all decoded frames
+    max_pixels: u64,
+}
+
+/// Limits of decoded animation, read from `ANIMATION_MAX_FRAMES` and `ANIMATION_MAX_PIXELS`
+fn limits() -> &'static AnimationLimits {
+    static LIMITS: OnceLock<AnimationLimits> = OnceLock::new();
+    LIMITS.get_or_init(|| AnimationLimits {
+        max_frames: std::env::var("ANIMATION_MAX_FRAMES")
+            .map_or(Ok(500), |x| x.parse())
+            .expect_or_log("Can't parse ANIMATION_MAX_FRAMES"),
+        max_pixels: std::env::var("ANIMATION_MAX_PIXELS")
+            .map_or(Ok(200_000_000), |x| x.parse())
+            .expect_or_log("Can't parse ANIMATION_MAX_PIXELS"),
+    })
+}
+
+/// Resized frames of animated image
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    pub loop_count: LoopCount,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decode frames of animated GIF or WebP, resize them to fit into `max_width` and `max_height`
+/// and rotate them according to EXIF orientation.
+/// Returns `None` if image isn't animated or exceeds limits, so still thumbnail should be used
+pub fn decode_animation(
+    image_buf: &[u8],
+    format: ImageFormat,
+    orientation: u32,
+    max_width: u32,
+    max_height: u32,
+) -> Result<Option<Animation>, String> {
+    let cursor = Cursor::new(image_buf);
+    let map_err = |e| format!("Can't read animation: {e}");
+    let (width, height, loop_count, frames) = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(cursor).map_err(map_err)?;
+            let (width, height) = decoder.dimensions();
+            (width, height, decoder.loop_count(), decoder.into_frames())
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(cursor).map_err(map_err)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            let (width, height) = decoder.dimensions();
+            (width, height, decoder.loop_count(), decoder.into_frames())
+        }
+        _ => return Ok(None),
+    };
+
+    let scale = f64::min(
+        1.0,
+        f64::min(
+            max_width as f64 / width as f64,
+            max_height as f64 / height as f64,
+        ),
+    );
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+
+    let limits = limits();
+    let mut pixels = 0;
+    let mut resized = Vec::new();
+    for frame in frames {
+        pixels += width as u64 * height as u64;
+        if resized.len() == limits.max_frames || pixels > limits.max_pixels {
+            tracing::warn!("Animation exceeds limits, using still thumbnail");
+            return Ok(None);
+        }
+        // Frames are composited onto the whole canvas
+        let frame = frame.map_err(map_err)?;
+        let delay = frame.delay();
+        let buffer = if scale < 1.0 {
+            imageops::resize(
+                frame.buffer(),
+                new_width,
+                new_height,
+                FilterType::CatmullRom,
+            )
+        } else {
+            frame.into_buffer()
+        };
+        resized.push(Frame::from_parts(
+            apply_orientation(buffer, orientation),
+            0,
+            0,
+            delay,
+        ));
+    }
+    if resized.len() < 2 {
+        return Ok(None);
+    }
+
+    let (width, height) = resized[0].buffer().dimensions();
+    Ok(Some(Animation {
+        frames: resized,
+        loop_count,
+        width,
+        height,
+    }))
+}
+
+/// Encode animation as GIF or WebP
+pub fn encode_animation(animation: &Animation, format: ImageFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ImageFormat::Gif => encode_gif(animation),
+        ImageFormat::WebP => encode_webp(animation),
+        _ => Err(format!("Can't encode animation as {format:?}")),
+    }
+    .map_err(|e| format!("Can't encode animation: {e}"))
+}
+
+fn encode_gif(animation: &Animation) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buf, GIF_SPEED);
+        let repeat = match animation.loop_count {
+            LoopCount::Infinite => Repeat::Infinite,
+            LoopCount::Finite(n) => Repeat::Finite(n.get().min(u16::MAX as u32) as u16),
+        };
+        encoder.set_repeat(repeat).map_err(|e| e.to_string())?;
+        encoder
+            .encode_frames(animation.frames.iter().cloned())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(buf)
+}
+
+/// Append RIFF chunk, padded to even size
+fn push_chunk(buf: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(fourcc);
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        buf.push(0);
+    }
+}
+
+fn push_u24(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+/// Find chunk with image data (including its header) in still WebP
+fn find_image_chunk(webp: &[u8]) -> Option<&[u8]> {
+    // Skip "RIFF", size and "WEBP"
+    let mut pos = 12;
+    while pos + 8 <= webp.len() {
+        let fourcc = &webp[pos..(pos + 4)];
+        let size = u32::from_le_bytes(webp[(pos + 4)..(pos + 8)].try_into().unwrap()) as usize;
+        let end = (pos + 8 + size + size % 2).min(webp.len());
+        if fourcc == b"VP8L" || fourcc == b"VP8 " {
+            return Some(&webp[pos..end]);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Encode frames as lossless WebP and combine them into animated WebP,
+/// because `image` can only encode still WebP
+fn encode_webp(animation: &Animation) -> Result<Vec<u8>, String> {
+    let mut chunks = Vec::new();
+
+    let mut vp8x = Vec::with_capacity(10);
+    // Alpha and animation flags
+    vp8x.extend_from_slice(&[0x12, 0, 0, 0]);
+    push_u24(&mut vp8x, animation.width - 1);
+    push_u24(&mut vp8x, animation.height - 1);
+    push_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    let loop_count = match animation.loop_count {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(n) => n.get().min(u16::MAX as u32) as u16,
+    };
+    let mut anim = vec![0; 4];
+    anim.extend_from_slice(&loop_count.to_le_bytes());
+    push_chunk(&mut chunks, b"ANIM", &anim);
+
+    for frame in &animation.frames {
+        let buffer = frame.buffer();
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still)
+            .encode(
+                buffer.as_raw(),
+                buffer.width(),
+                buffer.height(),
+                ExtendedColorType::Rgba8,
+            )
+            .map_err(|e| e.to_string())?;
+        let image_chunk =
+            find_image_chunk(&still).ok_or_else(|| "No image data in frame".to_owned())?;
+
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let duration = (numer / denom.max(1)).min(MAX_WEBP_DURATION);
+        let mut anmf = Vec::with_capacity(16 + image_chunk.len());
+        // Offset of frame, divided by 2
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, buffer.width() - 1);
+        push_u24(&mut anmf, buffer.height() - 1);
+        push_u24(&mut anmf, duration);
+        // Frames are whole canvases, so they are not blended with previous ones
+        anmf.push(0x02);
+        anmf.extend_from_slice(image_chunk);
+        push_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    let mut buf = Vec::with_capacity(12 + chunks.len());
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+    buf.extend_from_slice(b"WEBP");
+    buf.extend_from_slice(&chunks);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use image::{Delay, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn webp_round_trip() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 128], [0, 0, 255, 0]];
+        let frames = colors
+            .iter()
+            .zip([100, 250, 40])
+            .map(|(&color, ms)| {
+                let buffer = RgbaImage::from_pixel(5, 3, Rgba(color));
+                Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(ms, 1))
+            })
+            .collect();
+        let animation = Animation {
+            frames,
+            loop_count: LoopCount::Finite(NonZeroU32::new(3).unwrap()),
+            width: 5,
+            height: 3,
+        };
+        let webp = encode_animation(&animation, ImageFormat::WebP).unwrap();
+
+        let decoder = WebPDecoder::new(Cursor::new(&webp)).unwrap();
+        assert!(decoder.has_animation());
+        assert_eq!(decoder.dimensions(), (5, 3));
+        assert!(matches!(decoder.loop_count(), LoopCount::Finite(x) if x.get() == 3));
+        let decoded = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(decoded.len(), 3);
+        for ((frame, color), ms) in decoded.iter().zip(colors).zip([100, 250, 40]) {
+            assert_eq!(frame.delay().numer_denom_ms(), (ms, 1));
+            assert_eq!(frame.buffer().dimensions(), (5, 3));
+            assert!(frame.buffer().pixels().all(|x| x.0 == color));
+        }
+
+        let animation = Animation {
+            loop_count: LoopCount::Infinite,
+            ..animation
+        };
+        let webp = encode_animation(&animation, ImageFormat::WebP).unwrap();
+        let decoder = WebPDecoder::new(Cursor::new(&webp)).unwrap();
+        assert!(matches!(decoder.loop_count(), LoopCount::Infinite));
+    }
+}
@@ -9,8 +9,6 @@ use tokio::{
 };
 use tracing_unwrap::ResultExt;
 
-use crate::Settings;
-
 /// Commands for batch processing
 #[derive(Debug)]
 pub enum Command<In, Out> {
@@ -20,9 +18,11 @@ pub enum Command<In, Out> {
     Flush,
 }
 
-/// Start batch process with given settings and processing function, returns command sender
+/// Start batch process with given batch size, maximum delay before processing incomplete batch
+/// and processing function, returns command sender
 pub fn start_batch_process<In, Out, F>(
-    settings: &Settings,
+    batch_size: usize,
+    max_delay: Duration,
     process: F,
 ) -> mpsc::Sender<Command<In, Out>>
 where
@@ -30,9 +30,7 @@ where
     Out: Send + 'static,
     F: Fn(Vec<In>) -> Vec<Out> + Send + Copy + 'static,
 {
-    let batch_size = settings.batch_size;
-    let max_delay = Duration::from_millis(settings.max_delay_ms);
-    let max_capacity = 2 * settings.batch_size;
+    let max_capacity = 2 * batch_size;
 
     let (tx, mut rx) = mpsc::channel(max_capacity);
     // Start task for processing commands
@@ -0,0 +1,30 @@
+use amqprs::{channel::BasicPublishArguments, BasicProperties};
+use async_trait::async_trait;
+use common::{CallbackMessage, RABBITMQ_CALLBACK_QUEUE_NAME};
+use tracing_unwrap::ResultExt;
+use worker::status::Callback;
+
+use crate::RABBITMQ_CHANNEL;
+
+/// Sends messages to web server through callback queue
+pub struct RabbitMQCallback;
+
+#[async_trait]
+impl Callback for RabbitMQCallback {
+    async fn send(&self, message: CallbackMessage) -> Result<(), String> {
+        let message = serde_json::to_vec(&message).unwrap_or_log();
+
+        let props = BasicProperties::default().with_persistence(true).finish();
+        let args = BasicPublishArguments::default()
+            .routing_key(RABBITMQ_CALLBACK_QUEUE_NAME.to_owned())
+            .finish();
+        RABBITMQ_CHANNEL
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .basic_publish(props, message, args)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
@@ -2,35 +2,33 @@ use std::sync::{Arc, OnceLock};
 
 use image::{imageops::FilterType, DynamicImage};
 use ndarray::{arr3, Array3, Axis};
-use ort::{
-    execution_providers::CUDAExecutionProvider,
-    session::{builder::GraphOptimizationLevel, Session},
-};
+use ort::session::Session;
 use tokio::sync::mpsc;
 use tracing_unwrap::{OptionExt, ResultExt};
 
 use crate::{
     batch_processing::{batch_process, log_processing_function, start_batch_process, Command},
     util::ToNdarray3,
-    Embedding, Settings,
+    Embedding, ModelSettings,
 };
 
 static MODEL: OnceLock<Session> = OnceLock::new();
 static BATCH_SENDER: OnceLock<mpsc::Sender<Command<Array3<f32>, Embedding>>> = OnceLock::new();
 
-pub fn initialize_model(settings: &Settings) -> ort::Result<()> {
+pub fn initialize_model(settings: &ModelSettings) -> ort::Result<()> {
     MODEL
         .set(
-            Session::builder()?
-                .with_execution_providers([CUDAExecutionProvider::default().build()])?
-                .with_optimization_level(GraphOptimizationLevel::Level3)?
-                .commit_from_file("models/clip-ViT-B-32/model.onnx")?,
+            settings
+                .session_builder(false)?
+                .commit_from_file(settings.image_model_dir.join("model.onnx"))?,
         )
         .unwrap_or_log();
     BATCH_SENDER
-        .set(start_batch_process(settings, |batch| {
-            log_processing_function("CLIP/Image", compute_embeddings, batch)
-        }))
+        .set(start_batch_process(
+            settings.batch_size,
+            settings.max_delay(),
+            |batch| log_processing_function("CLIP/Image", compute_embeddings, batch),
+        ))
         .unwrap_or_log();
     Ok(())
 }
@@ -1,17 +1,14 @@
 use std::sync::OnceLock;
 
 use ndarray::{Array2, ArrayD, ArrayViewD, Axis};
-use ort::{
-    execution_providers::CUDAExecutionProvider,
-    session::{builder::GraphOptimizationLevel, Session},
-};
+use ort::session::Session;
 use tokenizers::{EncodeInput, PaddingParams, Tokenizer, TruncationParams};
 use tokio::sync::mpsc;
 use tracing_unwrap::{OptionExt, ResultExt};
 
 use crate::{
     batch_processing::{batch_process, log_processing_function, start_batch_process, Command},
-    Embedding, Settings,
+    Embedding, ModelSettings,
 };
 
 static MAIN_MODEL: OnceLock<Session> = OnceLock::new();
@@ -24,26 +21,25 @@ struct PreprocessedText {
     pub attention_mask: Array2<i64>,
 }
 
-pub fn initialize_model(settings: &Settings) -> anyhow::Result<()> {
+pub fn initialize_model(settings: &ModelSettings) -> anyhow::Result<()> {
     MAIN_MODEL
         .set(
-            Session::builder()?
-                .with_execution_providers([CUDAExecutionProvider::default().build()])?
-                .with_optimization_level(GraphOptimizationLevel::Level3)?
-                .commit_from_file("models/clip-ViT-B-32-multilingual-v1/model.onnx")?,
+            settings
+                .session_builder(false)?
+                .commit_from_file(settings.text_model_dir.join("model.onnx"))?,
         )
         .unwrap_or_log();
     // Always on CPU
     DENSE_MODEL
         .set(
-            Session::builder()?
-                .with_optimization_level(GraphOptimizationLevel::Level3)?
-                .commit_from_file("models/clip-ViT-B-32-multilingual-v1/dense.onnx")?,
+            settings
+                .session_builder(true)?
+                .commit_from_file(settings.text_model_dir.join("dense.onnx"))?,
         )
         .unwrap_or_log();
     TOKENIZER
         .set(
-            Tokenizer::from_file("models/clip-ViT-B-32-multilingual-v1/tokenizer.json")
+            Tokenizer::from_file(settings.text_model_dir.join("tokenizer.json"))
                 .map(|mut x| {
                     x.with_padding(Some(PaddingParams::default()));
                     x.with_truncation(Some(TruncationParams::default()))
@@ -54,9 +50,11 @@ pub fn initialize_model(settings: &Settings) -> anyhow::Result<()> {
         )
         .unwrap_or_log();
     BATCH_SENDER
-        .set(start_batch_process(settings, |batch| {
-            log_processing_function("CLIP/Text", compute_embeddings, batch)
-        }))
+        .set(start_batch_process(
+            settings.batch_size,
+            settings.max_delay(),
+            |batch| log_processing_function("CLIP/Text", compute_embeddings, batch),
+        ))
         .unwrap_or_log();
     Ok(())
 }
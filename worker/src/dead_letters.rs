@@ -0,0 +1,152 @@
+use amqprs::{
+    channel::{
+        BasicAckArguments, BasicGetArguments, BasicNackArguments, Channel, QueueDeclareArguments,
+        QueuePurgeArguments,
+    },
+    connection::{Connection, OpenConnectionArguments},
+};
+use clap::{Subcommand, ValueEnum};
+use common::{
+    retry::{dead_letter_error, dead_letter_queue_name, replay, retry_count, RetryPublisher},
+    RABBITMQ_CALLBACK_QUEUE_NAME, RABBITMQ_JOBS_QUEUE_NAME, RABBITMQ_RPC_QUEUE_NAME,
+};
+
+use crate::RabbitMQSettings;
+
+/// Queue whose dead letters are managed
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum WorkerQueue {
+    Rpc,
+    Jobs,
+    /// Messages from worker to web server
+    Callback,
+}
+
+impl WorkerQueue {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Rpc => RABBITMQ_RPC_QUEUE_NAME,
+            Self::Jobs => RABBITMQ_JOBS_QUEUE_NAME,
+            Self::Callback => RABBITMQ_CALLBACK_QUEUE_NAME,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeadLettersAction {
+    /// Print dead letters, leaving them in the queue
+    List {
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    /// Return dead letters to their original queue to be processed again
+    Replay {
+        /// Replay all dead letters if not set
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// Delete all dead letters
+    Purge,
+}
+
+/// Inspect, replay or purge dead letters of queue
+pub async fn run(
+    settings: &RabbitMQSettings,
+    queue: WorkerQueue,
+    action: &DeadLettersAction,
+) -> anyhow::Result<()> {
+    let connection = Connection::open(&OpenConnectionArguments::new(
+        &settings.host,
+        settings.port,
+        &settings.username,
+        &settings.password,
+    ))
+    .await?;
+    let channel = connection.open_channel(None).await?;
+
+    let queue_name = queue.name();
+    let dead_letter_queue = dead_letter_queue_name(queue_name);
+    let (_, message_count, _) = channel
+        .queue_declare(
+            QueueDeclareArguments::new(&dead_letter_queue)
+                .durable(true)
+                .finish(),
+        )
+        .await?
+        .unwrap();
+    println!("{message_count} dead letters in {dead_letter_queue}");
+
+    match *action {
+        DeadLettersAction::List { count } => list(&channel, &dead_letter_queue, count).await?,
+        DeadLettersAction::Replay { count } => {
+            let count = count.unwrap_or(message_count as usize);
+            let publisher = RetryPublisher::new(&connection);
+            let replayed =
+                replay_all(&channel, &publisher, &dead_letter_queue, queue_name, count).await?;
+            println!("Replayed {replayed} dead letters to {queue_name}");
+        }
+        DeadLettersAction::Purge => {
+            let purged = channel
+                .queue_purge(QueuePurgeArguments::new(&dead_letter_queue))
+                .await?
+                .unwrap_or_default();
+            println!("Purged {purged} dead letters");
+        }
+    }
+
+    connection.close().await?;
+    Ok(())
+}
+
+async fn list(channel: &Channel, dead_letter_queue: &str, count: usize) -> anyhow::Result<()> {
+    let mut last_delivery_tag = None;
+    for i in 0..count {
+        let Some((get_ok, props, content)) = channel
+            .basic_get(BasicGetArguments::new(dead_letter_queue))
+            .await?
+        else {
+            break;
+        };
+        last_delivery_tag = Some(get_ok.delivery_tag());
+
+        println!(
+            "#{}: retries: {}, error: {}",
+            i + 1,
+            retry_count(&props),
+            dead_letter_error(&props).unwrap_or("unknown")
+        );
+        println!("{}", String::from_utf8_lossy(&content));
+    }
+
+    // Return listed messages to the queue
+    if let Some(delivery_tag) = last_delivery_tag {
+        channel
+            .basic_nack(BasicNackArguments::new(delivery_tag, true, true))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn replay_all(
+    channel: &Channel,
+    publisher: &RetryPublisher,
+    dead_letter_queue: &str,
+    queue_name: &str,
+    count: usize,
+) -> anyhow::Result<usize> {
+    let mut replayed = 0;
+    while replayed < count {
+        let Some((get_ok, props, content)) = channel
+            .basic_get(BasicGetArguments::new(dead_letter_queue))
+            .await?
+        else {
+            break;
+        };
+        replay(publisher, queue_name, props, content).await?;
+        channel
+            .basic_ack(BasicAckArguments::new(get_ok.delivery_tag(), false))
+            .await?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
@@ -0,0 +1,51 @@
+use common::{
+    storage::{get_thumbnail_key, get_thumbnail_variant_keys, storage},
+    DeleteMessage,
+};
+
+use crate::{
+    on_upload::{remove_sized_thumbnails, thumbnail_widths},
+    search_backend::search_backend,
+};
+
+async fn delete_thumbnail(message: &DeleteMessage) -> Result<(), ()> {
+    let keys = std::iter::once(get_thumbnail_key(message.id, &message.format, None)).chain(
+        get_thumbnail_variant_keys(message.id, &message.format, None)
+            .into_iter()
+            .map(|(_, key)| key),
+    );
+    for key in keys {
+        // Thumbnail doesn't exist if image wasn't processed or variant wasn't smaller
+        if storage().metadata(&key).await.is_err() {
+            continue;
+        }
+        storage()
+            .delete(&key)
+            .await
+            .map_err(|e| tracing::error!("Can't delete thumbnail: {e}"))?;
+    }
+
+    // Widths of image can be from ladder that is no longer configured
+    let mut widths = thumbnail_widths().to_vec();
+    widths.extend(&message.thumbnail_widths);
+    widths.sort_unstable();
+    widths.dedup();
+    remove_sized_thumbnails(message.id, &message.format, widths)
+        .await
+        .map_err(|e| tracing::error!("{e}"))
+}
+
+async fn delete_from_search_index(message: &DeleteMessage) -> Result<(), ()> {
+    search_backend()
+        .delete(message.id)
+        .await
+        .map_err(|e| tracing::error!("{e}"))
+}
+
+pub async fn process_request(message: DeleteMessage) -> Result<(), ()> {
+    let (res_1, res_2) = tokio::join!(
+        delete_thumbnail(&message),
+        delete_from_search_index(&message)
+    );
+    res_1.and(res_2)
+}
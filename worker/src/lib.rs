@@ -0,0 +1,67 @@
+pub mod admin;
+mod animation;
+pub mod batch_processing;
+mod clip_image;
+mod clip_text;
+mod delete;
+mod model_settings;
+mod on_upload;
+mod search;
+pub mod search_backend;
+mod similar;
+pub mod status;
+mod update;
+mod util;
+
+use common::{RpcReply, RpcRequest, RpcResponse, WorkerMessage};
+use ndarray::{Array, ArrayD, Dimension};
+use serde::{Deserialize, Serialize};
+
+pub use model_settings::{ArenaExtend, ExecutionProvider, ModelSettings, OptimizationLevel};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embedding {
+    pub embedding: Vec<f32>,
+}
+
+impl Embedding {
+    pub fn normalize<D: Dimension>(arr: Array<f32, D>) -> Array<f32, D> {
+        const NORMALIZE_EPS: f32 = 1e-12;
+
+        let norm = arr.mapv(|x| x.powi(2)).sum().sqrt().max(NORMALIZE_EPS);
+        arr / norm
+    }
+
+    pub fn from_unnormalized_array(embedding: ArrayD<f32>) -> Self {
+        Self {
+            embedding: Embedding::normalize(embedding).into_iter().collect(),
+        }
+    }
+}
+
+pub fn initialize_models(settings: &ModelSettings) -> anyhow::Result<()> {
+    clip_image::initialize_model(settings)?;
+    clip_text::initialize_model(settings)?;
+    Ok(())
+}
+
+/// Process background job, errors are logged
+pub async fn process_job(message: WorkerMessage) -> Result<(), ()> {
+    match message {
+        WorkerMessage::OnUpload(x) => on_upload::process_request(x).await,
+        WorkerMessage::Delete(x) => delete::process_request(x).await,
+        WorkerMessage::Update(x) => update::process_request(x).await,
+    }
+}
+
+/// Process request, error is returned to be sent to client
+pub async fn process_request(request: RpcRequest) -> RpcReply {
+    let reply = match request {
+        RpcRequest::Search(x) => search::process_request(x).await.map(RpcResponse::Search),
+        RpcRequest::Similar(x) => similar::process_request(x).await.map(RpcResponse::Similar),
+    };
+    if let Err(e) = &reply {
+        tracing::error!("Request failed: {e}");
+    }
+    reply
+}
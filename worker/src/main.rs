@@ -1,17 +1,13 @@
-mod batch_processing;
-mod clip_image;
-mod clip_text;
-mod create_index;
-mod on_upload;
-mod search;
-mod util;
+mod callback;
+mod dead_letters;
+mod rpc;
 
-use std::{sync::OnceLock, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use amqprs::{
     callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
     channel::{
-        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, Channel,
+        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicQosArguments, Channel,
         QueueDeclareArguments,
     },
     connection::{Connection, OpenConnectionArguments},
@@ -19,39 +15,106 @@ use amqprs::{
     BasicProperties, Deliver,
 };
 use async_trait::async_trait;
-use clap::Parser;
-use common::{WorkerMessage, RABBITMQ_QUEUE_NAME};
-use elasticsearch::{
-    auth::Credentials,
-    http::{
-        transport::{SingleNodeConnectionPool, TransportBuilder},
-        Url,
-    },
-    Elasticsearch,
+use clap::{Parser, Subcommand};
+use common::{
+    retry::{self, RetryPolicy, RetryPublisher},
+    storage::init_storage,
+    RABBITMQ_JOBS_QUEUE_NAME, RABBITMQ_RPC_QUEUE_NAME,
 };
-use ndarray::{Array, ArrayD, Dimension};
-use serde::Serialize;
 use tokio::{
     signal,
-    sync::{oneshot, RwLock},
+    sync::{oneshot, RwLock, Semaphore},
 };
 use tracing_subscriber::{
     filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
-use tracing_unwrap::{OptionExt, ResultExt};
+use tracing_unwrap::ResultExt;
+
+use worker::{
+    admin::{self, ImageSelection},
+    search_backend::init_search_backend,
+    status::set_callback,
+    ModelSettings,
+};
 
-use crate::create_index::create_index;
+use crate::{
+    callback::RabbitMQCallback,
+    dead_letters::{DeadLettersAction, WorkerQueue},
+};
 
 static RABBITMQ_CHANNEL: RwLock<Option<Channel>> = RwLock::const_new(None);
-static ELASTICSEARCH: OnceLock<Elasticsearch> = OnceLock::new();
+
+/// Time to wait for messages being processed when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Settings {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    model: ModelSettings,
+    /// Maximum number of unacknowledged search requests delivered by RabbitMQ
+    #[arg(long, default_value_t = 32)]
+    rpc_prefetch: u16,
+    /// Maximum number of search requests processed concurrently
     #[arg(long, default_value_t = 16)]
-    batch_size: usize,
-    #[arg(long, default_value_t = 100)]
-    max_delay_ms: u64,
+    rpc_max_in_flight: usize,
+    /// Maximum number of unacknowledged background jobs delivered by RabbitMQ
+    #[arg(long, default_value_t = 64)]
+    jobs_prefetch: u16,
+    /// Maximum number of background jobs processed concurrently
+    #[arg(long, default_value_t = 32)]
+    jobs_max_in_flight: usize,
+    /// Number of retries of failed background job before moving it to dead-letter queue
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+    /// Delay before first retry of failed background job, doubled after each retry
+    #[arg(long, default_value_t = 1000)]
+    retry_delay_ms: u64,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Process messages from RabbitMQ (default)
+    Serve,
+    /// Manage messages that failed processing
+    DeadLetters {
+        #[arg(long, value_enum, default_value_t = WorkerQueue::Jobs)]
+        queue: WorkerQueue,
+        #[command(subcommand)]
+        action: DeadLettersAction,
+    },
+    #[command(flatten)]
+    Admin(AdminCommand),
+}
+
+/// Maintenance commands, run instead of consuming queues
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    /// Re-embed and re-index images. Without ID range, search index is rebuilt with current
+    /// settings and mapping from database, then switched to without downtime
+    Reindex {
+        #[command(flatten)]
+        images: ImageSelection,
+        /// Discard progress of interrupted rebuild
+        #[arg(long)]
+        restart: bool,
+    },
+    /// Rebuild thumbnails after changing their size or resize filter
+    RegenThumbnails {
+        #[command(flatten)]
+        images: ImageSelection,
+    },
+    /// Check that every image has thumbnail and search index entry
+    Verify {
+        #[command(flatten)]
+        images: ImageSelection,
+    },
+    /// Print embedding of text query
+    EmbedText { text: String },
+    /// Print embedding of image file
+    EmbedImage { path: String },
 }
 
 struct RabbitMQSettings {
@@ -59,26 +122,31 @@ struct RabbitMQSettings {
     port: u16,
     username: String,
     password: String,
+    rpc: QueueSettings,
+    jobs: QueueSettings,
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Embedding {
-    pub embedding: Vec<f32>,
+/// Kind of messages in queue, determines how they are processed
+#[derive(Debug, Clone, Copy)]
+enum QueueKind {
+    Jobs,
+    Rpc,
 }
 
-impl Embedding {
-    pub fn normalize<D: Dimension>(arr: Array<f32, D>) -> Array<f32, D> {
-        const NORMALIZE_EPS: f32 = 1e-12;
-
-        let norm = arr.mapv(|x| x.powi(2)).sum().sqrt().max(NORMALIZE_EPS);
-        arr / norm
-    }
+/// Reason why message wasn't processed
+pub enum MessageError {
+    /// Message can't be processed, retrying won't help
+    Malformed(String),
+    /// Processing failed and can be retried
+    Failed(String),
+}
 
-    pub fn from_unnormalized_array(embedding: ArrayD<f32>) -> Self {
-        Self {
-            embedding: Embedding::normalize(embedding).into_iter().collect(),
-        }
-    }
+/// Consumer settings for one queue
+#[derive(Debug, Clone, Copy)]
+struct QueueSettings {
+    prefetch: u16,
+    max_in_flight: usize,
+    retry: RetryPolicy,
 }
 
 #[tokio::main]
@@ -95,6 +163,11 @@ async fn main() {
     dotenvy::dotenv().ok();
     let settings = Settings::parse();
 
+    if let Some(Command::Admin(command)) = &settings.command {
+        run_admin_command(command, &settings.model).await;
+        return;
+    }
+
     let rabbitmq_settings = RabbitMQSettings {
         host: std::env::var("RABBITMQ_HOST")
             .expect_or_log("RABBITMQ_HOST environment variable is not set"),
@@ -106,31 +179,43 @@ async fn main() {
             .expect_or_log("RABBITMQ_USERNAME environment variable is not set"),
         password: std::env::var("RABBITMQ_PASSWORD")
             .expect_or_log("RABBITMQ_PASSWORD environment variable is not set"),
+        rpc: QueueSettings {
+            prefetch: settings.rpc_prefetch,
+            max_in_flight: settings.rpc_max_in_flight,
+            // Only malformed requests are dead-lettered. Request fails if reply can't be sent,
+            // and client doesn't wait long for it, so failed requests are dropped
+            retry: RetryPolicy {
+                max_retries: 0,
+                base_delay: Duration::ZERO,
+            },
+        },
+        jobs: QueueSettings {
+            prefetch: settings.jobs_prefetch,
+            max_in_flight: settings.jobs_max_in_flight,
+            retry: RetryPolicy {
+                max_retries: settings.max_retries,
+                base_delay: Duration::from_millis(settings.retry_delay_ms),
+            },
+        },
     };
 
-    let elasticsearch_url = std::env::var("ELASTICSEARCH_URL")
-        .expect_or_log("ELASTICSEARCH_URL environment variable is not set");
-    let elasticsearch_username = std::env::var("ELASTICSEARCH_USERNAME")
-        .expect_or_log("ELASTICSEARCH_USERNAME environment variable is not set");
-    let elasticsearch_password = std::env::var("ELASTICSEARCH_PASSWORD")
-        .expect_or_log("ELASTICSEARCH_PASSWORD environment variable is not set");
-
-    let es_url = Url::parse(&elasticsearch_url).unwrap_or_log();
-    let es_conn_pool = SingleNodeConnectionPool::new(es_url);
-    let es_transport = TransportBuilder::new(es_conn_pool)
-        .auth(Credentials::Basic(
-            elasticsearch_username,
-            elasticsearch_password,
-        ))
-        .build()
-        .expect_or_log("Can't create connection to Elasticsearch");
-    let es_client = Elasticsearch::new(es_transport);
-    create_index(&es_client)
+    if let Some(Command::DeadLetters { queue, action }) = &settings.command {
+        dead_letters::run(&rabbitmq_settings, *queue, action)
+            .await
+            .expect_or_log("Can't manage dead letters");
+        return;
+    }
+
+    init_search_backend()
+        .await
+        .expect_or_log("Can't initialize search backend");
+
+    init_storage()
         .await
-        .expect_or_log("Can't create Elasticsearch index");
-    ELASTICSEARCH.set(es_client).unwrap();
+        .expect_or_log("Can't initialize storage");
 
-    initialize_models(&settings).expect_or_log("Can't initialize models");
+    worker::initialize_models(&settings.model).expect_or_log("Can't initialize models");
+    set_callback(Box::new(RabbitMQCallback)).unwrap_or_log();
 
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let rabbitmq_task = tokio::spawn(async {
@@ -142,10 +227,36 @@ async fn main() {
     rabbitmq_task.await.unwrap();
 }
 
-fn initialize_models(settings: &Settings) -> anyhow::Result<()> {
-    clip_image::initialize_model(settings)?;
-    clip_text::initialize_model(settings)?;
-    Ok(())
+/// Run maintenance command, exits with error if it fails
+async fn run_admin_command(command: &AdminCommand, model_settings: &ModelSettings) {
+    if !matches!(
+        command,
+        AdminCommand::EmbedText { .. } | AdminCommand::EmbedImage { .. }
+    ) {
+        init_storage()
+            .await
+            .expect_or_log("Can't initialize storage");
+    }
+    if matches!(
+        command,
+        AdminCommand::Reindex { .. }
+            | AdminCommand::EmbedText { .. }
+            | AdminCommand::EmbedImage { .. }
+    ) {
+        worker::initialize_models(model_settings).expect_or_log("Can't initialize models");
+    }
+
+    let res = match command {
+        AdminCommand::Reindex { images, restart } => admin::reindex::run(images, *restart).await,
+        AdminCommand::RegenThumbnails { images } => admin::regen_thumbnails::run(images).await,
+        AdminCommand::Verify { images } => admin::verify::run(images).await,
+        AdminCommand::EmbedText { text } => admin::embed::embed_text(text.clone()).await,
+        AdminCommand::EmbedImage { path } => admin::embed::embed_image(path).await,
+    };
+    if let Err(e) = res {
+        tracing::error!("{e:#}");
+        std::process::exit(1);
+    }
 }
 
 async fn launch_rabbitmq_connection(
@@ -189,34 +300,37 @@ async fn connect_rabbitmq(
         .unwrap_or_log();
     *RABBITMQ_CHANNEL.write().await = Some(channel);
 
-    let (queue_name, _, _) = RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
-        .unwrap()
-        .queue_declare(
-            QueueDeclareArguments::new(RABBITMQ_QUEUE_NAME)
-                .durable(true)
-                .finish(),
-        )
-        .await
-        .unwrap_or_log()
-        .unwrap_or_log();
-
-    let args = BasicConsumeArguments::new(&queue_name, "");
-    RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
-        .unwrap()
-        .basic_consume(Consumer, args)
-        .await
-        .unwrap_or_log();
+    let retry_publisher = Arc::new(RetryPublisher::new(&connection));
+    // Separate queues so that search requests don't wait behind backlog of uploads
+    let rpc_in_flight = consume_queue(
+        RABBITMQ_RPC_QUEUE_NAME,
+        QueueKind::Rpc,
+        settings.rpc,
+        &retry_publisher,
+    )
+    .await?;
+    let jobs_in_flight = consume_queue(
+        RABBITMQ_JOBS_QUEUE_NAME,
+        QueueKind::Jobs,
+        settings.jobs,
+        &retry_publisher,
+    )
+    .await?;
 
     tracing::info!("Listening...");
 
     tokio::select! {
         _ = shutdown_rx => {
+            // Let messages being processed finish, others are redelivered after closing
+            let drain = async {
+                tokio::join!(
+                    rpc_in_flight.acquire_many(settings.rpc.max_in_flight as u32),
+                    jobs_in_flight.acquire_many(settings.jobs.max_in_flight as u32),
+                )
+            };
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+                tracing::warn!("Messages weren't processed before shutdown timeout");
+            }
             connection.close().await?;
             Ok(())
         }
@@ -230,7 +344,60 @@ async fn connect_rabbitmq(
     }
 }
 
-struct Consumer;
+/// Declare queue and start consuming it, returns semaphore limiting messages in flight
+async fn consume_queue(
+    queue_name: &str,
+    kind: QueueKind,
+    settings: QueueSettings,
+    retry_publisher: &Arc<RetryPublisher>,
+) -> anyhow::Result<Arc<Semaphore>> {
+    let channel = RABBITMQ_CHANNEL.read().await;
+    let channel = channel.as_ref().unwrap();
+    channel
+        .queue_declare(
+            QueueDeclareArguments::new(queue_name)
+                .durable(true)
+                .finish(),
+        )
+        .await?;
+    retry::declare_retry_queues(channel, queue_name, settings.retry).await?;
+    // Per-consumer prefetch, so it's set before starting consumer. It should be large enough
+    // for messages in flight to fill batches of models
+    channel
+        .basic_qos(BasicQosArguments::new(0, settings.prefetch, false))
+        .await?;
+
+    let in_flight = Arc::new(Semaphore::new(settings.max_in_flight));
+    channel
+        .basic_consume(
+            Consumer {
+                in_flight: Arc::clone(&in_flight),
+                queue: Arc::new(ConsumedQueue {
+                    name: queue_name.to_owned(),
+                    kind,
+                    retry: settings.retry,
+                    retry_publisher: Arc::clone(retry_publisher),
+                }),
+            },
+            BasicConsumeArguments::new(queue_name, ""),
+        )
+        .await?;
+    Ok(in_flight)
+}
+
+/// Consumer that processes messages concurrently in separate tasks
+struct Consumer {
+    in_flight: Arc<Semaphore>,
+    queue: Arc<ConsumedQueue>,
+}
+
+/// Queue with settings of processing its messages
+struct ConsumedQueue {
+    name: String,
+    kind: QueueKind,
+    retry: RetryPolicy,
+    retry_publisher: Arc<RetryPublisher>,
+}
 
 #[async_trait]
 impl AsyncConsumer for Consumer {
@@ -241,34 +408,88 @@ impl AsyncConsumer for Consumer {
         basic_properties: BasicProperties,
         content: Vec<u8>,
     ) {
-        let message: WorkerMessage = serde_json::from_slice(&content).unwrap_or_log();
-        let res = match message {
-            WorkerMessage::OnUpload(x) => on_upload::process_request(x).await,
-            WorkerMessage::Search(x) => {
-                search::process_request(
-                    x,
-                    basic_properties.reply_to(),
-                    basic_properties.correlation_id(),
-                )
+        // Wait for free slot, so that at most `max_in_flight` messages are processed at once
+        let permit = Arc::clone(&self.in_flight)
+            .acquire_owned()
+            .await
+            .unwrap_or_log();
+        let channel = channel.clone();
+        let queue = Arc::clone(&self.queue);
+        tokio::spawn(async move {
+            process_message(&channel, &queue, deliver, basic_properties, content).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Process message and acknowledge it. Failed message is retried later or moved to dead-letter queue
+async fn process_message(
+    channel: &Channel,
+    queue: &ConsumedQueue,
+    deliver: Deliver,
+    basic_properties: BasicProperties,
+    content: Vec<u8>,
+) {
+    let res = match queue.kind {
+        QueueKind::Jobs => process_job(&content).await,
+        QueueKind::Rpc => rpc::process_request(&content, &basic_properties).await,
+    };
+    let res = match res {
+        Ok(_) => Ok(()),
+        Err(MessageError::Malformed(error)) => {
+            retry::dead_letter(
+                &queue.retry_publisher,
+                &queue.name,
+                basic_properties,
+                content,
+                &error,
+            )
+            .await
+        }
+        Err(MessageError::Failed(error)) if matches!(queue.kind, QueueKind::Rpc) => {
+            tracing::error!("Dropping failed request: {error}");
+            Ok(())
+        }
+        Err(MessageError::Failed(error)) => {
+            retry::retry_or_dead_letter(
+                &queue.retry_publisher,
+                &queue.name,
+                queue.retry,
+                basic_properties,
+                content,
+                &error,
+            )
+            .await
+        }
+    };
+    let res = match res {
+        Ok(_) => {
+            channel
+                .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
                 .await
-            }
-        };
-        match res {
-            Ok(_) => {
-                channel
-                    .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
-                    .await
-            }
-            Err(_) => {
-                channel
-                    .basic_nack(BasicNackArguments::new(deliver.delivery_tag(), false, true))
-                    .await
-            }
         }
-        .unwrap_or_log();
+        // Message couldn't be moved to another queue, so it's redelivered
+        Err(e) => {
+            tracing::error!("Can't retry or dead-letter message: {e}");
+            channel
+                .basic_nack(BasicNackArguments::new(deliver.delivery_tag(), false, true))
+                .await
+        }
+    };
+    // Channel can be closed while message was processed, it will be redelivered
+    if let Err(e) = res {
+        tracing::error!("Can't acknowledge message: {e}");
     }
 }
 
+async fn process_job(content: &[u8]) -> Result<(), MessageError> {
+    let message = serde_json::from_slice(content)
+        .map_err(|e| MessageError::Malformed(format!("can't parse message: {e}")))?;
+    worker::process_job(message)
+        .await
+        .map_err(|_| MessageError::Failed("processing failed".to_owned()))
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
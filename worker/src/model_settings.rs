@@ -0,0 +1,161 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{ArgAction, Parser, ValueEnum};
+use ort::{
+    execution_providers::{
+        ArenaExtendStrategy, CPUExecutionProvider, CUDAExecutionProvider,
+        ExecutionProviderDispatch, TensorRTExecutionProvider,
+    },
+    session::{builder::GraphOptimizationLevel, builder::SessionBuilder, Session},
+};
+
+/// Settings of models and batched inference, also read from environment variables
+#[derive(Debug, Clone, clap::Args)]
+pub struct ModelSettings {
+    /// Maximum number of images or texts processed by model at once
+    #[arg(long, env = "MODEL_BATCH_SIZE", default_value_t = 16)]
+    pub batch_size: usize,
+    /// Maximum time of waiting for batch to fill
+    #[arg(long, env = "MODEL_MAX_DELAY_MS", default_value_t = 100)]
+    pub max_delay_ms: u64,
+    /// Folder of image model with `model.onnx`
+    #[arg(long, env = "IMAGE_MODEL_DIR", default_value = "models/clip-ViT-B-32")]
+    pub image_model_dir: PathBuf,
+    /// Folder of text model with `model.onnx`, `dense.onnx` and `tokenizer.json`
+    #[arg(
+        long,
+        env = "TEXT_MODEL_DIR",
+        default_value = "models/clip-ViT-B-32-multilingual-v1"
+    )]
+    pub text_model_dir: PathBuf,
+    /// Execution providers in order of preference. Unavailable ones are skipped,
+    /// CPU is used for everything they don't support
+    #[arg(
+        long,
+        env = "EXECUTION_PROVIDERS",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [ExecutionProvider::Cuda, ExecutionProvider::Cpu]
+    )]
+    pub execution_providers: Vec<ExecutionProvider>,
+    /// Number of threads used to run operator, ONNX Runtime chooses if not set
+    #[arg(long, env = "INTRA_OP_THREADS")]
+    pub intra_op_threads: Option<usize>,
+    /// Number of threads used to run independent operators in parallel,
+    /// operators are run sequentially if not set
+    #[arg(long, env = "INTER_OP_THREADS")]
+    pub inter_op_threads: Option<usize>,
+    /// Graph optimizations applied when loading model
+    #[arg(long, env = "GRAPH_OPTIMIZATION_LEVEL", value_enum, default_value_t = OptimizationLevel::All)]
+    pub optimization_level: OptimizationLevel,
+    /// Use memory arena for CPU allocations
+    #[arg(long, env = "CPU_MEMORY_ARENA", action = ArgAction::Set, default_value_t = true)]
+    pub cpu_memory_arena: bool,
+    /// Preallocate memory based on allocations of previous runs
+    #[arg(long, env = "MEMORY_PATTERN", action = ArgAction::Set, default_value_t = true)]
+    pub memory_pattern: bool,
+    /// Limit of CUDA memory arena in bytes
+    #[arg(long, env = "GPU_MEMORY_LIMIT")]
+    pub gpu_memory_limit: Option<usize>,
+    /// How CUDA memory arena grows
+    #[arg(long, env = "GPU_ARENA_EXTEND_STRATEGY", value_enum)]
+    pub gpu_arena_extend_strategy: Option<ArenaExtend>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExecutionProvider {
+    Tensorrt,
+    Cuda,
+    Cpu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OptimizationLevel {
+    Disable,
+    Basic,
+    Extended,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ArenaExtend {
+    NextPowerOfTwo,
+    SameAsRequested,
+}
+
+/// Parser of settings from environment variables only
+#[derive(Parser)]
+struct EnvSettings {
+    #[command(flatten)]
+    model: ModelSettings,
+}
+
+impl ModelSettings {
+    /// Read settings from environment variables, using defaults for unset ones
+    pub fn from_env() -> Result<Self, String> {
+        EnvSettings::try_parse_from(["worker"])
+            .map(|x| x.model)
+            .map_err(|e| format!("Can't parse model settings: {e}"))
+    }
+
+    pub(crate) fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+
+    fn execution_provider(&self, provider: ExecutionProvider) -> ExecutionProviderDispatch {
+        match provider {
+            ExecutionProvider::Tensorrt => TensorRTExecutionProvider::default().build(),
+            ExecutionProvider::Cuda => {
+                let mut cuda = CUDAExecutionProvider::default();
+                if let Some(limit) = self.gpu_memory_limit {
+                    cuda = cuda.with_memory_limit(limit);
+                }
+                if let Some(strategy) = self.gpu_arena_extend_strategy {
+                    cuda = cuda.with_arena_extend_strategy(match strategy {
+                        ArenaExtend::NextPowerOfTwo => ArenaExtendStrategy::NextPowerOfTwo,
+                        ArenaExtend::SameAsRequested => ArenaExtendStrategy::SameAsRequested,
+                    });
+                }
+                cuda.build()
+            }
+            ExecutionProvider::Cpu => {
+                let mut cpu = CPUExecutionProvider::default();
+                if self.cpu_memory_arena {
+                    cpu = cpu.with_arena_allocator();
+                }
+                cpu.build()
+            }
+        }
+    }
+
+    /// Builder of model session with these settings. If `cpu_only` is set, accelerators
+    /// aren't used
+    pub(crate) fn session_builder(&self, cpu_only: bool) -> ort::Result<SessionBuilder> {
+        let providers: Vec<_> = if cpu_only {
+            vec![self.execution_provider(ExecutionProvider::Cpu)]
+        } else {
+            self.execution_providers
+                .iter()
+                .map(|&x| self.execution_provider(x))
+                .collect()
+        };
+        let mut builder = Session::builder()?
+            .with_execution_providers(providers)?
+            .with_optimization_level(match self.optimization_level {
+                OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+                OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+                OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+                OptimizationLevel::All => GraphOptimizationLevel::Level3,
+            })?
+            .with_memory_pattern(self.memory_pattern)?;
+        if let Some(threads) = self.intra_op_threads {
+            builder = builder.with_intra_threads(threads)?;
+        }
+        if let Some(threads) = self.inter_op_threads {
+            builder = builder
+                .with_parallel_execution(true)?
+                .with_inter_threads(threads)?;
+        }
+        Ok(builder)
+    }
+}
@@ -1,116 +1,414 @@
-use std::{io::Cursor, sync::Arc};
+use std::{
+    io::Cursor,
+    sync::{Arc, OnceLock},
+};
 
 use common::{
-    storage::{get_image_path, load_image, symlink_thumbnail},
-    OnUploadMessage, ELASTICSEARCH_INDEX,
+    storage::{
+        get_original_key, get_thumbnail_key, get_thumbnail_variant_keys, link_thumbnail, storage,
+    },
+    OnUploadMessage, ProcessingStatus, ThumbnailsMessage,
 };
-use elasticsearch::IndexParts;
 use exif::{In, Tag};
 use image::{
+    codecs::avif::AvifEncoder,
     imageops::{self, FilterType},
-    DynamicImage,
+    DynamicImage, ImageBuffer, ImageFormat, Pixel, RgbImage,
 };
-use serde_json::json;
-use tracing_unwrap::ResultExt;
+use tracing_unwrap::{OptionExt, ResultExt};
 
-use crate::{clip_image, ELASTICSEARCH};
+use crate::{
+    animation::{decode_animation, encode_animation, Animation},
+    clip_image,
+    search_backend::search_backend,
+    status::{report_status, report_thumbnails},
+};
 
 const MAX_WIDTH: u32 = 800;
 const MAX_HEIGHT: u32 = 600;
+/// Quality of thumbnail variants in lossy formats
+const VARIANT_QUALITY: u8 = 70;
+/// Encoding speed of AVIF from 1 (slowest) to 10
+const AVIF_SPEED: u8 = 6;
 
-async fn create_thumbnail(
-    message: Arc<OnUploadMessage>,
-    image: Arc<DynamicImage>,
-    image_buf: Vec<u8>,
-) -> Result<(), ()> {
-    if image.width() <= MAX_WIDTH && image.height() <= MAX_HEIGHT {
-        symlink_thumbnail(message.id, &message.format)
-            .await
-            .map_err(|e| tracing::error!("Can't symlink thumbnail: {e}"))?;
-    } else {
-        tokio::task::spawn_blocking(move || {
-            let mut cursor = Cursor::new(image_buf);
-            let exif_reader = exif::Reader::new();
-            let orientation = exif_reader
-                .read_from_container(&mut cursor)
-                .map(|exif| match exif.get_field(Tag::Orientation, In::PRIMARY) {
-                    Some(orientation) => match orientation.value.get_uint(0) {
-                        Some(v @ 1..=8) => v,
-                        _ => 1,
-                    },
-                    None => 1,
-                })
-                .unwrap_or(1);
-
-            let mut thumbnail = image
-                .resize(MAX_WIDTH, MAX_HEIGHT, FilterType::CatmullRom)
-                .to_rgb8();
-            if orientation == 2 {
-                thumbnail = imageops::flip_horizontal(&thumbnail);
-            } else if orientation == 3 {
-                thumbnail = imageops::rotate180(&thumbnail);
-            } else if orientation == 4 {
-                thumbnail = imageops::flip_horizontal(&thumbnail);
-            } else if orientation == 5 {
-                thumbnail = imageops::rotate90(&thumbnail);
-                thumbnail = imageops::flip_horizontal(&thumbnail);
-            } else if orientation == 6 {
-                thumbnail = imageops::rotate90(&thumbnail);
-            } else if orientation == 7 {
-                thumbnail = imageops::rotate270(&thumbnail);
-                thumbnail = imageops::flip_horizontal(&thumbnail);
-            } else if orientation == 8 {
-                thumbnail = imageops::rotate270(&thumbnail);
-            }
+/// Widths of responsive thumbnails, read from `THUMBNAIL_WIDTHS` (comma-separated list)
+pub(crate) fn thumbnail_widths() -> &'static [u32] {
+    static THUMBNAIL_WIDTHS: OnceLock<Vec<u32>> = OnceLock::new();
+    THUMBNAIL_WIDTHS.get_or_init(|| {
+        std::env::var("THUMBNAIL_WIDTHS")
+            .unwrap_or_else(|_| "320,640,1280,1920".to_owned())
+            .split(',')
+            .map(|x| x.trim().parse())
+            .collect::<Result<_, _>>()
+            .expect_or_log("Can't parse THUMBNAIL_WIDTHS")
+    })
+}
 
-            let path = get_image_path(message.id, &message.format, true);
-            thumbnail
-                .save(path)
-                .map_err(|e| tracing::error!("Can't save thumbnail: {e}"))
+/// EXIF orientation of image, 1 (normal) if it's not set
+fn exif_orientation(image_buf: &[u8]) -> u32 {
+    let mut cursor = Cursor::new(image_buf);
+    let exif_reader = exif::Reader::new();
+    exif_reader
+        .read_from_container(&mut cursor)
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|orientation| orientation.value.get_uint(0))
         })
+        .filter(|v| (1..=8).contains(v))
+        .unwrap_or(1)
+}
+
+/// Width and height of image after applying EXIF orientation
+fn oriented_dimensions(image: &DynamicImage, orientation: u32) -> (u32, u32) {
+    if orientation >= 5 {
+        (image.height(), image.width())
+    } else {
+        (image.width(), image.height())
+    }
+}
+
+/// Rotate and flip thumbnail according to EXIF orientation
+pub(crate) fn apply_orientation<P: Pixel + 'static>(
+    mut thumbnail: ImageBuffer<P, Vec<P::Subpixel>>,
+    orientation: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    if orientation == 2 {
+        thumbnail = imageops::flip_horizontal(&thumbnail);
+    } else if orientation == 3 {
+        thumbnail = imageops::rotate180(&thumbnail);
+    } else if orientation == 4 {
+        thumbnail = imageops::flip_horizontal(&thumbnail);
+    } else if orientation == 5 {
+        thumbnail = imageops::rotate90(&thumbnail);
+        thumbnail = imageops::flip_horizontal(&thumbnail);
+    } else if orientation == 6 {
+        thumbnail = imageops::rotate90(&thumbnail);
+    } else if orientation == 7 {
+        thumbnail = imageops::rotate270(&thumbnail);
+        thumbnail = imageops::flip_horizontal(&thumbnail);
+    } else if orientation == 8 {
+        thumbnail = imageops::rotate270(&thumbnail);
+    }
+    thumbnail
+}
+
+/// Resize image to fit into thumbnail size and rotate it according to EXIF orientation
+fn make_thumbnail(image: &DynamicImage, orientation: u32) -> RgbImage {
+    let thumbnail = if image.width() <= MAX_WIDTH && image.height() <= MAX_HEIGHT {
+        image.to_rgb8()
+    } else {
+        image
+            .resize(MAX_WIDTH, MAX_HEIGHT, FilterType::CatmullRom)
+            .to_rgb8()
+    };
+    apply_orientation(thumbnail, orientation)
+}
+
+/// Resize image to have `width` after rotating it according to EXIF orientation
+fn make_sized_thumbnail(image: &DynamicImage, orientation: u32, width: u32) -> RgbImage {
+    let (oriented_width, oriented_height) = oriented_dimensions(image, orientation);
+    let height = (oriented_height as u64 * width as u64 / oriented_width as u64).max(1) as u32;
+    let (new_width, new_height) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let thumbnail = image
+        .resize_exact(new_width, new_height, FilterType::CatmullRom)
+        .to_rgb8();
+    apply_orientation(thumbnail, orientation)
+}
+
+fn encode_thumbnail(thumbnail: &RgbImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let mut thumbnail_buf = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Avif => thumbnail.write_with_encoder(AvifEncoder::new_with_speed_quality(
+            &mut thumbnail_buf,
+            AVIF_SPEED,
+            VARIANT_QUALITY,
+        )),
+        _ => thumbnail.write_to(&mut thumbnail_buf, format),
+    }
+    .map(|_| thumbnail_buf.into_inner())
+    .map_err(|e| format!("Can't encode thumbnail: {e}"))
+}
+
+/// Encode thumbnail in format of original and save it, returns its size
+async fn store_thumbnail(
+    message: &OnUploadMessage,
+    thumbnail: Arc<RgbImage>,
+    width: Option<u32>,
+) -> Result<usize, String> {
+    let format = ImageFormat::from_extension(&message.format).unwrap_or_log();
+    let thumbnail_buf = tokio::task::spawn_blocking(move || encode_thumbnail(&thumbnail, format))
         .await
         .unwrap_or_log()?;
+    let size = thumbnail_buf.len();
+    storage()
+        .store(
+            &get_thumbnail_key(message.id, &message.format, width),
+            thumbnail_buf,
+        )
+        .await
+        .map_err(|e| format!("Can't save thumbnail: {e}"))?;
+    Ok(size)
+}
+
+/// Create thumbnail variants in modern formats, keeping only those smaller than `fallback_size`
+async fn create_thumbnail_variants(
+    message: &OnUploadMessage,
+    thumbnail: Arc<RgbImage>,
+    width: Option<u32>,
+    fallback_size: usize,
+) -> Result<(), String> {
+    for (variant, key) in get_thumbnail_variant_keys(message.id, &message.format, width) {
+        let format = ImageFormat::from_extension(variant).unwrap_or_log();
+        let thumbnail = Arc::clone(&thumbnail);
+        let thumbnail_buf =
+            tokio::task::spawn_blocking(move || encode_thumbnail(&thumbnail, format))
+                .await
+                .unwrap_or_log()?;
+        if thumbnail_buf.len() < fallback_size {
+            storage()
+                .store(&key, thumbnail_buf)
+                .await
+                .map_err(|e| format!("Can't save thumbnail: {e}"))?;
+        } else if storage().metadata(&key).await.is_ok() {
+            // Variant of previous thumbnail is outdated
+            storage()
+                .delete(&key)
+                .await
+                .map_err(|e| format!("Can't delete thumbnail: {e}"))?;
+        }
     }
     Ok(())
 }
 
-async fn add_to_elasticsearch(
+/// Create thumbnails of widths smaller than width of image, returns their widths
+async fn create_sized_thumbnails(
     message: &OnUploadMessage,
     image: Arc<DynamicImage>,
-) -> Result<(), ()> {
-    let embedding = clip_image::process_request(image).await;
+    orientation: u32,
+) -> Result<Vec<u32>, String> {
+    let (oriented_width, _) = oriented_dimensions(&image, orientation);
+    let mut widths = Vec::new();
+    for &width in thumbnail_widths() {
+        if width == 0 || width >= oriented_width {
+            continue;
+        }
+        let image = Arc::clone(&image);
+        let thumbnail = Arc::new(
+            tokio::task::spawn_blocking(move || make_sized_thumbnail(&image, orientation, width))
+                .await
+                .unwrap_or_log(),
+        );
+        let size = store_thumbnail(message, Arc::clone(&thumbnail), Some(width)).await?;
+        create_thumbnail_variants(message, thumbnail, Some(width), size).await?;
+        widths.push(width);
+    }
+    Ok(widths)
+}
 
-    ELASTICSEARCH
-        .get()
-        .unwrap()
-        .index(IndexParts::IndexId(
-            ELASTICSEARCH_INDEX,
-            &message.id.to_string(),
-        ))
-        .body(json!({"title": message.title, "embedding": embedding.embedding}))
-        .send()
-        .await
-        .map_err(|e| tracing::error!("Can't add to Elasticsearch: {e}"))?;
+/// Create thumbnail keeping animation, variants in formats without animation are removed
+async fn create_animated_thumbnail(
+    message: &OnUploadMessage,
+    original_key: &str,
+    animation: Arc<Animation>,
+    is_small: bool,
+    original_size: usize,
+) -> Result<(), String> {
+    let fallback_size = if is_small {
+        link_thumbnail(original_key, message.id, &message.format)
+            .await
+            .map_err(|e| format!("Can't link thumbnail: {e}"))?;
+        original_size
+    } else {
+        let format = ImageFormat::from_extension(&message.format).unwrap_or_log();
+        let animation = Arc::clone(&animation);
+        let thumbnail_buf =
+            tokio::task::spawn_blocking(move || encode_animation(&animation, format))
+                .await
+                .unwrap_or_log()?;
+        let size = thumbnail_buf.len();
+        storage()
+            .store(
+                &get_thumbnail_key(message.id, &message.format, None),
+                thumbnail_buf,
+            )
+            .await
+            .map_err(|e| format!("Can't save thumbnail: {e}"))?;
+        size
+    };
+
+    for (variant, key) in get_thumbnail_variant_keys(message.id, &message.format, None) {
+        let format = ImageFormat::from_extension(variant).unwrap_or_log();
+        let thumbnail_buf = if format == ImageFormat::WebP {
+            let animation = Arc::clone(&animation);
+            Some(
+                tokio::task::spawn_blocking(move || encode_animation(&animation, format))
+                    .await
+                    .unwrap_or_log()?,
+            )
+        } else {
+            None
+        };
+        match thumbnail_buf {
+            Some(thumbnail_buf) if thumbnail_buf.len() < fallback_size => storage()
+                .store(&key, thumbnail_buf)
+                .await
+                .map_err(|e| format!("Can't save thumbnail: {e}"))?,
+            _ if storage().metadata(&key).await.is_ok() => storage()
+                .delete(&key)
+                .await
+                .map_err(|e| format!("Can't delete thumbnail: {e}"))?,
+            _ => {}
+        }
+    }
     Ok(())
 }
 
-pub async fn process_request(message: OnUploadMessage) -> Result<(), ()> {
-    let image_buf = load_image(get_image_path(message.id, &message.format, false))
+/// Remove thumbnails of `widths` with their variants, missing ones are skipped
+pub(crate) async fn remove_sized_thumbnails(
+    id: i64,
+    format: &str,
+    widths: impl IntoIterator<Item = u32>,
+) -> Result<(), String> {
+    for width in widths {
+        let keys = std::iter::once(get_thumbnail_key(id, format, Some(width))).chain(
+            get_thumbnail_variant_keys(id, format, Some(width))
+                .into_iter()
+                .map(|(_, key)| key),
+        );
+        for key in keys {
+            if storage().metadata(&key).await.is_ok() {
+                storage()
+                    .delete(&key)
+                    .await
+                    .map_err(|e| format!("Can't delete thumbnail: {e}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create thumbnails of image, returns its dimensions and widths of responsive thumbnails
+pub(crate) async fn create_thumbnail(
+    message: Arc<OnUploadMessage>,
+    original_key: &str,
+    image: Arc<DynamicImage>,
+    image_buf: Vec<u8>,
+) -> Result<ThumbnailsMessage, String> {
+    let is_small = image.width() <= MAX_WIDTH && image.height() <= MAX_HEIGHT;
+    let original_size = image_buf.len();
+    let format = ImageFormat::from_extension(&message.format).unwrap_or_log();
+    let (orientation, animation) = tokio::task::spawn_blocking(move || {
+        let orientation = exif_orientation(&image_buf);
+        decode_animation(&image_buf, format, orientation, MAX_WIDTH, MAX_HEIGHT)
+            .map(|animation| (orientation, animation))
+    })
+    .await
+    .unwrap_or_log()?;
+    let (width, height) = oriented_dimensions(&image, orientation);
+
+    // Responsive thumbnails aren't created for animations to not lose animation in srcset
+    let created_widths = if let Some(animation) = animation {
+        create_animated_thumbnail(
+            &message,
+            original_key,
+            Arc::new(animation),
+            is_small,
+            original_size,
+        )
+        .await?;
+        Vec::new()
+    } else {
+        let image_ = Arc::clone(&image);
+        let thumbnail = Arc::new(
+            tokio::task::spawn_blocking(move || make_thumbnail(&image_, orientation))
+                .await
+                .unwrap_or_log(),
+        );
+
+        let fallback_size = if is_small {
+            link_thumbnail(original_key, message.id, &message.format)
+                .await
+                .map_err(|e| format!("Can't link thumbnail: {e}"))?;
+            original_size
+        } else {
+            store_thumbnail(&message, Arc::clone(&thumbnail), None).await?
+        };
+        create_thumbnail_variants(&message, thumbnail, None, fallback_size).await?;
+
+        create_sized_thumbnails(&message, image, orientation).await?
+    };
+
+    // Thumbnails of previous processing can exist for widths that weren't created now
+    let unused_widths = thumbnail_widths()
+        .iter()
+        .copied()
+        .filter(|x| !created_widths.contains(x));
+    remove_sized_thumbnails(message.id, &message.format, unused_widths).await?;
+
+    Ok(ThumbnailsMessage {
+        id: message.id,
+        width,
+        height,
+        thumbnail_widths: created_widths,
+    })
+}
+
+async fn add_to_search_index(
+    message: &OnUploadMessage,
+    image: Arc<DynamicImage>,
+) -> Result<(), String> {
+    let embedding = clip_image::process_request(image).await;
+    search_backend()
+        .index(message.id, &message.title, &embedding)
+        .await
+}
+
+async fn process_image(message: OnUploadMessage) -> Result<(), String> {
+    let original_key = get_original_key(message.id, &message.format, message.blob_hash.as_deref());
+    let image_buf = storage()
+        .load(&original_key)
         .await
-        .map_err(|e| tracing::error!("Can't load image: {e}"))?;
+        .map_err(|e| format!("Can't load image: {e}"))?;
     let image = Arc::new(
-        image::load_from_memory(&image_buf)
-            .map_err(|e| tracing::error!("Can't read image: {e}"))?,
+        image::load_from_memory(&image_buf).map_err(|e| format!("Can't read image: {e}"))?,
     );
 
     let message = Arc::new(message);
     let image_ = Arc::clone(&image);
     let (res_1, res_2) = tokio::join!(
-        create_thumbnail(Arc::clone(&message), image, image_buf),
-        add_to_elasticsearch(&message, image_)
+        async {
+            let thumbnails =
+                create_thumbnail(Arc::clone(&message), &original_key, image, image_buf).await?;
+            report_thumbnails(thumbnails).await;
+            report_status(message.id, ProcessingStatus::ThumbnailReady, None).await;
+            Ok(())
+        },
+        async {
+            add_to_search_index(&message, image_).await?;
+            report_status(message.id, ProcessingStatus::Indexed, None).await;
+            Ok(())
+        }
     );
-    if res_1.is_err() || res_2.is_err() {
-        return Err(());
+    res_1.and(res_2)
+}
+
+pub async fn process_request(message: OnUploadMessage) -> Result<(), ()> {
+    let id = message.id;
+    report_status(id, ProcessingStatus::Processing, None).await;
+    match process_image(message).await {
+        Ok(_) => {
+            report_status(id, ProcessingStatus::Done, None).await;
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!("Can't process image {id}: {e}");
+            report_status(id, ProcessingStatus::Failed, Some(e)).await;
+            Err(())
+        }
     }
-    Ok(())
 }
@@ -0,0 +1,55 @@
+use amqprs::{channel::BasicPublishArguments, BasicProperties};
+use common::RpcReply;
+use tracing_unwrap::ResultExt;
+
+use crate::{MessageError, RABBITMQ_CHANNEL};
+
+/// Process request from RPC queue and reply to client, failed request is answered with error
+pub async fn process_request(
+    content: &[u8],
+    basic_properties: &BasicProperties,
+) -> Result<(), MessageError> {
+    let (Some(reply_to), Some(correlation_id)) = (
+        basic_properties.reply_to(),
+        basic_properties.correlation_id(),
+    ) else {
+        return Err(MessageError::Malformed(
+            "request has no reply address".to_owned(),
+        ));
+    };
+
+    let request = match serde_json::from_slice(content) {
+        Ok(x) => x,
+        Err(e) => {
+            let error = format!("can't parse request: {e}");
+            // Client doesn't have to wait for deadline, request is kept for inspection
+            send_reply(reply_to, correlation_id, &Err(error.clone()))
+                .await
+                .ok();
+            return Err(MessageError::Malformed(error));
+        }
+    };
+    let reply = worker::process_request(request).await;
+    send_reply(reply_to, correlation_id, &reply)
+        .await
+        .map_err(MessageError::Failed)
+}
+
+async fn send_reply(reply_to: &str, correlation_id: &str, reply: &RpcReply) -> Result<(), String> {
+    let reply = serde_json::to_vec(reply).unwrap_or_log();
+
+    let props = BasicProperties::default()
+        .with_correlation_id(correlation_id)
+        .finish();
+    let args = BasicPublishArguments::default()
+        .routing_key(reply_to.to_owned())
+        .finish();
+    RABBITMQ_CHANNEL
+        .read()
+        .await
+        .as_ref()
+        .unwrap()
+        .basic_publish(props, reply, args)
+        .await
+        .map_err(|e| format!("can't send reply: {e}"))
+}
@@ -1,56 +1,31 @@
-use amqprs::{channel::BasicPublishArguments, BasicProperties};
-use common::{SearchMessage, SearchResponse, ELASTICSEARCH_INDEX};
-use elasticsearch::SearchParts;
-use serde_json::{json, Value};
-use tracing_unwrap::{OptionExt, ResultExt};
-
-use crate::{clip_text, Embedding, ELASTICSEARCH, RABBITMQ_CHANNEL};
-
-const RESULTS_PER_PAGE: i64 = 6;
-const KNN_PAGES: i64 = 20;
-const KNN_K: i64 = RESULTS_PER_PAGE * KNN_PAGES;
-
-async fn search_in_elasticsearch(
-    message: &SearchMessage,
-    embedding: Embedding,
-) -> Result<SearchResponse, ()> {
-    let request_body = json!({
-        "query": {
-            "simple_query_string" : {
-                "query": message.query_text,
-                "fields": ["title"]
-            }
-        },
-        "knn": {
-            "field": "embedding",
-            "query_vector": embedding.embedding,
-            "k": KNN_K
-        },
-        "_source": false
-    });
-
-    let res = ELASTICSEARCH
-        .get()
-        .unwrap()
-        .search(SearchParts::Index(&[ELASTICSEARCH_INDEX]))
-        .from(message.page * RESULTS_PER_PAGE)
-        .size(RESULTS_PER_PAGE + 1)
-        .body(request_body)
-        .send()
-        .await
-        .map_err(|e| tracing::error!("Can't search in Elasticsearch: {e}"))?
-        .json::<Value>()
-        .await
-        .unwrap_or_log();
-
-    let mut ids: Vec<_> = res["hits"]["hits"]
-        .as_array()
-        .unwrap_or_log()
-        .iter()
-        .map(|val| val["_id"].as_str().unwrap().parse().unwrap())
-        .collect();
+use std::sync::Arc;
+
+use common::{
+    storage::{get_query_image_key, storage},
+    SearchMessage, SearchQuery, SearchResponse,
+};
+
+use crate::{clip_image, clip_text, search_backend::search_backend, Embedding};
+
+const RESULTS_PER_PAGE: usize = 6;
+const KNN_PAGES: usize = 20;
+const KNN_K: usize = RESULTS_PER_PAGE * KNN_PAGES;
+
+async fn search(message: &SearchMessage, embedding: Embedding) -> Result<SearchResponse, String> {
+    let text = match &message.query {
+        SearchQuery::Text(query_text) => Some(query_text.as_str()),
+        // Title can't be matched, search only by embedding
+        SearchQuery::Image(_) => None,
+    };
+    let from = usize::try_from(message.page)
+        .ok()
+        .and_then(|x| x.checked_mul(RESULTS_PER_PAGE))
+        .ok_or_else(|| format!("Invalid page {}", message.page))?;
+    let mut ids = search_backend()
+        .search(text, &embedding, KNN_K, from, RESULTS_PER_PAGE + 1)
+        .await?;
     let mut last_page = true;
-    if ids.len() == (RESULTS_PER_PAGE + 1) as usize {
+    if ids.len() == RESULTS_PER_PAGE + 1 {
         last_page = false;
         ids.pop();
     }
@@ -58,29 +33,30 @@ async fn search_in_elasticsearch(
     Ok(SearchResponse { ids, last_page })
 }
 
-pub async fn process_request(
-    message: SearchMessage,
-    reply_to: Option<&String>,
-    correlation_id: Option<&String>,
-) -> Result<(), ()> {
-    let embedding = clip_text::process_request(message.query_text.clone()).await;
-    let response =
-        serde_json::to_vec(&search_in_elasticsearch(&message, embedding).await?).unwrap_or_log();
+async fn get_embedding(query: &SearchQuery) -> Option<Embedding> {
+    match query {
+        SearchQuery::Text(query_text) => Some(clip_text::process_request(query_text.clone()).await),
+        SearchQuery::Image(hash) => {
+            let image_buf = storage()
+                .load(&get_query_image_key(hash))
+                .await
+                .map_err(|e| tracing::error!("Can't load query image: {e}"))
+                .ok()?;
+            let image = image::load_from_memory(&image_buf)
+                .map_err(|e| tracing::error!("Can't read query image: {e}"))
+                .ok()?;
+            Some(clip_image::process_request(Arc::new(image)).await)
+        }
+    }
+}
 
-    let props = BasicProperties::default()
-        .with_persistence(true)
-        .with_correlation_id(correlation_id.unwrap_or_log())
-        .finish();
-    let args = BasicPublishArguments::default()
-        .routing_key(reply_to.unwrap_or_log().to_owned())
-        .finish();
-    RABBITMQ_CHANNEL
-        .read()
-        .await
-        .as_ref()
-        .unwrap()
-        .basic_publish(props, response, args)
-        .await
-        .map_err(|e| tracing::error!("Can't send search results: {e}"))?;
-    Ok(())
+pub async fn process_request(message: SearchMessage) -> Result<SearchResponse, String> {
+    match get_embedding(&message.query).await {
+        Some(embedding) => search(&message, embedding).await,
+        // Query can't be processed, retrying won't help
+        None => Ok(SearchResponse {
+            ids: Vec::new(),
+            last_page: true,
+        }),
+    }
 }
@@ -0,0 +1,496 @@
+use async_trait::async_trait;
+use common::ELASTICSEARCH_INDEX;
+use elasticsearch::{
+    auth::Credentials,
+    http::{
+        request::JsonBody,
+        transport::{SingleNodeConnectionPool, TransportBuilder},
+        StatusCode, Url,
+    },
+    indices::{
+        IndicesCreateParts, IndicesDeleteParts, IndicesExistsParts, IndicesGetAliasParts,
+        IndicesGetMappingParts, IndicesPutMappingParts,
+    },
+    BulkParts, DeleteParts, Elasticsearch, GetParts, IndexParts, SearchParts, UpdateParts,
+};
+use serde_json::{json, Value};
+
+use super::SearchBackend;
+use crate::Embedding;
+
+/// Version of index settings and mapping, must be increased after changing them.
+/// Physical index of each version is hidden behind `ELASTICSEARCH_INDEX` alias
+const INDEX_VERSION: u32 = 1;
+/// Alias of index being rebuilt, which also receives all changes
+const REBUILD_ALIAS: &str = "image_hosting_rebuild";
+/// Field of index `_meta` with ID of last image added by rebuild
+const REBUILD_LAST_ID: &str = "rebuild_last_id";
+
+/// Name of physical index with current version
+fn current_index() -> String {
+    format!("{ELASTICSEARCH_INDEX}_v{INDEX_VERSION}")
+}
+
+fn index_body() -> Value {
+    json!({
+        "settings": {
+            "index": {
+                "analysis": {
+                    "filter": {
+                        "english_stemmer": {
+                            "type": "stemmer",
+                            "name": "english"
+                        },
+                        "russian_stemmer": {
+                            "type": "stemmer",
+                            "name": "russian"
+                        },
+                        "english_stop": {
+                            "type": "stop",
+                            "stopwords": "_english_"
+                        },
+                        "russian_stop": {
+                            "type": "stop",
+                            "stopwords": "_russian_"
+                        }
+                    },
+                    "analyzer": {
+                        "en_ru_analyzer": {
+                            "tokenizer": "standard",
+                            "filter": [
+                                "lowercase",
+                                "english_stemmer",
+                                "russian_stemmer",
+                                "english_stop",
+                                "russian_stop"
+                            ]
+                        }
+                    }
+                }
+            }
+        },
+        "mappings": {
+            "properties": {
+                "title": {
+                    "type": "text",
+                    "analyzer": "en_ru_analyzer"
+                },
+                "embedding": {
+                    "type": "dense_vector",
+                    "dims": 512,
+                    "index": true,
+                    "similarity": "dot_product"
+                }
+            }
+        }
+    })
+}
+
+/// Progress of rebuilding index with current version
+#[derive(Debug, Clone, Copy)]
+pub enum RebuildState {
+    /// Alias already points to index with current version
+    UpToDate,
+    /// Images with IDs up to `last_id` are already added
+    InProgress { last_id: Option<i64> },
+}
+
+/// Search in Elasticsearch index with `dense_vector` field
+pub struct ElasticsearchBackend {
+    client: Elasticsearch,
+}
+
+impl ElasticsearchBackend {
+    pub fn new(url: &str, username: String, password: String) -> Result<Self, String> {
+        let url = Url::parse(url).map_err(|e| format!("Can't parse Elasticsearch URL: {e}"))?;
+        let conn_pool = SingleNodeConnectionPool::new(url);
+        let transport = TransportBuilder::new(conn_pool)
+            .auth(Credentials::Basic(username, password))
+            .build()
+            .map_err(|e| format!("Can't create Elasticsearch client: {e}"))?;
+        Ok(Self {
+            client: Elasticsearch::new(transport),
+        })
+    }
+
+    /// Physical indices behind alias, empty if alias doesn't exist
+    async fn alias_indices(&self, alias: &str) -> Result<Vec<String>, String> {
+        let res = self
+            .client
+            .indices()
+            .get_alias(IndicesGetAliasParts::Name(&[alias]))
+            .send()
+            .await
+            .map_err(|e| format!("Can't get Elasticsearch alias: {e}"))?;
+        if res.status_code() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let res = res
+            .error_for_status_code()
+            .map_err(|e| format!("Can't get Elasticsearch alias: {e}"))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Can't read Elasticsearch response: {e}"))?;
+        Ok(res
+            .as_object()
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// Whether index or alias exists
+    async fn exists(&self, name: &str) -> Result<bool, String> {
+        Ok(self
+            .client
+            .indices()
+            .exists(IndicesExistsParts::Index(&[name]))
+            .send()
+            .await
+            .map_err(|e| format!("Can't connect to Elasticsearch: {e}"))?
+            .status_code()
+            == StatusCode::OK)
+    }
+
+    /// Create index with current settings and mapping, added to alias
+    async fn create_index(&self, index: &str, alias: &str) -> Result<(), String> {
+        let mut body = index_body();
+        body["aliases"] = json!({ alias: {} });
+        self.client
+            .indices()
+            .create(IndicesCreateParts::Index(index))
+            .body(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status_code())
+            .map_err(|e| format!("Can't create Elasticsearch index: {e}"))?;
+        Ok(())
+    }
+
+    /// Indices to which changes are written: live one and one being rebuilt
+    async fn write_indices(&self) -> Result<Vec<String>, String> {
+        let mut indices = vec![ELASTICSEARCH_INDEX.to_owned()];
+        indices.extend(self.alias_indices(REBUILD_ALIAS).await?);
+        Ok(indices)
+    }
+
+    async fn index_document(
+        &self,
+        index: &str,
+        id: i64,
+        title: &str,
+        embedding: &Embedding,
+    ) -> Result<(), String> {
+        self.client
+            .index(IndexParts::IndexId(index, &id.to_string()))
+            .body(json!({"title": title, "embedding": embedding.embedding}))
+            .send()
+            .await
+            .map_err(|e| format!("Can't add to Elasticsearch: {e}"))?;
+        Ok(())
+    }
+
+    /// Run search request, returns IDs of hits
+    async fn search_ids(
+        &self,
+        request_body: Value,
+        from: usize,
+        size: usize,
+    ) -> Result<Vec<i64>, String> {
+        let map_err = |_| "Too many search results requested".to_owned();
+        let res = self
+            .client
+            .search(SearchParts::Index(&[ELASTICSEARCH_INDEX]))
+            .from(i64::try_from(from).map_err(map_err)?)
+            .size(i64::try_from(size).map_err(map_err)?)
+            .body(request_body)
+            .send()
+            .await
+            .map_err(|e| format!("Can't search in Elasticsearch: {e}"))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Can't read Elasticsearch response: {e}"))?;
+
+        Ok(res["hits"]["hits"]
+            .as_array()
+            .ok_or_else(|| "Elasticsearch response has no hits".to_owned())?
+            .iter()
+            .filter_map(|val| val["_id"].as_str()?.parse().ok())
+            .collect())
+    }
+
+    /// Create index with current version (or continue its rebuilding) and start
+    /// writing all changes to it. Rebuilding is started from scratch if `restart` is set
+    pub async fn start_rebuild(&self, restart: bool) -> Result<RebuildState, String> {
+        let index = current_index();
+        if self
+            .alias_indices(ELASTICSEARCH_INDEX)
+            .await?
+            .contains(&index)
+        {
+            return Ok(RebuildState::UpToDate);
+        }
+
+        if restart && self.exists(&index).await? {
+            self.client
+                .indices()
+                .delete(IndicesDeleteParts::Index(&[&index]))
+                .send()
+                .await
+                .and_then(|res| res.error_for_status_code())
+                .map_err(|e| format!("Can't delete Elasticsearch index: {e}"))?;
+        }
+        if !self.exists(&index).await? {
+            self.create_index(&index, REBUILD_ALIAS).await?;
+            return Ok(RebuildState::InProgress { last_id: None });
+        }
+
+        let res = self
+            .client
+            .indices()
+            .get_mapping(IndicesGetMappingParts::Index(&[&index]))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status_code())
+            .map_err(|e| format!("Can't get Elasticsearch mapping: {e}"))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Can't read Elasticsearch response: {e}"))?;
+        Ok(RebuildState::InProgress {
+            last_id: res[&index]["mappings"]["_meta"][REBUILD_LAST_ID].as_i64(),
+        })
+    }
+
+    /// Add images to index being rebuilt, keeping documents that were written after rebuild started
+    pub async fn add_to_rebuild(&self, docs: &[(i64, String, Embedding)]) -> Result<(), String> {
+        if docs.is_empty() {
+            return Ok(());
+        }
+        let body: Vec<JsonBody<Value>> = docs
+            .iter()
+            .flat_map(|(id, title, embedding)| {
+                [
+                    json!({"create": {"_id": id.to_string()}}).into(),
+                    json!({"title": title, "embedding": embedding.embedding}).into(),
+                ]
+            })
+            .collect();
+        let res = self
+            .client
+            .bulk(BulkParts::Index(REBUILD_ALIAS))
+            .body(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status_code())
+            .map_err(|e| format!("Can't add to Elasticsearch: {e}"))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Can't read Elasticsearch response: {e}"))?;
+
+        // Conflict means that document was already written with newer data
+        let failed = res["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|x| &x["create"])
+            .find(|x| {
+                x["status"]
+                    .as_u64()
+                    .is_some_and(|status| status >= 300 && status != 409)
+            });
+        match failed {
+            Some(item) => Err(format!("Can't add to Elasticsearch: {}", item["error"])),
+            None => Ok(()),
+        }
+    }
+
+    /// Remember that images with IDs up to `last_id` were added to index being rebuilt
+    pub async fn set_rebuild_progress(&self, last_id: i64) -> Result<(), String> {
+        self.client
+            .indices()
+            .put_mapping(IndicesPutMappingParts::Index(&[REBUILD_ALIAS]))
+            .body(json!({"_meta": {REBUILD_LAST_ID: last_id}}))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status_code())
+            .map_err(|e| format!("Can't save rebuild progress: {e}"))?;
+        Ok(())
+    }
+
+    /// Atomically switch alias to rebuilt index, returns names of previous indices.
+    /// Index from before versioning had the same name as alias, so it's deleted
+    pub async fn finish_rebuild(&self) -> Result<Vec<String>, String> {
+        let index = current_index();
+        let previous = self.alias_indices(ELASTICSEARCH_INDEX).await?;
+        let mut actions: Vec<_> = previous
+            .iter()
+            .map(|x| json!({"remove": {"index": x, "alias": ELASTICSEARCH_INDEX}}))
+            .collect();
+        if previous.is_empty() && self.exists(ELASTICSEARCH_INDEX).await? {
+            actions.push(json!({"remove_index": {"index": ELASTICSEARCH_INDEX}}));
+        }
+        actions.push(json!({"add": {"index": index, "alias": ELASTICSEARCH_INDEX}}));
+        actions.push(json!({"remove": {"index": index, "alias": REBUILD_ALIAS}}));
+
+        self.client
+            .indices()
+            .update_aliases()
+            .body(json!({ "actions": actions }))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status_code())
+            .map_err(|e| format!("Can't switch Elasticsearch alias: {e}"))?;
+        Ok(previous)
+    }
+}
+
+#[async_trait]
+impl SearchBackend for ElasticsearchBackend {
+    async fn init(&self) -> Result<(), String> {
+        if self.exists(ELASTICSEARCH_INDEX).await? {
+            if !self
+                .alias_indices(ELASTICSEARCH_INDEX)
+                .await?
+                .contains(&current_index())
+            {
+                tracing::warn!(
+                    "Search index has outdated version, run `worker reindex` to update it"
+                );
+            }
+            return Ok(());
+        }
+        self.create_index(&current_index(), ELASTICSEARCH_INDEX)
+            .await
+    }
+
+    async fn index(&self, id: i64, title: &str, embedding: &Embedding) -> Result<(), String> {
+        for index in self.write_indices().await? {
+            self.index_document(&index, id, title, embedding).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_title(&self, id: i64, title: &str) -> Result<bool, String> {
+        let res = self
+            .client
+            .update(UpdateParts::IndexId(ELASTICSEARCH_INDEX, &id.to_string()))
+            .body(json!({ "doc": { "title": title } }))
+            .send()
+            .await
+            .map_err(|e| format!("Can't update in Elasticsearch: {e}"))?;
+        let status = res.status_code();
+        // Image is not indexed yet, caller decides whether to retry
+        if status == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !status.is_success() {
+            return Err(format!("Can't update in Elasticsearch: status {status}"));
+        }
+
+        // Image may not be added to index being rebuilt yet, so whole document is written
+        let rebuild_indices = self.alias_indices(REBUILD_ALIAS).await?;
+        if !rebuild_indices.is_empty() {
+            if let Some(embedding) = self.get_embedding(id).await? {
+                for index in rebuild_indices {
+                    self.index_document(&index, id, title, &embedding).await?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), String> {
+        for index in self.write_indices().await? {
+            let res = self
+                .client
+                .delete(DeleteParts::IndexId(&index, &id.to_string()))
+                .send()
+                .await
+                .map_err(|e| format!("Can't delete from Elasticsearch: {e}"))?;
+            let status = res.status_code();
+            if !status.is_success() && status != StatusCode::NOT_FOUND {
+                return Err(format!("Can't delete from Elasticsearch: status {status}"));
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_embedding(&self, id: i64) -> Result<Option<Embedding>, String> {
+        let res = self
+            .client
+            .get(GetParts::IndexId(ELASTICSEARCH_INDEX, &id.to_string()))
+            ._source_includes(&["embedding"])
+            .send()
+            .await
+            .map_err(|e| format!("Can't get from Elasticsearch: {e}"))?;
+        if res.status_code() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut res = res
+            .error_for_status_code()
+            .map_err(|e| format!("Can't get from Elasticsearch: {e}"))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Can't read Elasticsearch response: {e}"))?;
+        serde_json::from_value(res["_source"].take())
+            .map(Some)
+            .map_err(|e| format!("Can't read embedding from Elasticsearch: {e}"))
+    }
+
+    async fn search(
+        &self,
+        text: Option<&str>,
+        embedding: &Embedding,
+        k: usize,
+        from: usize,
+        size: usize,
+    ) -> Result<Vec<i64>, String> {
+        let knn = json!({
+            "field": "embedding",
+            "query_vector": embedding.embedding,
+            "k": k
+        });
+        let request_body = match text {
+            Some(text) => json!({
+                "query": {
+                    "simple_query_string" : {
+                        "query": text,
+                        "fields": ["title"]
+                    }
+                },
+                "knn": knn,
+                "_source": false
+            }),
+            None => json!({
+                "knn": knn,
+                "_source": false
+            }),
+        };
+        self.search_ids(request_body, from, size).await
+    }
+
+    async fn similar(
+        &self,
+        embedding: &Embedding,
+        exclude_id: i64,
+        count: usize,
+    ) -> Result<Vec<i64>, String> {
+        let request_body = json!({
+            "knn": {
+                "field": "embedding",
+                "query_vector": embedding.embedding,
+                "k": count,
+                "num_candidates": (count * 10).max(100),
+                "filter": {
+                    "bool": {
+                        "must_not": {
+                            "ids": {
+                                "values": [exclude_id.to_string()]
+                            }
+                        }
+                    }
+                }
+            },
+            "_source": false
+        });
+        self.search_ids(request_body, 0, count).await
+    }
+}
@@ -0,0 +1,617 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tantivy::{
+    collector::TopDocs,
+    doc,
+    query::{BooleanQuery, ConstScoreQuery, QueryParser, TermSetQuery},
+    schema::{IndexRecordOption, Schema, TextFieldIndexing, TextOptions, FAST, INDEXED},
+    tokenizer::{Language, LowerCaser, SimpleTokenizer, Stemmer, StopWordFilter, TextAnalyzer},
+    Index, IndexReader, IndexWriter, ReloadPolicy, Term,
+};
+use tracing_unwrap::ResultExt;
+
+use super::{hnsw::Hnsw, SearchBackend};
+use crate::Embedding;
+
+/// Folder of Tantivy index with titles inside index folder
+const TEXT_INDEX_PATH: &str = "text";
+/// File with snapshot of vector index inside index folder
+const VECTOR_INDEX_FILE: &str = "vectors.bin";
+/// File with changes of vector index made after snapshot
+const VECTOR_LOG_FILE: &str = "vectors.log";
+/// File with changes that are being written to snapshot
+const OLD_VECTOR_LOG_FILE: &str = "vectors.old.log";
+/// Number of logged changes after which snapshot of vector index is written
+const SNAPSHOT_CHANGES: usize = 10_000;
+const TOKENIZER_NAME: &str = "en_ru";
+/// Memory budget of Tantivy index writer
+const WRITER_MEMORY: usize = 50_000_000;
+/// Minimum number of candidates in nearest neighbors search
+const MIN_CANDIDATES: usize = 100;
+
+/// Search without external services: Tantivy index for titles and HNSW index for embeddings,
+/// both stored in local folder
+pub struct EmbeddedBackend {
+    root: PathBuf,
+    state: OnceLock<Arc<State>>,
+}
+
+/// Opened indices
+struct State {
+    root: PathBuf,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    query_parser: QueryParser,
+    id_field: tantivy::schema::Field,
+    title_field: tantivy::schema::Field,
+    vectors: RwLock<Hnsw>,
+    vector_log: Mutex<VectorLog>,
+    /// Number of changes made since opening
+    changes: AtomicU64,
+    /// Number of committed changes, locked while committing
+    committed: Mutex<u64>,
+    /// Whether snapshot of vector index is being written
+    snapshot_running: AtomicBool,
+}
+
+/// Change of vector index
+#[derive(Debug, Serialize, Deserialize)]
+enum VectorChange {
+    Insert(i64, Vec<f32>),
+    Remove(i64),
+}
+
+impl VectorChange {
+    fn apply(self, vectors: &mut Hnsw) {
+        match self {
+            Self::Insert(id, vector) => vectors.insert(id, vector),
+            Self::Remove(id) => vectors.remove(id),
+        }
+    }
+}
+
+/// Append-only log of changes of vector index made after its snapshot
+struct VectorLog {
+    file: File,
+    /// Number of changes in log
+    len: usize,
+}
+
+impl VectorLog {
+    /// Create empty log, replacing existing one
+    fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Can't create vector index log: {e}"))?;
+        Ok(Self { file, len: 0 })
+    }
+
+    /// Append change prefixed by its length, in one write so that log is never read partially
+    fn append(&mut self, change: &VectorChange) -> Result<(), String> {
+        let data = bincode::serialize(change).unwrap_or_log();
+        let mut entry = Vec::with_capacity(4 + data.len());
+        entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&data);
+        self.file
+            .write_all(&entry)
+            .map_err(|e| format!("Can't write vector index log: {e}"))?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), String> {
+        self.file
+            .sync_data()
+            .map_err(|e| format!("Can't write vector index log: {e}"))
+    }
+}
+
+/// Changes from vector index log, incompletely written change at the end is skipped
+fn read_vector_log(path: &Path) -> Result<Vec<VectorChange>, String> {
+    let data = match std::fs::read(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Can't read vector index log: {e}")),
+    };
+    let mut changes = Vec::new();
+    let mut rest = data.as_slice();
+    while rest.len() >= 4 {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let Some(entry) = rest.get(4..(4 + len)) else {
+            break;
+        };
+        changes.push(
+            bincode::deserialize(entry).map_err(|e| format!("Can't read vector index log: {e}"))?,
+        );
+        rest = &rest[(4 + len)..];
+    }
+    if !rest.is_empty() {
+        tracing::warn!("Incomplete change at the end of vector index log is skipped");
+    }
+    Ok(changes)
+}
+
+/// Write snapshot of vector index, replacing previous one atomically
+fn write_snapshot(root: &Path, vectors: &Hnsw) -> Result<(), String> {
+    let data = bincode::serialize(vectors).unwrap_or_log();
+    let path = root.join(VECTOR_INDEX_FILE);
+    let tmp_path = path.with_extension("tmp");
+    File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(&data)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, &path))
+        .map_err(|e| format!("Can't save vector index: {e}"))
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), String> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(format!("Can't remove {}: {e}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Analyzer matching `en_ru_analyzer` of Elasticsearch index
+fn en_ru_analyzer() -> TextAnalyzer {
+    TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(LowerCaser)
+        .filter(Stemmer::new(Language::English))
+        .filter(Stemmer::new(Language::Russian))
+        .filter(StopWordFilter::new(Language::English).unwrap())
+        .filter(StopWordFilter::new(Language::Russian).unwrap())
+        .build()
+}
+
+/// Score of vector similarity, same as `dot_product` similarity of Elasticsearch
+fn knn_score(similarity: f32) -> f32 {
+    (1.0 + similarity) / 2.0
+}
+
+impl EmbeddedBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            state: OnceLock::new(),
+        }
+    }
+
+    /// Run blocking operation on opened indices
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Arc<State>) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let state = Arc::clone(self.state.get().expect("Search index is not opened"));
+        tokio::task::spawn_blocking(move || f(&state))
+            .await
+            .unwrap_or_log()
+    }
+}
+
+impl State {
+    fn open(root: PathBuf) -> Result<Self, String> {
+        let text_path = root.join(TEXT_INDEX_PATH);
+        std::fs::create_dir_all(&text_path)
+            .map_err(|e| format!("Can't create search index folder: {e}"))?;
+
+        let mut schema_builder = Schema::builder();
+        let id_field = schema_builder.add_i64_field("id", INDEXED | FAST);
+        let title_field = schema_builder.add_text_field(
+            "title",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(TOKENIZER_NAME)
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            ),
+        );
+        let directory = tantivy::directory::MmapDirectory::open(&text_path)
+            .map_err(|e| format!("Can't open search index: {e}"))?;
+        let index = Index::open_or_create(directory, schema_builder.build())
+            .map_err(|e| format!("Can't open search index: {e}"))?;
+        index
+            .tokenizers()
+            .register(TOKENIZER_NAME, en_ru_analyzer());
+
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()
+            .map_err(|e| format!("Can't open search index: {e}"))?;
+        let writer = index
+            .writer(WRITER_MEMORY)
+            .map_err(|e| format!("Can't open search index: {e}"))?;
+        let query_parser = QueryParser::for_index(&index, vec![title_field]);
+
+        let mut vectors: Hnsw = match std::fs::read(root.join(VECTOR_INDEX_FILE)) {
+            Ok(x) => {
+                bincode::deserialize(&x).map_err(|e| format!("Can't read vector index: {e}"))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Hnsw::default(),
+            Err(e) => return Err(format!("Can't read vector index: {e}")),
+        };
+        // Old log is left if writing of snapshot was interrupted
+        let log_path = root.join(VECTOR_LOG_FILE);
+        let old_log_path = root.join(OLD_VECTOR_LOG_FILE);
+        let mut replayed = false;
+        for path in [&old_log_path, &log_path] {
+            for change in read_vector_log(path)? {
+                change.apply(&mut vectors);
+                replayed = true;
+            }
+        }
+        if vectors.needs_compaction() {
+            vectors.compact();
+            replayed = true;
+        }
+        if replayed {
+            write_snapshot(&root, &vectors)?;
+        }
+        let vector_log = VectorLog::create(&log_path)?;
+        remove_if_exists(&old_log_path)?;
+
+        Ok(Self {
+            root,
+            reader,
+            writer: Mutex::new(writer),
+            query_parser,
+            id_field,
+            title_field,
+            vectors: RwLock::new(vectors),
+            vector_log: Mutex::new(vector_log),
+            changes: AtomicU64::new(0),
+            committed: Mutex::new(0),
+            snapshot_running: AtomicBool::new(false),
+        })
+    }
+
+    /// Replace title document of image (or only remove it if `title` is `None`) and change its
+    /// vector, then wait until change is committed
+    fn write(
+        self: &Arc<Self>,
+        id: i64,
+        title: Option<&str>,
+        vector: Option<VectorChange>,
+    ) -> Result<(), String> {
+        let change = {
+            let writer = self.writer.lock().unwrap();
+            writer.delete_term(Term::from_field_i64(self.id_field, id));
+            if let Some(title) = title {
+                writer
+                    .add_document(doc!(self.id_field => id, self.title_field => title))
+                    .map_err(|e| format!("Can't add to search index: {e}"))?;
+            }
+            if let Some(vector) = vector {
+                let mut log = self.vector_log.lock().unwrap();
+                log.append(&vector)?;
+                vector.apply(&mut self.vectors.write().unwrap());
+            }
+            self.changes.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        self.commit(change)
+    }
+
+    /// Commit changes up to number `change`. Changes made by other threads while previous commit
+    /// was running are committed together, so concurrent writes share commits
+    fn commit(self: &Arc<Self>, change: u64) -> Result<(), String> {
+        let mut committed = self.committed.lock().unwrap();
+        if *committed >= change {
+            return Ok(());
+        }
+        let (changes, needs_snapshot) = {
+            let mut writer = self.writer.lock().unwrap();
+            let changes = self.changes.load(Ordering::SeqCst);
+            writer
+                .commit()
+                .map_err(|e| format!("Can't commit search index: {e}"))?;
+            let log = self.vector_log.lock().unwrap();
+            log.sync()?;
+            (changes, log.len >= SNAPSHOT_CHANGES)
+        };
+        self.reader
+            .reload()
+            .map_err(|e| format!("Can't reload search index: {e}"))?;
+        *committed = changes;
+
+        if needs_snapshot && !self.snapshot_running.swap(true, Ordering::SeqCst) {
+            let state = Arc::clone(self);
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = state.snapshot() {
+                    tracing::error!("{e}");
+                }
+                state.snapshot_running.store(false, Ordering::SeqCst);
+            });
+        }
+        Ok(())
+    }
+
+    /// Write snapshot of vector index and start new log. Index with many removed nodes is
+    /// compacted meanwhile and replaced with compacted one
+    fn snapshot(&self) -> Result<(), String> {
+        let log_path = self.root.join(VECTOR_LOG_FILE);
+        let old_log_path = self.root.join(OLD_VECTOR_LOG_FILE);
+        let mut vectors = {
+            let mut log = self.vector_log.lock().unwrap();
+            // Uncommitted changes are committed with new log, so old one is synced here
+            log.sync()?;
+            std::fs::rename(&log_path, &old_log_path)
+                .map_err(|e| format!("Can't rotate vector index log: {e}"))?;
+            *log = VectorLog::create(&log_path)?;
+            self.vectors.read().unwrap().clone()
+        };
+
+        let compacted = vectors.needs_compaction();
+        if compacted {
+            vectors.compact();
+        }
+        write_snapshot(&self.root, &vectors)?;
+        remove_if_exists(&old_log_path)?;
+
+        if compacted {
+            // Apply changes made while snapshot was written
+            let _log = self.vector_log.lock().unwrap();
+            for change in read_vector_log(&log_path)? {
+                change.apply(&mut vectors);
+            }
+            *self.vectors.write().unwrap() = vectors;
+        }
+        Ok(())
+    }
+
+    /// Relevance of titles to text query by image ID for `limit` most relevant images and
+    /// images from `ids`
+    fn search_titles(
+        &self,
+        text: &str,
+        limit: usize,
+        ids: &[i64],
+    ) -> Result<HashMap<i64, f32>, String> {
+        let searcher = self.reader.searcher();
+        let map_err = |e| format!("Can't search in search index: {e}");
+        let (query, _) = self.query_parser.parse_query_lenient(text);
+        let mut top_docs = searcher
+            .search(&query, &TopDocs::with_limit(limit.max(1)))
+            .map_err(map_err)?;
+        if !ids.is_empty() {
+            let terms = ids
+                .iter()
+                .map(|&id| Term::from_field_i64(self.id_field, id));
+            let filter = ConstScoreQuery::new(Box::new(TermSetQuery::new(terms)), 0.0);
+            let query = BooleanQuery::intersection(vec![query, Box::new(filter)]);
+            top_docs.extend(
+                searcher
+                    .search(&query, &TopDocs::with_limit(ids.len()))
+                    .map_err(map_err)?,
+            );
+        }
+
+        let id_columns = searcher
+            .segment_readers()
+            .iter()
+            .map(|x| x.fast_fields().i64("id"))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Can't read search index: {e}"))?;
+        top_docs
+            .into_iter()
+            .map(|(score, address)| {
+                let id = id_columns[address.segment_ord as usize]
+                    .first(address.doc_id)
+                    .ok_or_else(|| "Search index document has no ID".to_owned())?;
+                Ok((id, score))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SearchBackend for EmbeddedBackend {
+    async fn init(&self) -> Result<(), String> {
+        let root = self.root.clone();
+        let state = tokio::task::spawn_blocking(move || State::open(root))
+            .await
+            .unwrap_or_log()?;
+        self.state
+            .set(Arc::new(state))
+            .map_err(|_| "Search index is already opened".to_owned())
+    }
+
+    async fn index(&self, id: i64, title: &str, embedding: &Embedding) -> Result<(), String> {
+        let title = title.to_owned();
+        let vector = VectorChange::Insert(id, embedding.embedding.clone());
+        self.run(move |state| state.write(id, Some(&title), Some(vector)))
+            .await
+    }
+
+    async fn update_title(&self, id: i64, title: &str) -> Result<bool, String> {
+        let title = title.to_owned();
+        self.run(move |state| {
+            // Image is not indexed yet, caller decides whether to retry
+            if state.vectors.read().unwrap().get(id).is_none() {
+                return Ok(false);
+            }
+            state.write(id, Some(&title), None)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), String> {
+        self.run(move |state| {
+            let indexed = state.vectors.read().unwrap().get(id).is_some();
+            state.write(id, None, indexed.then_some(VectorChange::Remove(id)))
+        })
+        .await
+    }
+
+    async fn get_embedding(&self, id: i64) -> Result<Option<Embedding>, String> {
+        self.run(move |state| {
+            Ok(state.vectors.read().unwrap().get(id).map(|x| Embedding {
+                embedding: x.to_vec(),
+            }))
+        })
+        .await
+    }
+
+    async fn search(
+        &self,
+        text: Option<&str>,
+        embedding: &Embedding,
+        k: usize,
+        from: usize,
+        size: usize,
+    ) -> Result<Vec<i64>, String> {
+        let text = text.map(ToOwned::to_owned);
+        let vector = embedding.embedding.clone();
+        self.run(move |state| {
+            let num_candidates = (k * 10).max(MIN_CANDIDATES);
+            let neighbors =
+                state
+                    .vectors
+                    .read()
+                    .unwrap()
+                    .search(&vector, k, num_candidates, |_| true);
+            // Scores of title and embedding are summed, like in Elasticsearch. Images outside of
+            // nearest neighbors can only be on requested page if their title is relevant enough
+            let mut scores = match text {
+                Some(text) => {
+                    let ids: Vec<_> = neighbors.iter().map(|x| x.0).collect();
+                    let limit = from
+                        .checked_add(size)
+                        .ok_or_else(|| "Too many search results requested".to_owned())?;
+                    state.search_titles(&text, limit, &ids)?
+                }
+                None => HashMap::new(),
+            };
+            for (id, similarity) in neighbors {
+                *scores.entry(id).or_default() += knn_score(similarity);
+            }
+
+            let mut scores: Vec<_> = scores.into_iter().collect();
+            scores.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            Ok(scores
+                .into_iter()
+                .skip(from)
+                .take(size)
+                .map(|(id, _)| id)
+                .collect())
+        })
+        .await
+    }
+
+    async fn similar(
+        &self,
+        embedding: &Embedding,
+        exclude_id: i64,
+        count: usize,
+    ) -> Result<Vec<i64>, String> {
+        let vector = embedding.embedding.clone();
+        self.run(move |state| {
+            let num_candidates = (count * 10).max(MIN_CANDIDATES);
+            Ok(state
+                .vectors
+                .read()
+                .unwrap()
+                .search(&vector, count, num_candidates, |id| id != exclude_id)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedding(i: usize) -> Embedding {
+        let mut embedding = vec![0.0; 16];
+        embedding[i] = 1.0;
+        Embedding { embedding }
+    }
+
+    async fn open(root: &Path) -> EmbeddedBackend {
+        let backend = EmbeddedBackend::new(root);
+        backend.init().await.unwrap();
+        backend
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn search() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = open(dir.path()).await;
+        backend.index(1, "red car", &embedding(0)).await.unwrap();
+        backend.index(2, "blue car", &embedding(1)).await.unwrap();
+        backend.index(3, "red flower", &embedding(2)).await.unwrap();
+
+        // Title relevance is added to similarity
+        let ids = backend.search(None, &embedding(1), 3, 0, 10).await;
+        assert_eq!(ids.unwrap()[0], 2);
+        let ids = backend.search(Some("red"), &embedding(2), 3, 0, 10).await;
+        assert_eq!(ids.unwrap()[..2], [3, 1]);
+        let ids = backend.search(Some("cars"), &embedding(2), 1, 0, 10).await;
+        assert_eq!(ids.unwrap(), [3, 1, 2]);
+        let ids = backend.search(Some("cars"), &embedding(2), 1, 1, 1).await;
+        assert_eq!(ids.unwrap(), [1]);
+
+        assert!(backend.update_title(2, "green").await.unwrap());
+        assert!(!backend.update_title(4, "not indexed").await.unwrap());
+        let ids = backend.search(Some("blue"), &embedding(3), 1, 0, 10).await;
+        assert!(!ids.unwrap().contains(&2));
+        let ids = backend.search(Some("green"), &embedding(3), 1, 0, 10).await;
+        assert_eq!(ids.unwrap()[0], 2);
+
+        backend.delete(1).await.unwrap();
+        backend.delete(5).await.unwrap();
+        let ids = backend.search(Some("car"), &embedding(0), 3, 0, 10).await;
+        assert!(!ids.unwrap().contains(&1));
+        assert!(backend.get_embedding(1).await.unwrap().is_none());
+        let similar = backend.similar(&embedding(1), 2, 1).await.unwrap();
+        assert_eq!(similar, [3]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changes_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let backend = open(dir.path()).await;
+            backend.index(1, "red car", &embedding(0)).await.unwrap();
+            backend.index(2, "blue car", &embedding(1)).await.unwrap();
+            backend.run(|state| state.snapshot()).await.unwrap();
+            backend.index(3, "red flower", &embedding(2)).await.unwrap();
+            backend.delete(2).await.unwrap();
+        }
+
+        let backend = open(dir.path()).await;
+        let ids = backend.search(Some("red"), &embedding(3), 3, 0, 10).await;
+        assert_eq!(ids.unwrap().len(), 2);
+        assert!(backend.get_embedding(2).await.unwrap().is_none());
+        let vector = backend.get_embedding(3).await.unwrap().unwrap();
+        assert_eq!(vector.embedding, embedding(2).embedding);
+        assert!(!dir.path().join(OLD_VECTOR_LOG_FILE).exists());
+    }
+
+    #[test]
+    fn incomplete_log_entry_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(VECTOR_LOG_FILE);
+        let mut log = VectorLog::create(&path).unwrap();
+        log.append(&VectorChange::Insert(1, vec![1.0, 0.0]))
+            .unwrap();
+        log.append(&VectorChange::Remove(1)).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        log.file.set_len(len - 1).unwrap();
+        let changes = read_vector_log(&path).unwrap();
+        assert!(matches!(changes[..], [VectorChange::Insert(1, _)]));
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of neighbors of node on upper layers
+const M: usize = 16;
+/// Maximum number of neighbors of node on bottom layer
+const M0: usize = 2 * M;
+/// Number of candidates considered when inserting node
+const EF_CONSTRUCTION: usize = 100;
+
+/// Similarity of normalized vectors
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Node index ordered by similarity to query
+#[derive(Debug, Clone, Copy)]
+struct Scored(f32, usize);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    id: i64,
+    vector: Vec<f32>,
+    /// Neighbors on each layer, starting from bottom one
+    layers: Vec<Vec<usize>>,
+    /// Removed nodes are kept for navigation, but not returned
+    deleted: bool,
+}
+
+/// Hierarchical navigable small world graph for approximate search of nearest vectors by dot product
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hnsw {
+    nodes: Vec<Node>,
+    /// Node of each ID that wasn't removed
+    ids: HashMap<i64, usize>,
+    entry_point: Option<usize>,
+}
+
+impl Hnsw {
+    /// Whether removed nodes take more space than remaining ones
+    pub fn needs_compaction(&self) -> bool {
+        self.nodes.len() > 2 * self.ids.len() + M
+    }
+
+    /// Rebuild graph without removed nodes
+    pub fn compact(&mut self) {
+        let mut compacted = Self::default();
+        for node in self.nodes.drain(..).filter(|x| !x.deleted) {
+            compacted.insert(node.id, node.vector);
+        }
+        *self = compacted;
+    }
+
+    pub fn get(&self, id: i64) -> Option<&[f32]> {
+        self.ids.get(&id).map(|&x| self.nodes[x].vector.as_slice())
+    }
+
+    pub fn remove(&mut self, id: i64) {
+        if let Some(idx) = self.ids.remove(&id) {
+            self.nodes[idx].deleted = true;
+        }
+    }
+
+    /// Add vector, replacing previous vector with the same ID
+    pub fn insert(&mut self, id: i64, vector: Vec<f32>) {
+        self.remove(id);
+
+        // Layer is chosen with exponentially decaying probability
+        let level_mult = 1.0 / (M as f64).ln();
+        let random: f64 = rand::thread_rng().gen_range(f64::EPSILON..1.0);
+        let level = (-random.ln() * level_mult) as usize;
+
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            id,
+            vector,
+            layers: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id, idx);
+
+        let Some(mut entry_point) = self.entry_point else {
+            self.entry_point = Some(idx);
+            return;
+        };
+        let top_level = self.nodes[entry_point].layers.len() - 1;
+        let vector = self.nodes[idx].vector.clone();
+        for layer in (level + 1..=top_level).rev() {
+            entry_point = self.search_layer(&vector, entry_point, 1, layer)[0].1;
+        }
+        for layer in (0..=level.min(top_level)).rev() {
+            let candidates = self.search_layer(&vector, entry_point, EF_CONSTRUCTION, layer);
+            let max_neighbors = if layer == 0 { M0 } else { M };
+            let neighbors: Vec<_> = candidates.iter().take(max_neighbors).map(|x| x.1).collect();
+            for &neighbor in &neighbors {
+                self.connect(neighbor, idx, layer, max_neighbors);
+            }
+            self.nodes[idx].layers[layer] = neighbors;
+            entry_point = candidates[0].1;
+        }
+        if level > top_level {
+            self.entry_point = Some(idx);
+        }
+    }
+
+    /// Add edge from `node` to `neighbor`, keeping only the closest neighbors
+    fn connect(&mut self, node: usize, neighbor: usize, layer: usize, max_neighbors: usize) {
+        self.nodes[node].layers[layer].push(neighbor);
+        if self.nodes[node].layers[layer].len() <= max_neighbors {
+            return;
+        }
+        let vector = &self.nodes[node].vector;
+        let mut neighbors: Vec<_> = self.nodes[node].layers[layer]
+            .iter()
+            .map(|&x| Scored(dot(vector, &self.nodes[x].vector), x))
+            .collect();
+        neighbors.sort_unstable_by(|a, b| b.cmp(a));
+        self.nodes[node].layers[layer] = neighbors
+            .into_iter()
+            .take(max_neighbors)
+            .map(|x| x.1)
+            .collect();
+    }
+
+    /// Greedy beam search on one layer, returns up to `ef` closest nodes, most similar first
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_point: usize,
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Scored> {
+        let entry = Scored(dot(query, &self.nodes[entry_point].vector), entry_point);
+        let mut visited = HashSet::from([entry_point]);
+        let mut candidates = BinaryHeap::from([entry]);
+        let mut results = BinaryHeap::from([Reverse(entry)]);
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().unwrap().0;
+            if candidate < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbor in &self.nodes[candidate.1].layers[layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let scored = Scored(dot(query, &self.nodes[neighbor].vector), neighbor);
+                if results.len() < ef || scored > results.peek().unwrap().0 {
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results: Vec<_> = results.into_iter().map(|x| x.0).collect();
+        results.sort_unstable_by(|a, b| b.cmp(a));
+        results
+    }
+
+    /// Up to `k` IDs with the most similar vectors with their similarity, most similar first.
+    /// `ef` is number of candidates, larger values give more accurate results
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        ef: usize,
+        filter: impl Fn(i64) -> bool,
+    ) -> Vec<(i64, f32)> {
+        let Some(mut entry_point) = self.entry_point else {
+            return Vec::new();
+        };
+        let top_level = self.nodes[entry_point].layers.len() - 1;
+        for layer in (1..=top_level).rev() {
+            entry_point = self.search_layer(query, entry_point, 1, layer)[0].1;
+        }
+        // Removed nodes are also found, so more candidates are needed
+        let deleted = self.nodes.len() - self.ids.len();
+        self.search_layer(query, entry_point, ef.max(k) + deleted.min(ef), 0)
+            .into_iter()
+            .filter(|x| !self.nodes[x.1].deleted && filter(self.nodes[x.1].id))
+            .take(k)
+            .map(|x| (self.nodes[x.1].id, x.0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_vector(rng: &mut impl Rng) -> Vec<f32> {
+        let vector: Vec<f32> = (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let norm = dot(&vector, &vector).sqrt();
+        vector.into_iter().map(|x| x / norm).collect()
+    }
+
+    /// Vector along one axis
+    fn axis(i: usize) -> Vec<f32> {
+        let mut vector = vec![0.0; 16];
+        vector[i] = 1.0;
+        vector
+    }
+
+    #[test]
+    fn insert_and_replace() {
+        let mut hnsw = Hnsw::default();
+        assert!(hnsw.search(&axis(0), 1, 10, |_| true).is_empty());
+        hnsw.insert(1, axis(0));
+        hnsw.insert(2, axis(1));
+        assert_eq!(hnsw.get(1), Some(axis(0).as_slice()));
+        assert_eq!(hnsw.search(&axis(0), 1, 10, |_| true), [(1, 1.0)]);
+
+        hnsw.insert(1, axis(2));
+        assert_eq!(hnsw.get(1), Some(axis(2).as_slice()));
+        assert_eq!(hnsw.search(&axis(2), 1, 10, |_| true), [(1, 1.0)]);
+        assert_eq!(hnsw.search(&axis(0), 3, 10, |_| true).len(), 2);
+    }
+
+    #[test]
+    fn remove_and_compact() {
+        let mut hnsw = Hnsw::default();
+        for i in 0..16 {
+            hnsw.insert(i as i64, axis(i));
+        }
+        for i in 0..12 {
+            hnsw.remove(i);
+        }
+        assert_eq!(hnsw.get(0), None);
+        let found = hnsw.search(&axis(0), 16, 16, |_| true);
+        assert_eq!(found.len(), 4);
+        assert!(found.iter().all(|x| x.0 >= 12));
+        assert!(!hnsw.needs_compaction());
+
+        for i in 16..64 {
+            hnsw.insert(i, axis(0));
+            hnsw.remove(i);
+        }
+        assert!(hnsw.needs_compaction());
+        hnsw.compact();
+        assert!(!hnsw.needs_compaction());
+        assert_eq!(hnsw.nodes.len(), 4);
+        assert_eq!(hnsw.get(15), Some(axis(15).as_slice()));
+        assert_eq!(hnsw.search(&axis(13), 1, 10, |_| true), [(13, 1.0)]);
+        assert_eq!(hnsw.search(&axis(13), 1, 10, |id| id != 13).len(), 1);
+    }
+
+    #[test]
+    fn recall() {
+        let mut rng = rand::thread_rng();
+        let vectors: Vec<_> = (0..2000).map(|_| random_vector(&mut rng)).collect();
+        let mut hnsw = Hnsw::default();
+        for (id, vector) in vectors.iter().enumerate() {
+            hnsw.insert(id as i64, vector.clone());
+        }
+
+        let (k, queries) = (10, 50);
+        let mut found = 0;
+        for _ in 0..queries {
+            let query = random_vector(&mut rng);
+            let mut exact: Vec<_> = (0..vectors.len())
+                .map(|id| Scored(dot(&query, &vectors[id]), id))
+                .collect();
+            exact.sort_unstable_by(|a, b| b.cmp(a));
+            let exact: HashSet<_> = exact.iter().take(k).map(|x| x.1 as i64).collect();
+            found += hnsw
+                .search(&query, k, 100, |_| true)
+                .iter()
+                .filter(|x| exact.contains(&x.0))
+                .count();
+        }
+        let recall = found as f64 / (k * queries) as f64;
+        assert!(recall > 0.9, "Recall is {recall}");
+    }
+}
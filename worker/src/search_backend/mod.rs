@@ -0,0 +1,110 @@
+use std::sync::OnceLock;
+
+use async_trait::async_trait;
+
+use crate::Embedding;
+
+pub use self::elasticsearch::{ElasticsearchBackend, RebuildState};
+pub use embedded::EmbeddedBackend;
+
+mod elasticsearch;
+mod embedded;
+mod hnsw;
+
+static SEARCH_BACKEND: OnceLock<Box<dyn SearchBackend>> = OnceLock::new();
+
+/// Index of image titles and embeddings with hybrid search
+#[async_trait]
+pub trait SearchBackend: Send + Sync {
+    /// Prepare backend for use (create index, check connection)
+    async fn init(&self) -> Result<(), String>;
+    /// Add image to index, replacing previous version
+    async fn index(&self, id: i64, title: &str, embedding: &Embedding) -> Result<(), String>;
+    /// Change title of image, keeping embedding. Returns `false` if image is not indexed
+    async fn update_title(&self, id: i64, title: &str) -> Result<bool, String>;
+    /// Remove image from index. Image that is not indexed is skipped
+    async fn delete(&self, id: i64) -> Result<(), String>;
+    /// Stored embedding of image, `None` if image is not indexed
+    async fn get_embedding(&self, id: i64) -> Result<Option<Embedding>, String>;
+    /// Rank images by sum of title relevance (if `text` is set) and similarity to embedding
+    /// among `k` nearest neighbors, returns at most `size` IDs starting from `from`
+    async fn search(
+        &self,
+        text: Option<&str>,
+        embedding: &Embedding,
+        k: usize,
+        from: usize,
+        size: usize,
+    ) -> Result<Vec<i64>, String>;
+    /// `count` images with embeddings closest to `embedding`, excluding image `exclude_id`
+    async fn similar(
+        &self,
+        embedding: &Embedding,
+        exclude_id: i64,
+        count: usize,
+    ) -> Result<Vec<i64>, String>;
+}
+
+/// Search backend settings, read from environment variables
+#[derive(Debug, Clone)]
+pub enum SearchBackendSettings {
+    Elasticsearch {
+        url: String,
+        username: String,
+        password: String,
+    },
+    Embedded {
+        path: String,
+    },
+}
+
+impl SearchBackendSettings {
+    /// Read settings from `SEARCH_BACKEND` (`elasticsearch` or `embedded`) and backend-specific variables
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| format!("{name} environment variable is not set"))
+        };
+        let backend =
+            std::env::var("SEARCH_BACKEND").unwrap_or_else(|_| "elasticsearch".to_owned());
+        match backend.as_str() {
+            "elasticsearch" => Ok(Self::Elasticsearch {
+                url: var("ELASTICSEARCH_URL")?,
+                username: var("ELASTICSEARCH_USERNAME")?,
+                password: var("ELASTICSEARCH_PASSWORD")?,
+            }),
+            "embedded" => Ok(Self::Embedded {
+                path: std::env::var("SEARCH_INDEX_PATH")
+                    .unwrap_or_else(|_| "search_index".to_owned()),
+            }),
+            _ => Err(format!("Unknown search backend: {backend}")),
+        }
+    }
+
+    pub fn build(self) -> Result<Box<dyn SearchBackend>, String> {
+        Ok(match self {
+            Self::Elasticsearch {
+                url,
+                username,
+                password,
+            } => Box::new(ElasticsearchBackend::new(&url, username, password)?),
+            Self::Embedded { path } => Box::new(EmbeddedBackend::new(path)),
+        })
+    }
+}
+
+/// Create search backend from environment variables, initialize it and make it globally available
+pub async fn init_search_backend() -> Result<(), String> {
+    let search_backend = SearchBackendSettings::from_env()?.build()?;
+    search_backend.init().await?;
+    SEARCH_BACKEND
+        .set(search_backend)
+        .map_err(|_| "Search backend is already initialized".to_owned())
+}
+
+/// Global search backend, panics if `init_search_backend` wasn't called
+pub fn search_backend() -> &'static dyn SearchBackend {
+    SEARCH_BACKEND
+        .get()
+        .expect("Search backend is not initialized")
+        .as_ref()
+}
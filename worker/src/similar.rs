@@ -0,0 +1,18 @@
+use common::{SearchResponse, SimilarMessage};
+
+use crate::search_backend::search_backend;
+
+pub async fn process_request(message: SimilarMessage) -> Result<SearchResponse, String> {
+    let ids = match search_backend().get_embedding(message.id).await? {
+        Some(embedding) => {
+            search_backend()
+                .similar(&embedding, message.id, message.count as usize)
+                .await?
+        }
+        None => Vec::new(),
+    };
+    Ok(SearchResponse {
+        ids,
+        last_page: true,
+    })
+}
@@ -0,0 +1,37 @@
+use std::sync::OnceLock;
+
+use async_trait::async_trait;
+use common::{CallbackMessage, ProcessingStatus, StatusMessage, ThumbnailsMessage};
+
+static CALLBACK: OnceLock<Box<dyn Callback>> = OnceLock::new();
+
+/// Delivery of messages from worker to web server
+#[async_trait]
+pub trait Callback: Send + Sync {
+    async fn send(&self, message: CallbackMessage) -> Result<(), String>;
+}
+
+/// Set global delivery of messages to web server, must be called before processing jobs
+pub fn set_callback(callback: Box<dyn Callback>) -> Result<(), String> {
+    CALLBACK
+        .set(callback)
+        .map_err(|_| "Callback is already set".to_owned())
+}
+
+/// Send image processing status to web server, errors are only logged
+pub async fn report_status(id: i64, status: ProcessingStatus, error: Option<String>) {
+    let message = CallbackMessage::Status(StatusMessage { id, status, error });
+    let callback = CALLBACK.get().expect("Callback is not set");
+    if let Err(e) = callback.send(message).await {
+        tracing::error!("Can't send status of image {id}: {e}");
+    }
+}
+
+/// Send dimensions of image and created thumbnails to web server, errors are only logged
+pub async fn report_thumbnails(message: ThumbnailsMessage) {
+    let id = message.id;
+    let callback = CALLBACK.get().expect("Callback is not set");
+    if let Err(e) = callback.send(CallbackMessage::Thumbnails(message)).await {
+        tracing::error!("Can't send thumbnails of image {id}: {e}");
+    }
+}
@@ -0,0 +1,23 @@
+use common::UpdateMessage;
+
+use crate::search_backend::search_backend;
+
+/// Partially update image in search index, keeping embedding
+pub async fn process_request(message: UpdateMessage) -> Result<(), ()> {
+    let Some(title) = message.title else {
+        return Ok(());
+    };
+    match search_backend().update_title(message.id, &title).await {
+        Ok(true) => Ok(()),
+        // Image is being indexed with previous title from upload message, so update is retried
+        // after it. Update of image that won't be indexed is dead-lettered after retries
+        Ok(false) => {
+            tracing::warn!("Image {} is not indexed yet, retrying update", message.id);
+            Err(())
+        }
+        Err(e) => {
+            tracing::error!("{e}");
+            Err(())
+        }
+    }
+}